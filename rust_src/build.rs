@@ -98,6 +98,7 @@ struct ModuleData {
     pub c_exports: Vec<(Option<String>, String)>,
     pub lisp_fns: Vec<(Option<String>, String)>,
     pub protected_statics: Vec<String>,
+    pub has_init_errors: bool,
 }
 
 impl ModuleData {
@@ -107,6 +108,7 @@ impl ModuleData {
             c_exports: Vec::new(),
             lisp_fns: Vec::new(),
             protected_statics: Vec::new(),
+            has_init_errors: false,
         }
     }
 }
@@ -219,6 +221,9 @@ impl<'a> ModuleParser<'a> {
                 preceding_cfg = None;
             } else if line.starts_with("include!(concat!(") {
                 has_include = true;
+            } else if line.starts_with("pub fn init_errors()") {
+                mod_data.has_init_errors = true;
+                preceding_cfg = None;
             } else if line.starts_with("/*") && !line.ends_with("*/") {
                 while let Some(next) = reader.next() {
                     let line = next?;
@@ -516,7 +521,8 @@ fn generate_crate_exports(path: &PathBuf) -> Result<(), BuildError> {
 }
 
 /// Export lisp functions defined in rust by using the macro `export_lisp_fns`
-/// Add *_init_syms function of each module to the c_exports OUT_FILE
+/// Add *_init_syms function of each module to the c_exports OUT_FILE,
+/// along with the init_errors function defining its error symbols
 fn write_lisp_fns(
     crate_path: &PathBuf,
     mut out_file: &File,
@@ -569,6 +575,11 @@ fn write_lisp_fns(
                 mod_data.info.name
             )?;
         }
+
+        // Define the error symbols of the module
+        if mod_data.has_init_errors {
+            write!(out_file, "    {}::init_errors();\n", mod_data.info.name)?;
+        }
     }
 
     Ok(())
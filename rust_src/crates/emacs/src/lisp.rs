@@ -252,4 +252,23 @@ impl LispObject {
             wrong_type!(Quser_ptrp, *self);
         }
     }
+    /// Return the data of this user-ptr when it was made with FINALIZER,
+    /// or None otherwise. The finalizer identifies the type of the data
+    /// of a user-ptr, so the caller must make sure that the user-ptrs
+    /// made with FINALIZER hold a T.
+    pub unsafe fn as_userdata_with_finalizer<T>(
+        &self,
+        finalizer: unsafe extern "C" fn(*mut c_void),
+    ) -> Option<&T> {
+        if !self.is_user_ptr() {
+            return None;
+        }
+
+        let ptr = *XUSER_PTR(*self);
+        if ptr.p.is_null() || ptr.finalizer.map(|f| f as usize) != Some(finalizer as usize) {
+            return None;
+        }
+
+        Some(&*(ptr.p as *const T))
+    }
 }
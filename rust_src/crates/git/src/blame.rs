@@ -0,0 +1,221 @@
+use std::path::Path;
+
+use git2::{Blame, BlameOptions};
+
+use lisp_macros::lisp_fn;
+
+use emacs::bindings::make_int;
+use emacs::globals::{
+    QCauthor, QCauthor_email, QCcontents, QCmax_line, QCmin_line, QCnum_lines, QCoid, QCorig_path,
+    QCorig_start, QCrev, QCstart, QCtime, Qnil,
+};
+use emacs::lisp::LispObject;
+use emacs::multibyte::LispStringRef;
+
+use crate::repository::{
+    check_plist_args, make_list, open_repository, optional_string, signal_git_error,
+};
+
+#[derive(Default)]
+struct BlameArgs {
+    rev: Option<String>,
+    contents: Option<String>,
+    min_line: Option<usize>,
+    max_line: Option<usize>,
+}
+
+fn blame_args_from_args(args: &[LispObject]) -> BlameArgs {
+    let mut opts = BlameArgs::default();
+
+    check_plist_args(args);
+    for pair in args.chunks(2) {
+        let (key, value) = (pair[0], pair[1]);
+        match key {
+            QCrev => opts.rev = optional_string(value),
+            QCcontents => opts.contents = optional_string(value),
+            QCmin_line => {
+                if value.is_not_nil() {
+                    opts.min_line = Some(value.as_natnum_or_error() as usize);
+                }
+            }
+            QCmax_line => {
+                if value.is_not_nil() {
+                    opts.max_line = Some(value.as_natnum_or_error() as usize);
+                }
+            }
+            _ => error!("Wrong type: must be :rev, :contents, :min-line, :max-line"),
+        }
+    }
+
+    opts
+}
+
+// A run of consecutive lines of the blamed file, which are not
+// committed when they only exist in the buffer contents.
+struct Segment {
+    start: usize,
+    lines: usize,
+    committed: bool,
+}
+
+fn segment_to_lisp(blame: Option<&Blame>, segment: &Segment) -> LispObject {
+    let hunk = if segment.committed {
+        blame.and_then(|blame| blame.get_line(segment.start))
+    } else {
+        None
+    };
+
+    let (oid, author, email, time, orig_path, orig_start) = match hunk {
+        Some(hunk) => {
+            let signature = hunk.final_signature();
+            (
+                LispObject::from(hunk.final_commit_id().to_string().as_str()),
+                LispObject::from(String::from_utf8_lossy(signature.name_bytes()).as_ref()),
+                LispObject::from(String::from_utf8_lossy(signature.email_bytes()).as_ref()),
+                unsafe { make_int(signature.when().seconds()) },
+                hunk.path().map_or(Qnil, |path| {
+                    LispObject::from(path.to_string_lossy().as_ref())
+                }),
+                LispObject::from(hunk.orig_start_line() + segment.start - hunk.final_start_line()),
+            )
+        }
+        None => (Qnil, Qnil, Qnil, Qnil, Qnil, Qnil),
+    };
+
+    list!(
+        QCoid,
+        oid,
+        QCauthor,
+        author,
+        QCauthor_email,
+        email,
+        QCtime,
+        time,
+        QCorig_path,
+        orig_path,
+        QCorig_start,
+        orig_start,
+        QCstart,
+        LispObject::from(segment.start),
+        QCnum_lines,
+        LispObject::from(segment.lines)
+    )
+}
+
+/// Return the blame of FILE in the repository at PATH.
+/// FILE is relative to the working directory.  The result is a list of
+/// hunk plists with the keys :oid, :author, :author-email, :time,
+/// :orig-path, :orig-start, :start and :num-lines.  :start and
+/// :num-lines give the lines of FILE the hunk covers, while :orig-path
+/// and :orig-start locate them in the commit :oid that introduced them.
+///
+/// OPTIONS is a plist.  :rev is the revision to blame, HEAD by default.
+/// :min-line and :max-line restrict the blame to that range of lines,
+/// counting from 1.  :contents is a string with the current, possibly
+/// unsaved, contents of FILE; its lines are blamed against :rev, and
+/// the lines that were added or changed are returned in hunks whose
+/// other keys are all nil.
+/// usage: (git-blame PATH FILE &rest OPTIONS)
+#[lisp_fn(min = "2")]
+pub fn git_blame(args: &[LispObject]) -> LispObject {
+    let repo = open_repository(&args[0]);
+    let file: LispStringRef = args[1].into();
+    let file = file.to_utf8();
+    let file = Path::new(&file);
+    let opts = blame_args_from_args(&args[2..]);
+    let rev = opts.rev.as_deref().unwrap_or("HEAD");
+
+    let mut blame_opts = BlameOptions::new();
+    let commit = match repo
+        .revparse_single(rev)
+        .and_then(|obj| obj.peel_to_commit())
+    {
+        Ok(commit) => commit,
+        Err(e) => signal_git_error("Error resolving revision", e),
+    };
+    blame_opts.newest_commit(commit.id());
+
+    // A file that is not committed yet has nothing to blame
+    let committed = match commit.tree() {
+        Ok(tree) => tree.get_path(file).is_ok(),
+        Err(e) => signal_git_error("Error resolving revision", e),
+    };
+    if opts.contents.is_none() {
+        if let Some(min_line) = opts.min_line {
+            blame_opts.min_line(min_line);
+        }
+        if let Some(max_line) = opts.max_line {
+            blame_opts.max_line(max_line);
+        }
+    }
+
+    let file_blame = if committed || opts.contents.is_none() {
+        match repo.blame_file(file, Some(&mut blame_opts)) {
+            Ok(blame) => Some(blame),
+            Err(e) => signal_git_error("Error computing blame", e),
+        }
+    } else {
+        None
+    };
+    let contents_blame = match (&file_blame, &opts.contents) {
+        (Some(blame), Some(contents)) => match blame.blame_buffer(contents.as_bytes()) {
+            Ok(blame) => Some(blame),
+            Err(e) => signal_git_error("Error blaming contents", e),
+        },
+        _ => None,
+    };
+    let blame = contents_blame.as_ref().or(file_blame.as_ref());
+
+    let segments = match blame {
+        // The lines that only exist in the contents have no commit
+        Some(blame) => blame
+            .iter()
+            .map(|hunk| Segment {
+                start: hunk.final_start_line(),
+                lines: hunk.lines_in_hunk(),
+                committed: !hunk.final_commit_id().is_zero(),
+            })
+            .collect(),
+        None => vec![Segment {
+            start: 1,
+            lines: opts.contents.as_deref().map_or(0, |c| c.lines().count()),
+            committed: false,
+        }],
+    };
+
+    // The contents are always blamed as a whole, so clip them to the
+    // requested range afterwards.
+    let min_line = opts.min_line.unwrap_or(1);
+    let max_line = opts.max_line.unwrap_or(usize::MAX);
+    let items = segments
+        .into_iter()
+        .filter_map(|segment| {
+            let first = segment.start.max(min_line);
+            let last = (segment.start + segment.lines - 1).min(max_line);
+            if first > last {
+                return None;
+            }
+            let clipped = Segment {
+                start: first,
+                lines: last - first + 1,
+                committed: segment.committed,
+            };
+            Some(segment_to_lisp(blame, &clipped))
+        })
+        .collect();
+
+    make_list(items)
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCrev, ":rev");
+    def_lisp_sym!(QCcontents, ":contents");
+    def_lisp_sym!(QCmin_line, ":min-line");
+    def_lisp_sym!(QCmax_line, ":max-line");
+    def_lisp_sym!(QCorig_path, ":orig-path");
+    def_lisp_sym!(QCorig_start, ":orig-start");
+    def_lisp_sym!(QCnum_lines, ":num-lines");
+}
+
+include!(concat!(env!("CARGO_MANIFEST_DIR"), "/out/blame_exports.rs"));
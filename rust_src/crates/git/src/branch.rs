@@ -0,0 +1,213 @@
+use git2::{build::CheckoutBuilder, Branch, BranchType, Repository};
+
+use lisp_macros::lisp_fn;
+
+use emacs::globals::{
+    QCahead, QCbehind, QChead, QCname, QCoid, QCremote, QCupstream, Qlocal, Qnil, Qremote,
+};
+use emacs::lisp::LispObject;
+use emacs::multibyte::LispStringRef;
+
+use crate::repository::{make_list, open_repository, optional_string, signal_git_error};
+
+fn branch_name(branch: &Branch) -> String {
+    match branch.name_bytes() {
+        Ok(name) => String::from_utf8_lossy(name).into_owned(),
+        Err(e) => signal_git_error("Error reading branch name", e),
+    }
+}
+
+fn branch_to_lisp(repo: &Repository, branch: &Branch, branch_type: BranchType) -> LispObject {
+    let target = branch.get().target();
+    let upstream = branch.upstream().ok();
+    let upstream_name = upstream.as_ref().map(|u| branch_name(u));
+
+    let (ahead, behind) = match (target, upstream.as_ref().and_then(|u| u.get().target())) {
+        (Some(local), Some(remote)) => match repo.graph_ahead_behind(local, remote) {
+            Ok((ahead, behind)) => (LispObject::from(ahead), LispObject::from(behind)),
+            Err(e) => signal_git_error("Error comparing branch with upstream", e),
+        },
+        _ => (Qnil, Qnil),
+    };
+
+    list!(
+        QCname,
+        branch_name(branch).as_str(),
+        QCremote,
+        branch_type == BranchType::Remote,
+        QChead,
+        branch.is_head(),
+        QCoid,
+        target.map_or(Qnil, |oid| LispObject::from(oid.to_string().as_str())),
+        QCupstream,
+        upstream_name
+            .as_ref()
+            .map_or(Qnil, |name| LispObject::from(name.as_str())),
+        QCahead,
+        ahead,
+        QCbehind,
+        behind
+    )
+}
+
+/// Return the branches of the repository at PATH.
+/// TYPE is `local' or `remote' to only list branches of that kind; nil
+/// lists both.  Each branch is a plist with the keys :name, :remote,
+/// :head, :oid, :upstream, :ahead and :behind.  :ahead and :behind
+/// count the commits that are not in the upstream branch, and the ones
+/// that are only in it; both are nil for branches without upstream.
+#[lisp_fn(min = "1")]
+pub fn git_branch_list(path: LispObject, kind: LispObject) -> LispObject {
+    let repo = open_repository(&path);
+    let filter = match kind {
+        Qnil => None,
+        Qlocal => Some(BranchType::Local),
+        Qremote => Some(BranchType::Remote),
+        _ => error!("Branch type must be 'local, 'remote or nil"),
+    };
+
+    let branches = match repo.branches(filter) {
+        Ok(branches) => branches,
+        Err(e) => signal_git_error("Error listing branches", e),
+    };
+
+    let items = branches
+        .map(|next| match next {
+            Ok((branch, branch_type)) => branch_to_lisp(&repo, &branch, branch_type),
+            Err(e) => signal_git_error("Error listing branches", e),
+        })
+        .collect();
+
+    make_list(items)
+}
+
+/// Create a branch called NAME in the repository at PATH.
+/// The branch points to the revision START, HEAD by default.  If FORCE
+/// is non-nil, an existing branch with the same name is overwritten.
+#[lisp_fn(min = "2")]
+pub fn git_branch_create(
+    path: LispObject,
+    name: LispStringRef,
+    start: LispObject,
+    force: bool,
+) -> LispStringRef {
+    let repo = open_repository(&path);
+    let start = optional_string(start).unwrap_or_else(|| "HEAD".to_string());
+
+    let result = repo
+        .revparse_single(&start)
+        .and_then(|object| object.peel_to_commit())
+        .and_then(|commit| repo.branch(&name.to_utf8(), &commit, force));
+
+    if let Err(e) = result {
+        signal_git_error("Error creating branch", e);
+    }
+
+    name
+}
+
+/// Rename the local branch OLD-NAME to NEW-NAME in the repository at
+/// PATH.  If FORCE is non-nil, an existing branch called NEW-NAME is
+/// overwritten.
+#[lisp_fn(min = "3")]
+pub fn git_branch_rename(
+    path: LispObject,
+    old_name: LispStringRef,
+    new_name: LispStringRef,
+    force: bool,
+) -> LispStringRef {
+    let repo = open_repository(&path);
+
+    let result = repo
+        .find_branch(&old_name.to_utf8(), BranchType::Local)
+        .and_then(|mut branch| branch.rename(&new_name.to_utf8(), force));
+
+    if let Err(e) = result {
+        signal_git_error("Error renaming branch", e);
+    }
+
+    new_name
+}
+
+/// Delete the branch NAME from the repository at PATH.
+/// If REMOTE is non-nil, NAME is a remote-tracking branch such as
+/// "origin/topic", otherwise a local branch.
+#[lisp_fn(min = "2")]
+pub fn git_branch_delete(path: LispObject, name: LispStringRef, remote: bool) -> bool {
+    let repo = open_repository(&path);
+    let branch_type = if remote {
+        BranchType::Remote
+    } else {
+        BranchType::Local
+    };
+
+    let result = repo
+        .find_branch(&name.to_utf8(), branch_type)
+        .and_then(|mut branch| branch.delete());
+
+    if let Err(e) = result {
+        signal_git_error("Error deleting branch", e);
+    }
+
+    true
+}
+
+/// Check out TARGET in the repository at PATH.
+/// TARGET is the name of a local branch, which becomes the current
+/// branch, or any revision spec, which detaches HEAD.  The checkout is
+/// safe by default and fails on local changes that would be
+/// overwritten; if FORCE is non-nil, such changes are discarded.
+#[lisp_fn(min = "2")]
+pub fn git_checkout(path: LispObject, target: LispStringRef, force: bool) -> LispStringRef {
+    let repo = open_repository(&path);
+    let target_name = target.to_utf8();
+
+    let mut checkout = CheckoutBuilder::new();
+    if force {
+        checkout.force();
+    } else {
+        checkout.safe();
+    }
+
+    let result = match repo.find_branch(&target_name, BranchType::Local) {
+        Ok(branch) => {
+            let refname = match branch.get().name() {
+                Some(refname) => refname.to_string(),
+                None => error!("Branch name is not valid UTF-8"),
+            };
+            branch
+                .get()
+                .peel_to_commit()
+                .and_then(|commit| repo.checkout_tree(commit.as_object(), Some(&mut checkout)))
+                .and_then(|_| repo.set_head(&refname))
+        }
+        Err(_) => repo
+            .revparse_single(&target_name)
+            .and_then(|object| object.peel_to_commit())
+            .and_then(|commit| {
+                repo.checkout_tree(commit.as_object(), Some(&mut checkout))?;
+                repo.set_head_detached(commit.id())
+            }),
+    };
+
+    if let Err(e) = result {
+        signal_git_error("Error checking out", e);
+    }
+
+    target
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QChead, ":head");
+    def_lisp_sym!(QCupstream, ":upstream");
+    def_lisp_sym!(QCahead, ":ahead");
+    def_lisp_sym!(QCbehind, ":behind");
+    def_lisp_sym!(Qlocal, "local");
+    def_lisp_sym!(Qremote, "remote");
+}
+
+include!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/out/branch_exports.rs"
+));
@@ -0,0 +1,226 @@
+use std::convert::TryInto;
+
+use git2::{Commit, ObjectType, Oid, Repository, RepositoryState, Signature};
+
+use lisp_macros::lisp_fn;
+
+use emacs::bindings::Ffuncall;
+use emacs::globals::{
+    QCamend, QCauthor, QCauthor_email, QCcommitter, QCcommitter_email, QCref, QCsign,
+};
+use emacs::lisp::LispObject;
+use emacs::multibyte::LispStringRef;
+
+use crate::repository::{
+    check_plist_args, open_repository, optional_string, signal_git_error, update_reference,
+};
+
+#[derive(Default)]
+struct CommitOptions {
+    reference: Option<String>,
+    author: Option<String>,
+    author_email: Option<String>,
+    committer: Option<String>,
+    committer_email: Option<String>,
+    amend: bool,
+    sign: Option<LispObject>,
+}
+
+fn commit_options_from_args(args: &[LispObject]) -> CommitOptions {
+    let mut opts = CommitOptions::default();
+
+    check_plist_args(args);
+    for pair in args.chunks(2) {
+        let (key, value) = (pair[0], pair[1]);
+        match key {
+            QCref => opts.reference = optional_string(value),
+            QCauthor => opts.author = optional_string(value),
+            QCauthor_email => opts.author_email = optional_string(value),
+            QCcommitter => opts.committer = optional_string(value),
+            QCcommitter_email => opts.committer_email = optional_string(value),
+            QCamend => opts.amend = value.is_not_nil(),
+            QCsign => {
+                if value.is_not_nil() {
+                    opts.sign = Some(value);
+                }
+            }
+            _ => error!(
+                "Wrong type: must be :ref, :author, :author-email, :committer, \
+                 :committer-email, :amend, :sign"
+            ),
+        }
+    }
+
+    opts
+}
+
+// Build a signature from BASE, overriding the name and email that were
+// given explicitly. The time of BASE is kept, so that amending a commit
+// preserves its authorship date.
+fn make_signature(
+    base: &Signature,
+    name: &Option<String>,
+    email: &Option<String>,
+) -> Result<Signature<'static>, git2::Error> {
+    let name = name
+        .clone()
+        .unwrap_or_else(|| String::from_utf8_lossy(base.name_bytes()).into_owned());
+    let email = email
+        .clone()
+        .unwrap_or_else(|| String::from_utf8_lossy(base.email_bytes()).into_owned());
+    Signature::new(&name, &email, &base.when())
+}
+
+// Call the Lisp function SIGN with the commit buffer. It returns the
+// signature as a string, or nil to leave the commit unsigned.
+fn sign_commit_buffer(sign: LispObject, buffer: &str) -> Option<String> {
+    let mut args = vec![sign, LispObject::from(buffer)];
+    let result = unsafe { Ffuncall(args.len().try_into().unwrap(), args.as_mut_ptr()) };
+    if result.is_nil() {
+        None
+    } else {
+        let signature: LispStringRef = result.into();
+        Some(signature.to_utf8())
+    }
+}
+
+// Return the commits being merged when a merge stopped on conflicts,
+// so that committing the resolution concludes the merge.
+fn merge_heads(repo: &mut Repository) -> Vec<Oid> {
+    let mut oids = vec![];
+    if repo.state() == RepositoryState::Merge {
+        let result = repo.mergehead_foreach(|oid| {
+            oids.push(*oid);
+            true
+        });
+        if let Err(e) = result {
+            signal_git_error("Error reading MERGE_HEAD", e);
+        }
+    }
+
+    oids
+}
+
+fn create_commit(
+    repo: &Repository,
+    message: Option<String>,
+    merge_heads: &[Oid],
+    opts: &CommitOptions,
+) -> Oid {
+    // The commit extends the reference it updates, which is unborn if
+    // it does not exist yet.
+    let reference = opts.reference.as_deref().unwrap_or("HEAD");
+    let head: Option<Commit> = repo
+        .find_reference(reference)
+        .and_then(|reference| reference.resolve())
+        .and_then(|reference| reference.peel_to_commit())
+        .ok();
+    if opts.amend && head.is_none() {
+        error!("Cannot amend, {} does not point to a commit", reference);
+    }
+
+    let amended = if opts.amend { head.as_ref() } else { None };
+    let message = match message.or_else(|| amended.and_then(|c| c.message().map(String::from))) {
+        Some(message) => message,
+        None => error!("A commit message is required"),
+    };
+
+    let result = (|| -> Result<Oid, git2::Error> {
+        let default_signature = repo.signature()?;
+        let author_base = amended.map_or(default_signature.clone(), |c| c.author().to_owned());
+        let author = make_signature(&author_base, &opts.author, &opts.author_email)?;
+        let committer = make_signature(&default_signature, &opts.committer, &opts.committer_email)?;
+
+        let mut index = repo.index()?;
+        let tree = repo.find_tree(index.write_tree()?)?;
+
+        let mut parents: Vec<Commit> = match amended {
+            Some(commit) => commit.parents().collect(),
+            None => head.iter().cloned().collect(),
+        };
+        for oid in merge_heads {
+            parents.push(repo.find_commit(*oid)?);
+        }
+        let parent_refs: Vec<&Commit> = parents.iter().collect();
+
+        let oid = match opts.sign {
+            Some(sign) => {
+                let buffer =
+                    repo.commit_create_buffer(&author, &committer, &message, &tree, &parent_refs)?;
+                // The signature must cover the exact bytes of the commit
+                let content = buffer.as_str().ok_or_else(|| {
+                    git2::Error::from_str("Cannot sign a commit that is not valid UTF-8")
+                })?;
+                match sign_commit_buffer(sign, content) {
+                    Some(signature) => repo.commit_signed(content, &signature, None)?,
+                    None => repo.odb()?.write(ObjectType::Commit, &buffer)?,
+                }
+            }
+            None => repo.commit(None, &author, &committer, &message, &tree, &parent_refs)?,
+        };
+
+        let summary = message.lines().next().unwrap_or("");
+        let log_message = if opts.amend {
+            format!("commit (amend): {}", summary)
+        } else if parents.is_empty() {
+            format!("commit (initial): {}", summary)
+        } else if !merge_heads.is_empty() {
+            format!("commit (merge): {}", summary)
+        } else {
+            format!("commit: {}", summary)
+        };
+        update_reference(repo, reference, oid, &log_message)?;
+        if !merge_heads.is_empty() {
+            repo.cleanup_state()?;
+        }
+
+        Ok(oid)
+    })();
+
+    result.unwrap_or_else(|e| signal_git_error("Error creating commit", e))
+}
+
+/// Create a commit from the index of the repository at PATH with
+/// MESSAGE, and return its object id.  MESSAGE may be nil when
+/// amending, in which case the message of the amended commit is kept.
+///
+/// OPTIONS is a plist.  :ref is the reference to update, HEAD by
+/// default, whose tip becomes the parent of the commit.  :author,
+/// :author-email, :committer and :committer-email override the
+/// signatures taken from the git configuration.  If :amend is non-nil,
+/// the tip of :ref is replaced instead of extended.  :sign is
+/// a function called with the commit buffer as a string; it returns
+/// the signature to embed, e.g. an ASCII-armored GPG signature, or nil
+/// to create an unsigned commit.
+///
+/// When a merge stopped on conflicts, a commit to HEAD concludes it and
+/// gets the merged commits as additional parents.  Signing fails if the
+/// commit is not valid UTF-8.
+/// usage: (git-commit PATH MESSAGE &rest OPTIONS)
+#[lisp_fn(min = "2")]
+pub fn git_commit(args: &[LispObject]) -> LispObject {
+    let mut repo = open_repository(&args[0]).into_owned();
+    let message = optional_string(args[1]);
+    let opts = commit_options_from_args(&args[2..]);
+
+    let to_head = opts.reference.as_deref().map_or(true, |r| r == "HEAD");
+    let merge_heads = if opts.amend || !to_head {
+        vec![]
+    } else {
+        merge_heads(&mut repo)
+    };
+    let oid = create_commit(&repo, message, &merge_heads, &opts);
+    LispObject::from(oid.to_string().as_str())
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCref, ":ref");
+    def_lisp_sym!(QCamend, ":amend");
+    def_lisp_sym!(QCsign, ":sign");
+}
+
+include!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/out/commit_exports.rs"
+));
@@ -0,0 +1,259 @@
+use git2::{Delta, Diff, DiffOptions, Patch, Repository, Tree};
+
+use lisp_macros::lisp_fn;
+
+use emacs::globals::{
+    QCcached, QCcontext_lines, QCheader, QChunks, QCignore_whitespace, QCignore_whitespace_change,
+    QCignore_whitespace_eol, QClines, QCnew, QCnew_lines, QCnew_path, QCnew_start, QCold,
+    QCold_lines, QCold_path, QCold_start, QCpathspec, QCstatus, Qadded, Qconflicted, Qcopied,
+    Qdeleted, Qignored, Qmodified, Qnil, Qrenamed, Qtypechange, Qunmodified, Qunreadable,
+    Quntracked,
+};
+use emacs::lisp::LispObject;
+use emacs::multibyte::LispStringRef;
+
+use crate::repository::{check_plist_args, open_repository, signal_git_error, string_list};
+
+enum DiffTarget {
+    // Working directory against the index
+    Workdir,
+    // Index against HEAD
+    Cached,
+    // Tree of OLD against tree of NEW
+    Trees(String, String),
+}
+
+fn diff_options_from_args(args: &[LispObject]) -> (DiffTarget, DiffOptions) {
+    let mut opts = DiffOptions::new();
+    let mut cached = false;
+    let mut old: Option<String> = None;
+    let mut new: Option<String> = None;
+
+    check_plist_args(args);
+    for pair in args.chunks(2) {
+        let (key, value) = (pair[0], pair[1]);
+        match key {
+            QCcached => {
+                cached = value.is_not_nil();
+            }
+            QCold => {
+                if value.is_not_nil() {
+                    let rev: LispStringRef = value.into();
+                    old = Some(rev.to_utf8());
+                }
+            }
+            QCnew => {
+                if value.is_not_nil() {
+                    let rev: LispStringRef = value.into();
+                    new = Some(rev.to_utf8());
+                }
+            }
+            QCpathspec => {
+                for spec in string_list(value) {
+                    opts.pathspec(spec);
+                }
+            }
+            QCcontext_lines => {
+                opts.context_lines(value.as_natnum_or_error() as u32);
+            }
+            QCignore_whitespace => {
+                opts.ignore_whitespace(value.is_not_nil());
+            }
+            QCignore_whitespace_change => {
+                opts.ignore_whitespace_change(value.is_not_nil());
+            }
+            QCignore_whitespace_eol => {
+                opts.ignore_whitespace_eol(value.is_not_nil());
+            }
+            _ => error!(
+                "Wrong type: must be :cached, :old, :new, :pathspec, :context-lines, \
+                 :ignore-whitespace, :ignore-whitespace-change, :ignore-whitespace-eol"
+            ),
+        }
+    }
+
+    let target = match (old, new) {
+        (None, None) if cached => DiffTarget::Cached,
+        (None, None) => DiffTarget::Workdir,
+        (old, new) => DiffTarget::Trees(
+            old.unwrap_or_else(|| "HEAD".to_string()),
+            new.unwrap_or_else(|| "HEAD".to_string()),
+        ),
+    };
+
+    (target, opts)
+}
+
+fn rev_tree<'repo>(repo: &'repo Repository, rev: &str) -> Result<Tree<'repo>, git2::Error> {
+    repo.revparse_single(rev)?.peel_to_tree()
+}
+
+fn make_diff<'repo>(
+    repo: &'repo Repository,
+    target: DiffTarget,
+    mut opts: DiffOptions,
+) -> Diff<'repo> {
+    let diff = match target {
+        DiffTarget::Workdir => repo.diff_index_to_workdir(None, Some(&mut opts)),
+        DiffTarget::Cached => {
+            // An unborn HEAD compares the index against the empty tree.
+            let head = repo.head().and_then(|head| head.peel_to_tree()).ok();
+            repo.diff_tree_to_index(head.as_ref(), None, Some(&mut opts))
+        }
+        DiffTarget::Trees(old, new) => rev_tree(repo, &old).and_then(|old_tree| {
+            let new_tree = rev_tree(repo, &new)?;
+            repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), Some(&mut opts))
+        }),
+    };
+
+    diff.unwrap_or_else(|e| signal_git_error("Error computing diff", e))
+}
+
+fn delta_status(status: Delta) -> LispObject {
+    match status {
+        Delta::Unmodified => Qunmodified,
+        Delta::Added => Qadded,
+        Delta::Deleted => Qdeleted,
+        Delta::Modified => Qmodified,
+        Delta::Renamed => Qrenamed,
+        Delta::Copied => Qcopied,
+        Delta::Ignored => Qignored,
+        Delta::Untracked => Quntracked,
+        Delta::Typechange => Qtypechange,
+        Delta::Unreadable => Qunreadable,
+        Delta::Conflicted => Qconflicted,
+    }
+}
+
+fn optional_lineno(lineno: Option<u32>) -> LispObject {
+    lineno.map_or(Qnil, |n| LispObject::from(n as usize))
+}
+
+fn hunks_to_lisp(patch: &Patch) -> Result<LispObject, git2::Error> {
+    let mut hunks = Qnil;
+    for hunk_idx in (0..patch.num_hunks()).rev() {
+        let (hunk, line_count) = patch.hunk(hunk_idx)?;
+
+        let mut lines = Qnil;
+        for line_idx in (0..line_count).rev() {
+            let line = patch.line_in_hunk(hunk_idx, line_idx)?;
+            let item = list!(
+                LispObject::from(line.origin() as i32),
+                optional_lineno(line.old_lineno()),
+                optional_lineno(line.new_lineno()),
+                String::from_utf8_lossy(line.content()).as_ref()
+            );
+            lines = LispObject::cons(item, lines);
+        }
+
+        let item = list!(
+            QCheader,
+            String::from_utf8_lossy(hunk.header()).as_ref(),
+            QCold_start,
+            LispObject::from(hunk.old_start() as usize),
+            QCold_lines,
+            LispObject::from(hunk.old_lines() as usize),
+            QCnew_start,
+            LispObject::from(hunk.new_start() as usize),
+            QCnew_lines,
+            LispObject::from(hunk.new_lines() as usize),
+            QClines,
+            lines
+        );
+        hunks = LispObject::cons(item, hunks);
+    }
+
+    Ok(hunks)
+}
+
+fn file_path(file: git2::DiffFile) -> LispObject {
+    file.path_bytes().map_or(Qnil, |path| {
+        LispObject::from(String::from_utf8_lossy(path).as_ref())
+    })
+}
+
+fn diff_to_lisp(diff: &Diff) -> Result<LispObject, git2::Error> {
+    let mut files = Qnil;
+    for idx in (0..diff.deltas().len()).rev() {
+        let delta = match diff.get_delta(idx) {
+            Some(delta) => delta,
+            None => continue,
+        };
+        // Binary files have no patch and are reported without hunks.
+        let hunks = match Patch::from_diff(diff, idx)? {
+            Some(patch) => hunks_to_lisp(&patch)?,
+            None => Qnil,
+        };
+        let item = list!(
+            QCold_path,
+            file_path(delta.old_file()),
+            QCnew_path,
+            file_path(delta.new_file()),
+            QCstatus,
+            delta_status(delta.status()),
+            QChunks,
+            hunks
+        );
+        files = LispObject::cons(item, files);
+    }
+
+    Ok(files)
+}
+
+/// Return a structured diff of the repository at PATH.
+/// By default the working directory is compared against the index.
+/// The result is a list of file plists with the keys :old-path,
+/// :new-path, :status and :hunks.  Each hunk is a plist with the keys
+/// :header, :old-start, :old-lines, :new-start, :new-lines and :lines.
+/// Each line is a list (ORIGIN OLD-LINENO NEW-LINENO CONTENT), where
+/// ORIGIN is the character ?\s, ?+ or ?- and a line number is nil
+/// when the line does not exist on that side.
+///
+/// OPTIONS is a plist.  If :cached is non-nil, the index is compared
+/// against HEAD.  :old and :new are revision specs whose trees are
+/// compared with each other; either one defaults to HEAD.  :pathspec
+/// is a string or a list of strings restricting the files compared.
+/// :context-lines is the number of context lines around each hunk.
+/// :ignore-whitespace, :ignore-whitespace-change and
+/// :ignore-whitespace-eol control how whitespace changes are treated.
+/// usage: (git-diff PATH &rest OPTIONS)
+#[lisp_fn(min = "1")]
+pub fn git_diff(args: &[LispObject]) -> LispObject {
+    let repo = open_repository(&args[0]);
+    let (target, opts) = diff_options_from_args(&args[1..]);
+    let diff = make_diff(&repo, target, opts);
+
+    diff_to_lisp(&diff).unwrap_or_else(|e| signal_git_error("Error reading diff", e))
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCcached, ":cached");
+    def_lisp_sym!(QCold, ":old");
+    def_lisp_sym!(QCnew, ":new");
+    def_lisp_sym!(QCcontext_lines, ":context-lines");
+    def_lisp_sym!(QCignore_whitespace, ":ignore-whitespace");
+    def_lisp_sym!(QCignore_whitespace_change, ":ignore-whitespace-change");
+    def_lisp_sym!(QCignore_whitespace_eol, ":ignore-whitespace-eol");
+    def_lisp_sym!(QCold_path, ":old-path");
+    def_lisp_sym!(QCnew_path, ":new-path");
+    def_lisp_sym!(QCstatus, ":status");
+    def_lisp_sym!(QChunks, ":hunks");
+    def_lisp_sym!(QCheader, ":header");
+    def_lisp_sym!(QCold_start, ":old-start");
+    def_lisp_sym!(QCold_lines, ":old-lines");
+    def_lisp_sym!(QCnew_start, ":new-start");
+    def_lisp_sym!(QCnew_lines, ":new-lines");
+    def_lisp_sym!(QClines, ":lines");
+    def_lisp_sym!(Qunmodified, "unmodified");
+    def_lisp_sym!(Qadded, "added");
+    def_lisp_sym!(Qdeleted, "deleted");
+    def_lisp_sym!(Qmodified, "modified");
+    def_lisp_sym!(Qrenamed, "renamed");
+    def_lisp_sym!(Qcopied, "copied");
+    def_lisp_sym!(Quntracked, "untracked");
+    def_lisp_sym!(Qtypechange, "typechange");
+    def_lisp_sym!(Qunreadable, "unreadable");
+}
+
+include!(concat!(env!("CARGO_MANIFEST_DIR"), "/out/diff_exports.rs"));
@@ -0,0 +1,177 @@
+#[cfg(test)]
+use std::fs;
+#[cfg(test)]
+use std::path::Path;
+
+#[cfg(test)]
+use git2::DiffFormat;
+use git2::{ApplyLocation, ApplyOptions, Diff, IndexAddOption, Patch, Repository};
+
+use lisp_macros::lisp_fn;
+
+use emacs::lisp::LispObject;
+use emacs::list::{LispCons, LispConsCircularChecks, LispConsEndChecks};
+use emacs::multibyte::LispStringRef;
+
+#[cfg(test)]
+use crate::repository::{commit_file, make_test_repo};
+use crate::repository::{open_repository, signal_git_error, string_list};
+
+/// Add the current content of FILES to the index of the repository
+/// at PATH.  FILES is a string or a list of strings, relative to the
+/// working directory.  Files that were deleted from the working
+/// directory are removed from the index.
+#[lisp_fn]
+pub fn git_stage(path: LispObject, files: LispObject) -> bool {
+    let repo = open_repository(&path);
+    let pathspecs = string_list(files);
+
+    let result = repo.index().and_then(|mut index| {
+        index.add_all(pathspecs.iter(), IndexAddOption::DEFAULT, None)?;
+        index.update_all(pathspecs.iter(), None)?;
+        index.write()
+    });
+
+    if let Err(e) = result {
+        signal_git_error("Error staging files", e);
+    }
+
+    true
+}
+
+/// Reset the index entries of FILES in the repository at PATH to
+/// their state in HEAD.  FILES is a string or a list of strings,
+/// relative to the working directory.  When HEAD does not point to a
+/// commit yet, the entries are removed from the index.
+#[lisp_fn]
+pub fn git_unstage(path: LispObject, files: LispObject) -> bool {
+    let repo = open_repository(&path);
+    let pathspecs = string_list(files);
+
+    let head = repo.head().and_then(|head| head.peel_to_commit()).ok();
+    let result = repo.reset_default(head.as_ref().map(|c| c.as_object()), pathspecs.iter());
+
+    if let Err(e) = result {
+        signal_git_error("Error unstaging files", e);
+    }
+
+    true
+}
+
+fn hunk_count(diff: &Diff) -> usize {
+    (0..diff.deltas().len())
+        .map(|idx| match Patch::from_diff(diff, idx) {
+            Ok(Some(patch)) => patch.num_hunks(),
+            Ok(None) => 0,
+            Err(e) => signal_git_error("Error reading patch", e),
+        })
+        .sum()
+}
+
+/// Apply the hunks of the unified diff PATCH to the index of the
+/// repository at PATH, leaving the working directory untouched.
+/// This is the equivalent of 'git apply --cached'.
+///
+/// If HUNKS is non-nil, it is a list of zero-based indices of the
+/// hunks of PATCH to apply, counting across all files.  Other hunks
+/// are skipped.
+#[lisp_fn(min = "2")]
+pub fn git_stage_hunk(path: LispObject, patch: LispStringRef, hunks: LispObject) -> bool {
+    let repo = open_repository(&path);
+    let diff = match Diff::from_buffer(patch.as_slice()) {
+        Ok(diff) => diff,
+        Err(e) => signal_git_error("Error parsing patch", e),
+    };
+
+    let mut selected: Option<Vec<usize>> = None;
+    if hunks.is_not_nil() {
+        let count = hunk_count(&diff);
+        let list: LispCons = hunks.into();
+        let indices = list
+            .iter_cars(LispConsEndChecks::on, LispConsCircularChecks::on)
+            .map(|idx| {
+                let idx = idx.as_natnum_or_error() as usize;
+                if idx >= count {
+                    error!("Hunk index {} out of range, patch has {} hunks", idx, count);
+                }
+                idx
+            })
+            .collect();
+        selected = Some(indices);
+    }
+
+    if let Err(e) = apply_hunks(&repo, &diff, selected.as_deref()) {
+        signal_git_error("Error applying patch to the index", e);
+    }
+
+    true
+}
+
+// Apply the hunks of DIFF to the index of REPO, only those whose
+// indices are SELECTED when given.
+fn apply_hunks(
+    repo: &Repository,
+    diff: &Diff,
+    selected: Option<&[usize]>,
+) -> Result<(), git2::Error> {
+    let mut current = 0;
+    let mut opts = ApplyOptions::new();
+    opts.hunk_callback(|_hunk| {
+        let apply = selected.map_or(true, |s| s.contains(&current));
+        current += 1;
+        apply
+    });
+
+    repo.apply(diff, ApplyLocation::Index, Some(&mut opts))
+}
+
+// The contents of PATH in the index of REPO
+#[cfg(test)]
+fn staged_contents(repo: &Repository, path: &str) -> String {
+    let entry = repo.index().unwrap().get_path(Path::new(path), 0).unwrap();
+    let blob = repo.find_blob(entry.id).unwrap();
+    String::from_utf8(blob.content().to_vec()).unwrap()
+}
+
+#[test]
+fn test_apply_hunks() {
+    let repo = make_test_repo("stage-hunk");
+    let lines: Vec<String> = (1..=20).map(|n| format!("line {}\n", n)).collect();
+    let original = lines.concat();
+    commit_file(&repo, "file.txt", &original, "Base");
+
+    // Two changes far enough apart to make two hunks
+    let mut changed = lines.clone();
+    changed[1] = String::from("changed 2\n");
+    changed[17] = String::from("changed 18\n");
+    fs::write(repo.workdir().unwrap().join("file.txt"), changed.concat()).unwrap();
+
+    let mut patch = vec![];
+    repo.diff_index_to_workdir(None, None)
+        .unwrap()
+        .print(DiffFormat::Patch, |_, _, line| {
+            if let '+' | '-' | ' ' = line.origin() {
+                patch.push(line.origin() as u8);
+            }
+            patch.extend_from_slice(line.content());
+            true
+        })
+        .unwrap();
+    let diff = Diff::from_buffer(&patch).unwrap();
+    assert_eq!(hunk_count(&diff), 2);
+
+    apply_hunks(&repo, &diff, Some(&[1])).unwrap();
+    let mut expected = lines.clone();
+    expected[17] = String::from("changed 18\n");
+    assert_eq!(staged_contents(&repo, "file.txt"), expected.concat());
+
+    apply_hunks(&repo, &diff, Some(&[0])).unwrap();
+    assert_eq!(staged_contents(&repo, "file.txt"), changed.concat());
+    // The working directory is left untouched
+    assert_eq!(
+        fs::read_to_string(repo.workdir().unwrap().join("file.txt")).unwrap(),
+        changed.concat()
+    );
+}
+
+include!(concat!(env!("CARGO_MANIFEST_DIR"), "/out/index_exports.rs"));
@@ -6,7 +6,19 @@ extern crate lisp_macros;
 #[macro_use]
 extern crate lisp_util;
 
+mod blame;
+mod branch;
+mod commit;
+mod diff;
+mod index;
+mod log;
+mod merge;
+mod rebase;
+mod remote;
 mod repository;
+mod stash;
+mod status;
+mod tag;
 
 #[cfg(not(test))]
 include!(concat!(env!("CARGO_MANIFEST_DIR"), "/out/c_exports.rs"));
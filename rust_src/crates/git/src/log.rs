@@ -0,0 +1,432 @@
+use std::cell::RefCell;
+
+use git2::{Commit, DiffOptions, Oid, Repository, Revwalk, Sort};
+use self_cell::self_cell;
+
+use lisp_macros::lisp_fn;
+
+use ng_async::ng_async::UserData;
+
+use emacs::bindings::make_int;
+use emacs::globals::{
+    QCauthor, QCauthor_email, QCcommits, QCcommitter, QCcommitter_email, QCcursor, QClimit, QCoid,
+    QCparents, QCpath, QCsort, QCstart, QCsummary, QCtime, Qnil, Qreverse, Qtime, Qtopological,
+};
+use emacs::lisp::LispObject;
+use emacs::list::{LispCons, LispConsCircularChecks, LispConsEndChecks};
+use emacs::multibyte::LispStringRef;
+
+use crate::repository::{check_plist_args, open_repository, signal_git_error, string_list};
+#[cfg(test)]
+use crate::repository::{checkout_new_branch, commit_file, make_test_repo};
+
+// Number of commits returned by a single call when :limit is not given.
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+struct LogOptions {
+    start: Option<String>,
+    paths: Vec<String>,
+    sort: Sort,
+    limit: usize,
+    cursor: LispObject,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        LogOptions {
+            start: None,
+            paths: vec![],
+            sort: Sort::NONE,
+            limit: DEFAULT_PAGE_SIZE,
+            cursor: Qnil,
+        }
+    }
+}
+
+fn sort_from_symbol(symbol: LispObject) -> Sort {
+    match symbol {
+        Qtopological => Sort::TOPOLOGICAL,
+        Qtime => Sort::TIME,
+        Qreverse => Sort::REVERSE,
+        _ => error!(":sort must be 'topological, 'time, 'reverse or a list of them"),
+    }
+}
+
+fn log_options_from_args(args: &[LispObject]) -> LogOptions {
+    let mut opts = LogOptions::default();
+
+    check_plist_args(args);
+    for pair in args.chunks(2) {
+        let (key, value) = (pair[0], pair[1]);
+        match key {
+            QCstart => {
+                if value.is_not_nil() {
+                    let start: LispStringRef = value.into();
+                    opts.start = Some(start.to_utf8());
+                }
+            }
+            QCpath => {
+                opts.paths = string_list(value);
+            }
+            QCsort => {
+                opts.sort = if value.is_cons() {
+                    let list: LispCons = value.into();
+                    list.iter_cars(LispConsEndChecks::on, LispConsCircularChecks::on)
+                        .fold(Sort::NONE, |sort, symbol| sort | sort_from_symbol(symbol))
+                } else if value.is_nil() {
+                    Sort::NONE
+                } else {
+                    sort_from_symbol(value)
+                };
+            }
+            QClimit => {
+                opts.limit = value.as_natnum_or_error() as usize;
+                if opts.limit == 0 {
+                    error!(":limit must be a positive integer");
+                }
+            }
+            QCcursor => {
+                if value.is_not_nil() && as_log_cursor(&value).is_none() {
+                    error!(":cursor must be a cursor returned by git-log");
+                }
+                opts.cursor = value;
+            }
+            _ => error!("Wrong type: must be :start, :path, :sort, :limit, :cursor"),
+        }
+    }
+
+    opts
+}
+
+fn make_revwalk<'repo>(
+    repo: &'repo Repository,
+    opts: &LogOptions,
+) -> Result<Revwalk<'repo>, git2::Error> {
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(opts.sort)?;
+    match &opts.start {
+        Some(start) => {
+            let commit = repo.revparse_single(start)?.peel_to_commit()?;
+            walk.push(commit.id())?;
+        }
+        None => walk.push_head()?,
+    }
+
+    Ok(walk)
+}
+
+// A commit touches PATHS when it differs from each of its parents
+// there, which mirrors the default history simplification of 'git log'.
+fn touches_paths(
+    repo: &Repository,
+    commit: &Commit,
+    paths: &[String],
+) -> Result<bool, git2::Error> {
+    let mut diff_opts = DiffOptions::new();
+    for path in paths {
+        diff_opts.pathspec(path);
+    }
+
+    let mut differs = |parent: Option<Commit>| -> Result<bool, git2::Error> {
+        let old_tree = match parent {
+            Some(parent) => Some(parent.tree()?),
+            None => None,
+        };
+        let diff = repo.diff_tree_to_tree(
+            old_tree.as_ref(),
+            Some(&commit.tree()?),
+            Some(&mut diff_opts),
+        )?;
+        Ok(diff.deltas().len() > 0)
+    };
+
+    if commit.parent_count() == 0 {
+        differs(None)
+    } else {
+        commit
+            .parents()
+            .map(|parent| differs(Some(parent)))
+            .collect::<Result<Vec<bool>, git2::Error>>()
+            .map(|diffs| diffs.iter().all(|d| *d))
+    }
+}
+
+self_cell!(
+    // A revwalk together with the repository it borrows.
+    struct LogWalk {
+        owner: Repository,
+
+        #[covariant]
+        dependent: Revwalk,
+    }
+);
+
+// A history walk in progress. It is kept between the pages, so that the
+// next page continues where the previous one stopped instead of walking
+// the history from the start again.
+struct LogCursor {
+    walk: LogWalk,
+    paths: Vec<String>,
+    // The first commit of the next page, read ahead to know whether the
+    // history is exhausted.
+    next: Option<Oid>,
+}
+
+impl LogCursor {
+    fn new(repo: Repository, opts: &LogOptions) -> Result<Self, git2::Error> {
+        Ok(LogCursor {
+            walk: LogWalk::try_new(repo, |repo| make_revwalk(repo, opts))?,
+            paths: opts.paths.clone(),
+            next: None,
+        })
+    }
+
+    // Return the next commit touching the paths of the walk, or None
+    // once the history is exhausted.
+    fn next_oid(&mut self) -> Result<Option<Oid>, git2::Error> {
+        if let Some(oid) = self.next.take() {
+            return Ok(Some(oid));
+        }
+
+        let paths = &self.paths;
+        self.walk
+            .with_dependent_mut(|repo, revwalk| -> Result<Option<Oid>, git2::Error> {
+                for oid in revwalk {
+                    let oid = oid?;
+                    if paths.is_empty() || touches_paths(repo, &repo.find_commit(oid)?, paths)? {
+                        return Ok(Some(oid));
+                    }
+                }
+
+                Ok(None)
+            })
+    }
+
+    // Return the commits of the next page, at most LIMIT, and read the
+    // first commit of the page after it.
+    fn next_page(&mut self, limit: usize) -> Result<Vec<Oid>, git2::Error> {
+        let mut oids = vec![];
+        while oids.len() < limit {
+            match self.next_oid()? {
+                Some(oid) => oids.push(oid),
+                None => return Ok(oids),
+            }
+        }
+
+        self.next = self.next_oid()?;
+        Ok(oids)
+    }
+
+    fn commits_to_lisp(&self, oids: &[Oid]) -> Result<Vec<LispObject>, git2::Error> {
+        let repo = self.walk.borrow_owner();
+        oids.iter()
+            .map(|oid| repo.find_commit(*oid).map(|commit| commit_to_lisp(&commit)))
+            .collect()
+    }
+}
+
+unsafe extern "C" fn finalize_log_cursor(raw: *mut libc::c_void) {
+    let _cursor = Box::from_raw(raw as *mut RefCell<LogCursor>);
+}
+
+// The cursor is borrowed as long as OBJECT. It is only borrowed mutably
+// while no Lisp error can be signaled, so that the borrow is always
+// released.
+fn as_log_cursor(object: &LispObject) -> Option<&RefCell<LogCursor>> {
+    unsafe { object.as_userdata_with_finalizer(finalize_log_cursor) }
+}
+
+fn oid_to_lisp(oid: Oid) -> LispObject {
+    LispObject::from(oid.to_string().as_str())
+}
+
+fn commit_to_lisp(commit: &Commit) -> LispObject {
+    let author = commit.author();
+    let committer = commit.committer();
+    let parents = commit
+        .parent_ids()
+        .collect::<Vec<Oid>>()
+        .into_iter()
+        .rev()
+        .fold(Qnil, |result, oid| {
+            LispObject::cons(oid_to_lisp(oid), result)
+        });
+
+    list!(
+        QCoid,
+        oid_to_lisp(commit.id()),
+        QCauthor,
+        String::from_utf8_lossy(author.name_bytes()).as_ref(),
+        QCauthor_email,
+        String::from_utf8_lossy(author.email_bytes()).as_ref(),
+        QCcommitter,
+        String::from_utf8_lossy(committer.name_bytes()).as_ref(),
+        QCcommitter_email,
+        String::from_utf8_lossy(committer.email_bytes()).as_ref(),
+        QCtime,
+        unsafe { make_int(commit.time().seconds()) },
+        QCsummary,
+        String::from_utf8_lossy(commit.summary_bytes().unwrap_or(&[])).as_ref(),
+        QCparents,
+        parents
+    )
+}
+
+/// Return a page of the commit history of the repository at PATH.
+/// The result is a plist (:commits COMMITS :cursor CURSOR).  Each
+/// element of COMMITS is a plist with the keys :oid, :author,
+/// :author-email, :committer, :committer-email, :time, :summary and
+/// :parents.  CURSOR is nil when the history is exhausted, otherwise
+/// it should be passed as :cursor to fetch the next page.
+///
+/// OPTIONS is a plist.  :start is a revision spec to start walking
+/// from, HEAD by default.  :path is a string or a list of strings; only
+/// commits touching those paths are returned.  :sort is one of the
+/// symbols `topological', `time' and `reverse', or a list of them.
+/// :limit is the maximal number of commits returned, 100 by default.
+///
+/// A CURSOR keeps the walk in progress, so the next page continues where
+/// the previous one stopped.  :start, :path and :sort are then taken from
+/// the call that returned the first page.
+/// usage: (git-log PATH &rest OPTIONS)
+#[lisp_fn(min = "1")]
+pub fn git_log(args: &[LispObject]) -> LispObject {
+    let opts = log_options_from_args(&args[1..]);
+    let mut new_cursor = None;
+    let cursor = match as_log_cursor(&opts.cursor) {
+        Some(cursor) => cursor,
+        None => {
+            let repo = open_repository(&args[0]).into_owned();
+            let cursor = LogCursor::new(repo, &opts)
+                .unwrap_or_else(|e| signal_git_error("Error walking history", e));
+            &*new_cursor.get_or_insert(RefCell::new(cursor))
+        }
+    };
+
+    let page = match cursor.try_borrow_mut() {
+        Ok(mut cursor) => cursor
+            .next_page(opts.limit)
+            .map(|oids| (oids, cursor.next.is_some())),
+        Err(_) => error!(":cursor is already in use"),
+    };
+    let (oids, more) = page.unwrap_or_else(|e| signal_git_error("Error walking history", e));
+    let commits = cursor.borrow().commits_to_lisp(&oids);
+    let commits = commits.unwrap_or_else(|e| signal_git_error("Error walking history", e));
+
+    let cursor = if !more {
+        Qnil
+    } else if let Some(cursor) = new_cursor {
+        let data = Box::into_raw(Box::new(cursor)) as *mut libc::c_void;
+        UserData::with_data_and_finalizer(data, Some(finalize_log_cursor)).into()
+    } else {
+        opts.cursor
+    };
+    let commits = commits
+        .into_iter()
+        .rev()
+        .fold(Qnil, |result, commit| LispObject::cons(commit, result));
+
+    list!(QCcommits, commits, QCcursor, cursor)
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCstart, ":start");
+    def_lisp_sym!(QCpath, ":path");
+    def_lisp_sym!(QCsort, ":sort");
+    def_lisp_sym!(QClimit, ":limit");
+    def_lisp_sym!(QCcursor, ":cursor");
+    def_lisp_sym!(QCcommits, ":commits");
+    def_lisp_sym!(QCoid, ":oid");
+    def_lisp_sym!(QCauthor, ":author");
+    def_lisp_sym!(QCauthor_email, ":author-email");
+    def_lisp_sym!(QCcommitter, ":committer");
+    def_lisp_sym!(QCcommitter_email, ":committer-email");
+    def_lisp_sym!(QCtime, ":time");
+    def_lisp_sym!(QCsummary, ":summary");
+    def_lisp_sym!(QCparents, ":parents");
+    def_lisp_sym!(Qtopological, "topological");
+    def_lisp_sym!(Qtime, "time");
+    def_lisp_sym!(Qreverse, "reverse");
+}
+
+#[test]
+fn test_touches_paths() {
+    let repo = make_test_repo("log-paths");
+    let first = commit_file(&repo, "a.txt", "a\n", "Add a");
+    let second = commit_file(&repo, "b.txt", "b\n", "Add b");
+    let third = commit_file(&repo, "a.txt", "a2\n", "Change a");
+    let touches = |oid, paths: &[&str]| {
+        let paths: Vec<String> = paths.iter().map(|path| path.to_string()).collect();
+        touches_paths(&repo, &repo.find_commit(oid).unwrap(), &paths).unwrap()
+    };
+
+    assert!(touches(first, &["a.txt"]));
+    assert!(!touches(first, &["b.txt"]));
+    assert!(!touches(second, &["a.txt"]));
+    assert!(touches(second, &["a.txt", "b.txt"]));
+    assert!(touches(third, &["a.txt"]));
+}
+
+#[test]
+fn test_touches_paths_merge() {
+    let repo = make_test_repo("log-merge");
+    let base = commit_file(&repo, "base.txt", "base\n", "Base");
+    checkout_new_branch(&repo, "side", base);
+    let side = commit_file(&repo, "a.txt", "a\n", "Add a");
+    checkout_new_branch(&repo, "main", base);
+    let main = commit_file(&repo, "b.txt", "b\n", "Add b");
+
+    let (main, side) = (
+        repo.find_commit(main).unwrap(),
+        repo.find_commit(side).unwrap(),
+    );
+    let tree = repo
+        .merge_commits(&main, &side, None)
+        .unwrap()
+        .write_tree_to(&repo)
+        .unwrap();
+    let signature = repo.signature().unwrap();
+    let merge = repo
+        .commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            "Merge side",
+            &repo.find_tree(tree).unwrap(),
+            &[&main, &side],
+        )
+        .unwrap();
+    let merge = repo.find_commit(merge).unwrap();
+    let touches = |paths: &[&str]| {
+        let paths: Vec<String> = paths.iter().map(|path| path.to_string()).collect();
+        touches_paths(&repo, &merge, &paths).unwrap()
+    };
+
+    // Each file is the same as in one of the parents
+    assert!(!touches(&["a.txt"]));
+    assert!(!touches(&["b.txt"]));
+    assert!(touches(&["a.txt", "b.txt"]));
+}
+
+#[test]
+fn test_log_cursor_pages() {
+    let repo = make_test_repo("log-cursor");
+    let first = commit_file(&repo, "a.txt", "a\n", "Add a");
+    commit_file(&repo, "b.txt", "b\n", "Add b");
+    let third = commit_file(&repo, "a.txt", "a2\n", "Change a");
+
+    let opts = LogOptions {
+        paths: vec![String::from("a.txt")],
+        ..LogOptions::default()
+    };
+    let repo = Repository::open(repo.path()).unwrap();
+    let mut cursor = LogCursor::new(repo, &opts).unwrap();
+    assert_eq!(cursor.next_page(1).unwrap(), vec![third]);
+    assert_eq!(cursor.next, Some(first));
+    assert_eq!(cursor.next_page(1).unwrap(), vec![first]);
+    assert_eq!(cursor.next, None);
+    assert!(cursor.next_page(1).unwrap().is_empty());
+}
+
+include!(concat!(env!("CARGO_MANIFEST_DIR"), "/out/log_exports.rs"));
@@ -0,0 +1,289 @@
+#[cfg(test)]
+use git2::RepositoryState;
+use git2::{build::CheckoutBuilder, Index, IndexConflict, IndexEntry, Oid, Repository};
+
+use lisp_macros::lisp_fn;
+
+use emacs::globals::{
+    QCancestor, QCconflicts, QCff_only, QCmessage, QCno_ff, QCoid, QCours, QCpath, QCresult,
+    QCtheirs, Qconflict, Qfast_forward, Qmerge, Qnil, Qup_to_date,
+};
+use emacs::lisp::LispObject;
+use emacs::multibyte::LispStringRef;
+
+use crate::repository::{
+    check_plist_args, make_list, open_repository, optional_string, signal_git_error,
+    update_reference,
+};
+#[cfg(test)]
+use crate::repository::{checkout_new_branch, commit_file, make_test_repo};
+
+fn entry_oid(entry: &Option<IndexEntry>) -> LispObject {
+    entry.as_ref().map_or(Qnil, |entry| {
+        LispObject::from(entry.id.to_string().as_str())
+    })
+}
+
+fn conflict_to_lisp(conflict: &IndexConflict) -> LispObject {
+    let path = [&conflict.our, &conflict.their, &conflict.ancestor]
+        .iter()
+        .find_map(|entry| entry.as_ref())
+        .map_or(Qnil, |entry| {
+            LispObject::from(String::from_utf8_lossy(&entry.path).as_ref())
+        });
+
+    list!(
+        QCpath,
+        path,
+        QCancestor,
+        entry_oid(&conflict.ancestor),
+        QCours,
+        entry_oid(&conflict.our),
+        QCtheirs,
+        entry_oid(&conflict.their)
+    )
+}
+
+/// Return the conflicts recorded in INDEX as a list of plists with the
+/// keys :path, :ancestor, :ours and :theirs. The last three are the
+/// blob ids of each side, nil when the file does not exist there.
+pub fn index_conflicts(index: &Index) -> LispObject {
+    let conflicts = match index.conflicts() {
+        Ok(conflicts) => conflicts,
+        Err(e) => signal_git_error("Error reading conflicts", e),
+    };
+
+    let items = conflicts
+        .map(|conflict| match conflict {
+            Ok(conflict) => conflict_to_lisp(&conflict),
+            Err(e) => signal_git_error("Error reading conflicts", e),
+        })
+        .collect();
+
+    make_list(items)
+}
+
+#[derive(Default)]
+struct MergeArgs {
+    no_ff: bool,
+    ff_only: bool,
+    message: Option<String>,
+}
+
+fn merge_args_from_args(args: &[LispObject]) -> MergeArgs {
+    let mut opts = MergeArgs::default();
+
+    check_plist_args(args);
+    for pair in args.chunks(2) {
+        let (key, value) = (pair[0], pair[1]);
+        match key {
+            QCno_ff => opts.no_ff = value.is_not_nil(),
+            QCff_only => opts.ff_only = value.is_not_nil(),
+            QCmessage => opts.message = optional_string(value),
+            _ => error!("Wrong type: must be :no-ff, :ff-only, :message"),
+        }
+    }
+
+    if opts.no_ff && opts.ff_only {
+        error!(":no-ff and :ff-only are mutually exclusive");
+    }
+
+    opts
+}
+
+fn oid_result(result: LispObject, oid: Oid) -> LispObject {
+    list!(
+        QCresult,
+        result,
+        QCoid,
+        LispObject::from(oid.to_string().as_str())
+    )
+}
+
+// What a merge did to the repository
+#[derive(Debug, PartialEq)]
+enum MergeOutcome {
+    UpToDate,
+    FastForward(Oid),
+    Merged(Oid),
+    // The conflicts are left in the index
+    Conflict,
+}
+
+fn merge(repo: &Repository, rev: &str, opts: &MergeArgs) -> Result<MergeOutcome, git2::Error> {
+    let commit = repo.revparse_single(rev)?.peel_to_commit()?;
+    let annotated = repo.find_annotated_commit(commit.id())?;
+    let (analysis, _) = repo.merge_analysis(&[&annotated])?;
+
+    if analysis.is_up_to_date() {
+        return Ok(MergeOutcome::UpToDate);
+    }
+
+    if analysis.is_unborn() || (analysis.is_fast_forward() && !opts.no_ff) {
+        let mut checkout = CheckoutBuilder::new();
+        checkout.safe();
+        repo.checkout_tree(commit.as_object(), Some(&mut checkout))?;
+        update_reference(
+            repo,
+            "HEAD",
+            commit.id(),
+            &format!("merge {}: Fast-forward", rev),
+        )?;
+        return Ok(MergeOutcome::FastForward(commit.id()));
+    }
+
+    if opts.ff_only {
+        return Err(git2::Error::from_str("Not possible to fast-forward"));
+    }
+
+    repo.merge(&[&annotated], None, None)?;
+    let mut index = repo.index()?;
+    if index.has_conflicts() {
+        return Ok(MergeOutcome::Conflict);
+    }
+
+    let signature = repo.signature()?;
+    let head = repo.head()?.peel_to_commit()?;
+    let tree = repo.find_tree(index.write_tree()?)?;
+    let message = opts
+        .message
+        .clone()
+        .unwrap_or_else(|| format!("Merge {}", rev));
+    let oid = repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        &message,
+        &tree,
+        &[&head, &commit],
+    )?;
+    repo.cleanup_state()?;
+
+    Ok(MergeOutcome::Merged(oid))
+}
+
+fn merge_outcome_to_lisp(repo: &Repository, outcome: MergeOutcome) -> LispObject {
+    match outcome {
+        MergeOutcome::UpToDate => list!(QCresult, Qup_to_date),
+        MergeOutcome::FastForward(oid) => oid_result(Qfast_forward, oid),
+        MergeOutcome::Merged(oid) => oid_result(Qmerge, oid),
+        MergeOutcome::Conflict => {
+            let conflicts = match repo.index() {
+                Ok(index) => index_conflicts(&index),
+                Err(e) => signal_git_error("Error reading index", e),
+            };
+            list!(QCresult, Qconflict, QCconflicts, conflicts)
+        }
+    }
+}
+
+/// Merge REVISION into the current branch of the repository at PATH.
+/// The result is a plist whose :result is one of:
+///
+/// `up-to-date' when REVISION is already merged.
+///
+/// `fast-forward' when HEAD was moved to REVISION, given as :oid.
+///
+/// `merge' when a merge commit was created, given as :oid.
+///
+/// `conflict' when the merge stopped on conflicts, which are listed in
+/// :conflicts as plists with the keys :path, :ancestor, :ours and
+/// :theirs.  The repository is left in the `merge' state; once the
+/// conflicts are resolved and staged, `git-commit' concludes the merge.
+///
+/// OPTIONS is a plist.  If :no-ff is non-nil, a merge commit is created
+/// even when a fast-forward is possible.  If :ff-only is non-nil, the
+/// merge fails unless it is a fast-forward.  :message is the message of
+/// the merge commit.
+/// usage: (git-merge PATH REVISION &rest OPTIONS)
+#[lisp_fn(min = "2")]
+pub fn git_merge(args: &[LispObject]) -> LispObject {
+    let repo = open_repository(&args[0]);
+    let rev: LispStringRef = args[1].into();
+    let opts = merge_args_from_args(&args[2..]);
+
+    match merge(&repo, &rev.to_utf8(), &opts) {
+        Ok(outcome) => merge_outcome_to_lisp(&repo, outcome),
+        Err(e) => signal_git_error("Error merging", e),
+    }
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCno_ff, ":no-ff");
+    def_lisp_sym!(QCff_only, ":ff-only");
+    def_lisp_sym!(QCresult, ":result");
+    def_lisp_sym!(QCconflicts, ":conflicts");
+    def_lisp_sym!(QCancestor, ":ancestor");
+    def_lisp_sym!(QCours, ":ours");
+    def_lisp_sym!(QCtheirs, ":theirs");
+    def_lisp_sym!(Qup_to_date, "up-to-date");
+    def_lisp_sym!(Qfast_forward, "fast-forward");
+    def_lisp_sym!(Qconflict, "conflict");
+}
+
+// Make a repository where the branches "ours", checked out, and
+// "theirs" both commit FILE from a common base, with the contents OURS
+// and THEIRS.
+#[cfg(test)]
+fn make_diverged_repo(name: &str, file: &str, ours: &str, theirs: &str) -> Repository {
+    let repo = make_test_repo(name);
+    let base = commit_file(&repo, "base.txt", "base\n", "Base");
+    checkout_new_branch(&repo, "theirs", base);
+    commit_file(&repo, file, theirs, "Theirs");
+    checkout_new_branch(&repo, "ours", base);
+    commit_file(&repo, file, ours, "Ours");
+    repo
+}
+
+#[test]
+fn test_merge_conflict() {
+    let repo = make_diverged_repo("merge-conflict", "file.txt", "ours\n", "theirs\n");
+
+    let outcome = merge(&repo, "theirs", &MergeArgs::default()).unwrap();
+    assert_eq!(outcome, MergeOutcome::Conflict);
+    assert_eq!(repo.state(), RepositoryState::Merge);
+    assert!(repo.index().unwrap().has_conflicts());
+
+    repo.cleanup_state().unwrap();
+    assert_eq!(repo.state(), RepositoryState::Clean);
+}
+
+#[test]
+fn test_merge_commit() {
+    let repo = make_diverged_repo("merge-commit", "file.txt", "ours\n", "ours\n");
+    let theirs = repo.revparse_single("theirs").unwrap().id();
+
+    let oid = match merge(&repo, "theirs", &MergeArgs::default()).unwrap() {
+        MergeOutcome::Merged(oid) => oid,
+        outcome => panic!("unexpected outcome {:?}", outcome),
+    };
+    let commit = repo.find_commit(oid).unwrap();
+    assert_eq!(commit.parent_ids().nth(1), Some(theirs));
+    assert_eq!(commit.message(), Some("Merge theirs"));
+    assert_eq!(repo.head().unwrap().target(), Some(oid));
+    assert_eq!(repo.state(), RepositoryState::Clean);
+}
+
+#[test]
+fn test_merge_fast_forward() {
+    let repo = make_test_repo("merge-ff");
+    let base = commit_file(&repo, "file.txt", "base\n", "Base");
+    checkout_new_branch(&repo, "theirs", base);
+    let theirs = commit_file(&repo, "file.txt", "theirs\n", "Theirs");
+    checkout_new_branch(&repo, "ours", base);
+
+    let ff_only = MergeArgs {
+        ff_only: true,
+        ..MergeArgs::default()
+    };
+    let outcome = merge(&repo, "theirs", &ff_only).unwrap();
+    assert_eq!(outcome, MergeOutcome::FastForward(theirs));
+    assert_eq!(repo.head().unwrap().shorthand(), Some("ours"));
+    assert_eq!(repo.head().unwrap().target(), Some(theirs));
+
+    let outcome = merge(&repo, "theirs", &MergeArgs::default()).unwrap();
+    assert_eq!(outcome, MergeOutcome::UpToDate);
+}
+
+include!(concat!(env!("CARGO_MANIFEST_DIR"), "/out/merge_exports.rs"));
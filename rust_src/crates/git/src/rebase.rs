@@ -0,0 +1,229 @@
+#[cfg(test)]
+use git2::RepositoryState;
+use git2::{ErrorCode, Rebase, RebaseOperation, RebaseOperationType, Repository};
+
+use lisp_macros::lisp_fn;
+
+use emacs::globals::{
+    QCconflicts, QCexec, QCindex, QCoid, QCtype, Qedit, Qexec, Qfixup, Qnil, Qpick, Qreword,
+    Qsquash,
+};
+use emacs::lisp::LispObject;
+use emacs::multibyte::LispStringRef;
+
+use crate::merge::index_conflicts;
+#[cfg(test)]
+use crate::repository::{checkout_new_branch, commit_file, make_test_repo};
+use crate::repository::{make_list, open_repository, optional_string, signal_git_error};
+
+fn operation_to_lisp(operation: &RebaseOperation) -> LispObject {
+    let kind = match operation.kind() {
+        Some(RebaseOperationType::Pick) => Qpick,
+        Some(RebaseOperationType::Reword) => Qreword,
+        Some(RebaseOperationType::Edit) => Qedit,
+        Some(RebaseOperationType::Squash) => Qsquash,
+        Some(RebaseOperationType::Fixup) => Qfixup,
+        Some(RebaseOperationType::Exec) => Qexec,
+        None => Qnil,
+    };
+
+    list!(
+        QCtype,
+        kind,
+        QCoid,
+        LispObject::from(operation.id().to_string().as_str()),
+        QCexec,
+        operation.exec().map_or(Qnil, LispObject::from)
+    )
+}
+
+fn open_rebase(repo: &Repository) -> Rebase<'_> {
+    match repo.open_rebase(None) {
+        Ok(rebase) => rebase,
+        Err(e) => signal_git_error("Error opening rebase", e),
+    }
+}
+
+fn start_rebase<'repo>(
+    repo: &'repo Repository,
+    upstream: &str,
+    onto: Option<&str>,
+) -> Result<Rebase<'repo>, git2::Error> {
+    let annotated = |rev: &str| {
+        let commit = repo.revparse_single(rev)?.peel_to_commit()?;
+        repo.find_annotated_commit(commit.id())
+    };
+    let upstream = annotated(upstream)?;
+    let onto = match onto {
+        Some(onto) => Some(annotated(onto)?),
+        None => None,
+    };
+
+    repo.rebase(None, Some(&upstream), onto.as_ref(), None)
+}
+
+/// Start rebasing the current branch of the repository at PATH onto
+/// UPSTREAM, a revision spec.  If ONTO is non-nil, the commits are
+/// replayed on top of that revision instead of UPSTREAM.
+///
+/// No commit is replayed yet: the rebase is driven one operation at a
+/// time with `git-rebase-next' and `git-rebase-commit', and concluded
+/// with `git-rebase-finish' or `git-rebase-abort'.  Return the planned
+/// operations, each a plist with the keys :type, :oid and :exec.
+#[lisp_fn(min = "2")]
+pub fn git_rebase_start(path: LispObject, upstream: LispStringRef, onto: LispObject) -> LispObject {
+    let repo = open_repository(&path);
+    let onto = optional_string(onto);
+
+    let mut rebase = match start_rebase(&repo, &upstream.to_utf8(), onto.as_deref()) {
+        Ok(rebase) => rebase,
+        Err(e) => signal_git_error("Error starting rebase", e),
+    };
+    let operations = (0..rebase.len())
+        .filter_map(|idx| rebase.nth(idx).map(|op| operation_to_lisp(&op)))
+        .collect();
+
+    make_list(operations)
+}
+
+/// Apply the next operation of the rebase in progress in the repository
+/// at PATH to the index and working directory.  Return nil when all
+/// operations were applied.  Otherwise return a plist with the :index
+/// of the operation, its :type, :oid and :exec, and the :conflicts that
+/// must be resolved and staged before calling `git-rebase-commit', as
+/// plists with the keys :path, :ancestor, :ours and :theirs.
+#[lisp_fn]
+pub fn git_rebase_next(path: LispObject) -> LispObject {
+    let repo = open_repository(&path);
+    let mut rebase = open_rebase(&repo);
+
+    let operation = match rebase.next() {
+        Some(Ok(operation)) => operation_to_lisp(&operation),
+        Some(Err(e)) => signal_git_error("Error applying rebase operation", e),
+        None => return Qnil,
+    };
+    let index = rebase.operation_current().map_or(Qnil, LispObject::from);
+    let conflicts = match repo.index() {
+        Ok(index) => index_conflicts(&index),
+        Err(e) => signal_git_error("Error reading index", e),
+    };
+
+    LispObject::cons(
+        QCindex,
+        LispObject::cons(
+            index,
+            LispObject::cons(QCconflicts, LispObject::cons(conflicts, operation)),
+        ),
+    )
+}
+
+/// Commit the current operation of the rebase in progress in the
+/// repository at PATH, keeping its original author.  MESSAGE replaces
+/// the original commit message when non-nil.  Return the object id of
+/// the new commit, or nil when the changes of the operation were
+/// already applied upstream and there was nothing to commit.
+#[lisp_fn(min = "1")]
+pub fn git_rebase_commit(path: LispObject, message: LispObject) -> LispObject {
+    let repo = open_repository(&path);
+    let mut rebase = open_rebase(&repo);
+    let message = optional_string(message);
+
+    let committer = match repo.signature() {
+        Ok(signature) => signature,
+        Err(e) => signal_git_error("Error reading signature", e),
+    };
+
+    match rebase.commit(None, &committer, message.as_deref()) {
+        Ok(oid) => LispObject::from(oid.to_string().as_str()),
+        Err(e) if e.code() == ErrorCode::Applied => Qnil,
+        Err(e) => signal_git_error("Error committing rebase operation", e),
+    }
+}
+
+/// Conclude the rebase in progress in the repository at PATH, pointing
+/// the rebased branch to the last rewritten commit.
+#[lisp_fn]
+pub fn git_rebase_finish(path: LispObject) -> bool {
+    let repo = open_repository(&path);
+    let mut rebase = open_rebase(&repo);
+
+    if let Err(e) = rebase.finish(None) {
+        signal_git_error("Error finishing rebase", e);
+    }
+
+    true
+}
+
+/// Abort the rebase in progress in the repository at PATH, restoring
+/// the branch, index and working directory to their state before the
+/// rebase started.
+#[lisp_fn]
+pub fn git_rebase_abort(path: LispObject) -> bool {
+    let repo = open_repository(&path);
+    let mut rebase = open_rebase(&repo);
+
+    if let Err(e) = rebase.abort() {
+        signal_git_error("Error aborting rebase", e);
+    }
+
+    true
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCexec, ":exec");
+    def_lisp_sym!(Qpick, "pick");
+    def_lisp_sym!(Qreword, "reword");
+    def_lisp_sym!(Qedit, "edit");
+    def_lisp_sym!(Qsquash, "squash");
+    def_lisp_sym!(Qfixup, "fixup");
+    def_lisp_sym!(Qexec, "exec");
+}
+
+#[test]
+fn test_rebase_state() {
+    let repo = make_test_repo("rebase");
+    let base = commit_file(&repo, "base.txt", "base\n", "Base");
+    checkout_new_branch(&repo, "upstream", base);
+    commit_file(&repo, "upstream.txt", "upstream\n", "Upstream");
+    checkout_new_branch(&repo, "topic", base);
+    let topic = commit_file(&repo, "topic.txt", "topic\n", "Topic");
+
+    let mut rebase = start_rebase(&repo, "upstream", None).unwrap();
+    assert_eq!(rebase.len(), 1);
+    assert_eq!(rebase.nth(0).unwrap().id(), topic);
+    assert_eq!(repo.state(), RepositoryState::RebaseMerge);
+
+    // The rebase in progress is found again by the next calls
+    let mut rebase = repo.open_rebase(None).unwrap();
+    rebase.abort().unwrap();
+    assert_eq!(repo.state(), RepositoryState::Clean);
+    assert_eq!(repo.head().unwrap().target(), Some(topic));
+}
+
+#[test]
+fn test_rebase_onto() {
+    let repo = make_test_repo("rebase-onto");
+    let base = commit_file(&repo, "base.txt", "base\n", "Base");
+    checkout_new_branch(&repo, "upstream", base);
+    let upstream = commit_file(&repo, "upstream.txt", "upstream\n", "Upstream");
+    checkout_new_branch(&repo, "topic", upstream);
+    commit_file(&repo, "topic.txt", "topic\n", "Topic");
+
+    // The commits after UPSTREAM are replayed on top of ONTO
+    let mut rebase = start_rebase(&repo, "upstream", Some("HEAD~2")).unwrap();
+    assert_eq!(rebase.len(), 1);
+    rebase.next().unwrap().unwrap();
+    let signature = repo.signature().unwrap();
+    let oid = rebase.commit(None, &signature, None).unwrap();
+    rebase.finish(None).unwrap();
+
+    assert_eq!(repo.find_commit(oid).unwrap().parent_id(0).unwrap(), base);
+    assert_eq!(repo.head().unwrap().target(), Some(oid));
+    assert_eq!(repo.state(), RepositoryState::Clean);
+}
+
+include!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/out/rebase_exports.rs"
+));
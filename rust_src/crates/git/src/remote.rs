@@ -0,0 +1,350 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::Sender;
+use git2::{FetchOptions, PushOptions, Remote, RemoteCallbacks, Repository};
+
+use lisp_macros::lisp_fn;
+
+use ng_async::ng_async::{
+    eprint_if_unexpected_error, to_owned_userdata, worker_outcome, EmacsPipe, PipeDataOption,
+    UserData,
+};
+
+use emacs::globals::{
+    QCbytes, QCcurrent, QCindexed_deltas, QCindexed_objects, QClocal_objects, QCreceived_bytes,
+    QCreceived_objects, QCrejected, QCtext, QCtotal, QCtotal_deltas, QCtotal_objects, QCtype,
+    Qdone, Qfetch_progress, Qpush_progress, Qsideband,
+};
+use emacs::lisp::LispObject;
+use emacs::multibyte::LispStringRef;
+
+use crate::repository::{make_list, open_repository, string_list};
+
+// Minimal delay between two progress events, so that a large transfer
+// does not flood the Lisp thread with messages.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+// Events sent from the transfer thread to the Lisp handler. They are
+// converted to Lisp data by git-transfer-event on the Lisp thread.
+enum TransferEvent {
+    FetchProgress {
+        total_objects: usize,
+        indexed_objects: usize,
+        received_objects: usize,
+        local_objects: usize,
+        total_deltas: usize,
+        indexed_deltas: usize,
+        received_bytes: usize,
+    },
+    PushProgress {
+        current: usize,
+        total: usize,
+        bytes: usize,
+    },
+    Sideband(String),
+    Done(Vec<(String, String)>),
+}
+
+#[derive(Clone)]
+struct EventSink {
+    pipe: EmacsPipe,
+    sender: Sender<String>,
+}
+
+impl EventSink {
+    fn new(pipe: EmacsPipe) -> Self {
+        let sender = pipe.get_sender();
+        EventSink { pipe, sender }
+    }
+
+    // Returns false once the Lisp side is gone, which aborts the
+    // transfer.
+    fn send(&mut self, event: TransferEvent) -> bool {
+        self.pipe
+            .message_lisp(&self.sender, UserData::new(event))
+            .is_ok()
+    }
+}
+
+fn throttled(last: &mut Option<Instant>, finished: bool) -> bool {
+    let now = Instant::now();
+    match last {
+        Some(time) if !finished && now.duration_since(*time) < PROGRESS_INTERVAL => true,
+        _ => {
+            *last = Some(now);
+            false
+        }
+    }
+}
+
+// Look REMOTE up by name, falling back to an anonymous remote for
+// URLs and paths.
+fn find_remote<'repo>(repo: &'repo Repository, remote: &str) -> Result<Remote<'repo>, git2::Error> {
+    repo.find_remote(remote)
+        .or_else(|_| repo.remote_anonymous(remote))
+}
+
+fn make_callbacks<'a>(sink: &EventSink) -> RemoteCallbacks<'a> {
+    let mut callbacks = RemoteCallbacks::new();
+
+    let mut fetch_sink = sink.clone();
+    let mut last_fetch = None;
+    callbacks.transfer_progress(move |stats| {
+        let finished = stats.indexed_deltas() == stats.total_deltas()
+            && stats.received_objects() == stats.total_objects();
+        if throttled(&mut last_fetch, finished) {
+            return true;
+        }
+        fetch_sink.send(TransferEvent::FetchProgress {
+            total_objects: stats.total_objects(),
+            indexed_objects: stats.indexed_objects(),
+            received_objects: stats.received_objects(),
+            local_objects: stats.local_objects(),
+            total_deltas: stats.total_deltas(),
+            indexed_deltas: stats.indexed_deltas(),
+            received_bytes: stats.received_bytes(),
+        })
+    });
+
+    let mut push_sink = sink.clone();
+    let mut last_push = None;
+    callbacks.push_transfer_progress(move |current, total, bytes| {
+        if !throttled(&mut last_push, current == total) {
+            push_sink.send(TransferEvent::PushProgress {
+                current,
+                total,
+                bytes,
+            });
+        }
+    });
+
+    let mut sideband_sink = sink.clone();
+    callbacks.sideband_progress(move |text| {
+        sideband_sink.send(TransferEvent::Sideband(
+            String::from_utf8_lossy(text).into_owned(),
+        ))
+    });
+
+    callbacks
+}
+
+fn fetch(
+    repo: &Repository,
+    remote: &str,
+    refspecs: &[String],
+    sink: &EventSink,
+) -> Result<TransferEvent, git2::Error> {
+    let mut remote = find_remote(repo, remote)?;
+    let mut opts = FetchOptions::new();
+    opts.remote_callbacks(make_callbacks(sink));
+    remote.fetch(refspecs, Some(&mut opts), None)?;
+
+    Ok(TransferEvent::Done(vec![]))
+}
+
+fn push(
+    repo: &Repository,
+    remote: &str,
+    refspecs: &[String],
+    sink: &EventSink,
+) -> Result<TransferEvent, git2::Error> {
+    let mut rejected = vec![];
+    find_remote(repo, remote).and_then(|mut remote| {
+        let mut callbacks = make_callbacks(sink);
+        callbacks.push_update_reference(|reference, status| {
+            if let Some(status) = status {
+                rejected.push((reference.to_string(), status.to_string()));
+            }
+            Ok(())
+        });
+
+        let mut opts = PushOptions::new();
+        opts.remote_callbacks(callbacks);
+        remote.push(refspecs, Some(&mut opts))
+    })?;
+
+    Ok(TransferEvent::Done(rejected))
+}
+
+// Run TRANSFER on a new thread, and return the process through which it
+// reports to HANDLER. Its final event is sent last, while its failures
+// and panics are reported as for rust_worker.
+fn spawn_transfer<T>(handler: LispObject, transfer: T) -> LispObject
+where
+    T: 'static + FnOnce(&EventSink) -> Result<TransferEvent, git2::Error> + Send,
+{
+    let (pipe, proc) = EmacsPipe::with_handler(
+        handler,
+        PipeDataOption::USER_DATA,
+        PipeDataOption::USER_DATA,
+    );
+
+    let mut sink = EventSink::new(pipe);
+    thread::spawn(move || {
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+            transfer(&sink)
+                .map(UserData::new)
+                .map_err(|e| e.message().to_string())
+        }));
+        let sent = match worker_outcome(outcome, None) {
+            Ok(event) => sink.pipe.message_lisp(&sink.sender, event),
+            Err(error) => sink.pipe.message_lisp_error(&sink.sender, error),
+        };
+        if let Err(err) = sent {
+            eprint_if_unexpected_error(err);
+        }
+    });
+
+    proc
+}
+
+/// Fetch from REMOTE into the repository at PATH without blocking.
+/// REMOTE is the name of a configured remote, or a URL or path.
+/// REFSPECS is a string or a list of strings; when nil, the refspecs
+/// configured for the remote are used.
+///
+/// The transfer runs in a separate thread and reports to HANDLER, a
+/// function called with the pipe process returned by this function and
+/// an event.  Events are user-ptr objects that should be converted
+/// with `git-transfer-event'.  The last event is of type `done', unless
+/// the transfer failed: HANDLER then receives an `async-error' record
+/// instead, as described in `async-send-message'.
+#[lisp_fn(min = "3")]
+pub fn git_fetch_async(
+    path: LispObject,
+    remote: LispStringRef,
+    handler: LispObject,
+    refspecs: LispObject,
+) -> LispObject {
+    let repo = open_repository(&path).into_owned();
+    let remote = remote.to_utf8();
+    let refspecs = string_list(refspecs);
+
+    spawn_transfer(handler, move |sink| fetch(&repo, &remote, &refspecs, sink))
+}
+
+/// Push REFSPECS of the repository at PATH to REMOTE without blocking.
+/// REMOTE is the name of a configured remote, or a URL or path.
+/// REFSPECS is a string or a list of strings, such as
+/// "refs/heads/main:refs/heads/main"; when nil, the push refspecs
+/// configured for the remote are used.
+///
+/// HANDLER receives the events of the transfer as for
+/// `git-fetch-async'.  References refused by the remote are reported
+/// by the final `done' event.
+#[lisp_fn(min = "3")]
+pub fn git_push_async(
+    path: LispObject,
+    remote: LispStringRef,
+    handler: LispObject,
+    refspecs: LispObject,
+) -> LispObject {
+    let repo = open_repository(&path).into_owned();
+    let remote = remote.to_utf8();
+    let refspecs = string_list(refspecs);
+
+    spawn_transfer(handler, move |sink| push(&repo, &remote, &refspecs, sink))
+}
+
+/// Convert DATA, an event received by the handler of `git-fetch-async'
+/// or `git-push-async', to a plist.  The :type of the event is one of:
+///
+/// `fetch-progress', with the keys :total-objects, :indexed-objects,
+/// :received-objects, :local-objects, :total-deltas, :indexed-deltas
+/// and :received-bytes.
+///
+/// `push-progress', with the keys :current, :total and :bytes.
+///
+/// `sideband', with the progress output of the remote as :text.
+///
+/// `done', where :rejected is an alist of (REF . REASON) for the
+/// references the remote refused to update.
+#[lisp_fn]
+pub fn git_transfer_event(data: LispObject) -> LispObject {
+    let user_data: UserData = to_owned_userdata(data);
+    let event: TransferEvent = unsafe { user_data.unpack() };
+
+    match event {
+        TransferEvent::FetchProgress {
+            total_objects,
+            indexed_objects,
+            received_objects,
+            local_objects,
+            total_deltas,
+            indexed_deltas,
+            received_bytes,
+        } => list!(
+            QCtype,
+            Qfetch_progress,
+            QCtotal_objects,
+            LispObject::from(total_objects),
+            QCindexed_objects,
+            LispObject::from(indexed_objects),
+            QCreceived_objects,
+            LispObject::from(received_objects),
+            QClocal_objects,
+            LispObject::from(local_objects),
+            QCtotal_deltas,
+            LispObject::from(total_deltas),
+            QCindexed_deltas,
+            LispObject::from(indexed_deltas),
+            QCreceived_bytes,
+            LispObject::from(received_bytes)
+        ),
+        TransferEvent::PushProgress {
+            current,
+            total,
+            bytes,
+        } => list!(
+            QCtype,
+            Qpush_progress,
+            QCcurrent,
+            LispObject::from(current),
+            QCtotal,
+            LispObject::from(total),
+            QCbytes,
+            LispObject::from(bytes)
+        ),
+        TransferEvent::Sideband(text) => list!(QCtype, Qsideband, QCtext, text.as_str()),
+        TransferEvent::Done(rejected) => {
+            let rejected = rejected
+                .iter()
+                .map(|(reference, reason)| {
+                    LispObject::cons(
+                        LispObject::from(reference.as_str()),
+                        LispObject::from(reason.as_str()),
+                    )
+                })
+                .collect();
+            list!(QCtype, Qdone, QCrejected, make_list(rejected))
+        }
+    }
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCtotal_objects, ":total-objects");
+    def_lisp_sym!(QCindexed_objects, ":indexed-objects");
+    def_lisp_sym!(QCreceived_objects, ":received-objects");
+    def_lisp_sym!(QClocal_objects, ":local-objects");
+    def_lisp_sym!(QCtotal_deltas, ":total-deltas");
+    def_lisp_sym!(QCindexed_deltas, ":indexed-deltas");
+    def_lisp_sym!(QCreceived_bytes, ":received-bytes");
+    def_lisp_sym!(QCcurrent, ":current");
+    def_lisp_sym!(QCtotal, ":total");
+    def_lisp_sym!(QCbytes, ":bytes");
+    def_lisp_sym!(QCtext, ":text");
+    def_lisp_sym!(QCrejected, ":rejected");
+    def_lisp_sym!(QCmessage, ":message");
+    def_lisp_sym!(Qfetch_progress, "fetch-progress");
+    def_lisp_sym!(Qpush_progress, "push-progress");
+    def_lisp_sym!(Qsideband, "sideband");
+    def_lisp_sym!(Qdone, "done");
+}
+
+include!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/out/remote_exports.rs"
+));
@@ -1,21 +1,365 @@
+use std::convert::TryInto;
+use std::ops::Deref;
 use std::path::Path;
+#[cfg(test)]
+use std::{env, fs, process};
 
-use git2::Repository;
+#[cfg(test)]
+use git2::{build::CheckoutBuilder, Commit};
+use git2::{ErrorCode, Oid, ReferenceType, Repository, RepositoryState};
 
 use lisp_macros::lisp_fn;
 
+use ng_async::ng_async::UserData;
+
+use emacs::bindings::{make_int, Flist, Fput};
+use emacs::globals::{
+    QCdetached, QCname, QCoid, QCshorthand, QCunborn, Qapply_mailbox, Qapply_mailbox_or_rebase,
+    Qbisect, Qcherry_pick, Qcherry_pick_sequence, Qclean, Qerror, Qerror_conditions,
+    Qerror_message, Qgit_error, Qmerge, Qnil, Qplistp, Qrebase, Qrebase_interactive, Qrebase_merge,
+    Qrevert, Qrevert_sequence,
+};
+use emacs::lisp::LispObject;
+use emacs::list::{LispCons, LispConsCircularChecks, LispConsEndChecks};
 use emacs::multibyte::LispStringRef;
 
+/// Signal a `git-error' for the libgit2 error E.  The error data is
+/// (MESSAGE CODE CLASS), where MESSAGE starts with CONTEXT and CODE and
+/// CLASS are the raw libgit2 error code and class.
+pub fn signal_git_error(context: &str, e: git2::Error) -> ! {
+    let message = format!("{}: {}", context, e.message());
+    xsignal!(
+        Qgit_error,
+        message.as_str(),
+        unsafe { make_int(e.raw_code() as i64) },
+        unsafe { make_int(e.raw_class() as i64) }
+    );
+}
+
 #[lisp_fn]
 pub fn git_init(path: LispStringRef) -> LispStringRef {
     match Repository::init(Path::new(path.to_utf8().as_str())) {
         Ok(_repo) => path,
-        Err(e) => {
-            error!("Error initializing repository {:?}", e);
+        Err(e) => signal_git_error("Error initializing repository", e),
+    }
+}
+
+unsafe extern "C" fn finalize_repository(raw: *mut libc::c_void) {
+    let _repo = Box::from_raw(raw as *mut Repository);
+}
+
+fn as_repository_handle(object: &LispObject) -> Option<&Repository> {
+    unsafe { object.as_userdata_with_finalizer(finalize_repository) }
+}
+
+/// A repository opened from a path, or borrowed from a handle created
+/// by git-repository-open for the duration of a call.
+pub enum RepositoryRef<'a> {
+    Owned(Repository),
+    Handle(&'a Repository),
+}
+
+impl<'a> RepositoryRef<'a> {
+    /// Return a repository that can be moved to another thread or
+    /// mutated. A handle stays owned by Lisp, so it is reopened from its
+    /// path.
+    pub fn into_owned(self) -> Repository {
+        match self {
+            RepositoryRef::Owned(repo) => repo,
+            RepositoryRef::Handle(repo) => Repository::open(repo.path())
+                .unwrap_or_else(|e| signal_git_error("Error opening repository", e)),
+        }
+    }
+}
+
+impl<'a> Deref for RepositoryRef<'a> {
+    type Target = Repository;
+
+    fn deref(&self) -> &Repository {
+        match self {
+            RepositoryRef::Owned(repo) => repo,
+            RepositoryRef::Handle(repo) => repo,
+        }
+    }
+}
+
+/// Open the repository designated by OBJECT, either a path or a
+/// repository handle, signaling a Lisp error on failure. A handle is
+/// borrowed as long as OBJECT.
+pub fn open_repository(object: &LispObject) -> RepositoryRef<'_> {
+    if let Some(repo) = as_repository_handle(object) {
+        return RepositoryRef::Handle(repo);
+    }
+
+    let path: LispStringRef = (*object).into();
+    match Repository::open(Path::new(path.to_utf8().as_str())) {
+        Ok(repo) => RepositoryRef::Owned(repo),
+        Err(e) => signal_git_error("Error opening repository", e),
+    }
+}
+
+/// Open the repository at PATH and return a handle to it.
+/// The handle is a user-ptr that keeps the repository open until it is
+/// garbage collected.  Every git function taking the PATH of a
+/// repository also accepts a handle in its place, which avoids opening
+/// the repository again on each call.
+#[lisp_fn]
+pub fn git_repository_open(path: LispStringRef) -> LispObject {
+    let repo = match Repository::open(Path::new(path.to_utf8().as_str())) {
+        Ok(repo) => repo,
+        Err(e) => signal_git_error("Error opening repository", e),
+    };
+
+    let data = Box::into_raw(Box::new(repo)) as *mut libc::c_void;
+    UserData::with_data_and_finalizer(data, Some(finalize_repository)).into()
+}
+
+/// Return t if OBJECT is a repository handle made by
+/// `git-repository-open'.
+#[lisp_fn]
+pub fn git_repository_p(object: LispObject) -> bool {
+    as_repository_handle(&object).is_some()
+}
+
+/// Return the working directory of the repository at PATH, or nil for
+/// a bare repository.
+#[lisp_fn]
+pub fn git_repository_workdir(path: LispObject) -> LispObject {
+    let repo = open_repository(&path);
+    repo.workdir().map_or(Qnil, |workdir| {
+        LispObject::from(workdir.to_string_lossy().as_ref())
+    })
+}
+
+/// Return the HEAD of the repository at PATH as a plist.
+/// :name is the full name of the reference HEAD points to, such as
+/// "refs/heads/main", and :shorthand its short form.  :oid is the
+/// commit HEAD resolves to, or nil when :unborn is non-nil because the
+/// current branch has no commit yet.  :detached is non-nil when HEAD
+/// points directly to a commit.
+#[lisp_fn]
+pub fn git_repository_head(path: LispObject) -> LispObject {
+    let repo = open_repository(&path);
+    let detached = repo
+        .head_detached()
+        .unwrap_or_else(|e| signal_git_error("Error reading HEAD", e));
+
+    let head = repo.head();
+    match head {
+        Ok(head) => list!(
+            QCname,
+            head.name().map_or(Qnil, LispObject::from),
+            QCshorthand,
+            head.shorthand().map_or(Qnil, LispObject::from),
+            QCoid,
+            head.target()
+                .map_or(Qnil, |oid| LispObject::from(oid.to_string().as_str())),
+            QCdetached,
+            detached,
+            QCunborn,
+            false
+        ),
+        Err(e) if e.code() == ErrorCode::UnbornBranch => {
+            let name = repo
+                .find_reference("HEAD")
+                .ok()
+                .and_then(|head| head.symbolic_target().map(String::from));
+            let shorthand = name
+                .as_ref()
+                .map(|name| name.trim_start_matches("refs/heads/").to_string());
+            list!(
+                QCname,
+                name.as_deref().map_or(Qnil, LispObject::from),
+                QCshorthand,
+                shorthand.as_deref().map_or(Qnil, LispObject::from),
+                QCoid,
+                Qnil,
+                QCdetached,
+                false,
+                QCunborn,
+                true
+            )
         }
+        Err(e) => signal_git_error("Error reading HEAD", e),
+    }
+}
+
+/// Return the state of the repository at PATH, the operation in
+/// progress.  This is one of the symbols `clean', `merge', `revert',
+/// `revert-sequence', `cherry-pick', `cherry-pick-sequence', `bisect',
+/// `rebase', `rebase-interactive', `rebase-merge', `apply-mailbox' and
+/// `apply-mailbox-or-rebase'.
+#[lisp_fn]
+pub fn git_repository_state(path: LispObject) -> LispObject {
+    let repo = open_repository(&path);
+    match repo.state() {
+        RepositoryState::Clean => Qclean,
+        RepositoryState::Merge => Qmerge,
+        RepositoryState::Revert => Qrevert,
+        RepositoryState::RevertSequence => Qrevert_sequence,
+        RepositoryState::CherryPick => Qcherry_pick,
+        RepositoryState::CherryPickSequence => Qcherry_pick_sequence,
+        RepositoryState::Bisect => Qbisect,
+        RepositoryState::Rebase => Qrebase,
+        RepositoryState::RebaseInteractive => Qrebase_interactive,
+        RepositoryState::RebaseMerge => Qrebase_merge,
+        RepositoryState::ApplyMailbox => Qapply_mailbox,
+        RepositoryState::ApplyMailboxOrRebase => Qapply_mailbox_or_rebase,
+    }
+}
+
+/// Convert OBJECT, either a string or a list of strings, into a vector
+/// of strings. NIL yields an empty vector.
+pub fn string_list(object: LispObject) -> Vec<String> {
+    let mut strings: Vec<String> = vec![];
+    if object.is_nil() {
+        return strings;
+    }
+
+    if let Some(string_ref) = object.as_string() {
+        strings.push(string_ref.to_utf8());
+        return strings;
+    }
+
+    let list: LispCons = object.into();
+    list.iter_cars(LispConsEndChecks::on, LispConsCircularChecks::on)
+        .for_each(|x| {
+            if let Some(string_ref) = x.as_string() {
+                strings.push(string_ref.to_utf8());
+            } else {
+                error!("Expected a string or a list of strings");
+            }
+        });
+
+    strings
+}
+
+/// Convert VALUE, either nil or a string, into an optional string.
+pub fn optional_string(value: LispObject) -> Option<String> {
+    if value.is_nil() {
+        None
+    } else {
+        let string: LispStringRef = value.into();
+        Some(string.to_utf8())
+    }
+}
+
+/// Build a Lisp list holding ITEMS in order.
+pub fn make_list(items: Vec<LispObject>) -> LispObject {
+    items
+        .into_iter()
+        .rev()
+        .fold(Qnil, |result, item| LispObject::cons(item, result))
+}
+
+/// Signal a `wrong-type-argument' error unless ARGS can be read as a
+/// plist of option keywords and values.
+pub fn check_plist_args(args: &[LispObject]) {
+    if args.len() % 2 != 0 {
+        wrong_type!(Qplistp, unsafe {
+            Flist(
+                args.len().try_into().unwrap(),
+                args.as_ptr() as *mut LispObject,
+            )
+        });
+    }
+}
+
+/// Point the reference NAME at OID. For a symbolic HEAD, the branch it
+/// refers to is updated instead, even when that branch does not exist
+/// yet.
+pub fn update_reference(
+    repo: &Repository,
+    name: &str,
+    oid: Oid,
+    log_message: &str,
+) -> Result<(), git2::Error> {
+    let target = match repo.find_reference(name) {
+        Ok(reference) if reference.kind() == Some(ReferenceType::Symbolic) => reference
+            .symbolic_target()
+            .map(|target| target.to_string())
+            .unwrap_or_else(|| name.to_string()),
+        _ => name.to_string(),
+    };
+
+    repo.reference(&target, oid, true, log_message).map(|_| ())
+}
+
+/// Create an empty repository NAME in the temporary directory, with an
+/// identity to commit with.
+#[cfg(test)]
+pub fn make_test_repo(name: &str) -> Repository {
+    let dir = env::temp_dir().join(format!("git-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let repo = Repository::init(&dir).unwrap();
+    let mut config = repo.config().unwrap();
+    config.set_str("user.name", "Test").unwrap();
+    config.set_str("user.email", "test@example.com").unwrap();
+    repo
+}
+
+/// Write CONTENTS to the file PATH of REPO, then commit it on HEAD.
+#[cfg(test)]
+pub fn commit_file(repo: &Repository, path: &str, contents: &str, message: &str) -> Oid {
+    fs::write(repo.workdir().unwrap().join(path), contents).unwrap();
+    let mut index = repo.index().unwrap();
+    index.add_path(Path::new(path)).unwrap();
+    index.write().unwrap();
+
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let signature = repo.signature().unwrap();
+    let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+    let parents: Vec<&Commit> = parent.iter().collect();
+    repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        message,
+        &tree,
+        &parents,
+    )
+    .unwrap()
+}
+
+/// Create the branch NAME at OID and check it out.
+#[cfg(test)]
+pub fn checkout_new_branch(repo: &Repository, name: &str, oid: Oid) {
+    repo.branch(name, &repo.find_commit(oid).unwrap(), false)
+        .unwrap();
+    repo.set_head(&format!("refs/heads/{}", name)).unwrap();
+    repo.checkout_head(Some(CheckoutBuilder::new().force()))
+        .unwrap();
+}
+
+// Give git-error its conditions, like define-error. This is called at
+// startup, see build.rs.
+pub fn init_errors() {
+    unsafe {
+        Fput(Qgit_error, Qerror_conditions, list!(Qgit_error, Qerror));
+        Fput(Qgit_error, Qerror_message, LispObject::from("Git error"));
     }
 }
 
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(Qgit_error, "git-error");
+    def_lisp_sym!(QCshorthand, ":shorthand");
+    def_lisp_sym!(QCdetached, ":detached");
+    def_lisp_sym!(QCunborn, ":unborn");
+    def_lisp_sym!(Qclean, "clean");
+    def_lisp_sym!(Qmerge, "merge");
+    def_lisp_sym!(Qrevert, "revert");
+    def_lisp_sym!(Qrevert_sequence, "revert-sequence");
+    def_lisp_sym!(Qcherry_pick, "cherry-pick");
+    def_lisp_sym!(Qcherry_pick_sequence, "cherry-pick-sequence");
+    def_lisp_sym!(Qbisect, "bisect");
+    def_lisp_sym!(Qrebase, "rebase");
+    def_lisp_sym!(Qrebase_interactive, "rebase-interactive");
+    def_lisp_sym!(Qrebase_merge, "rebase-merge");
+    def_lisp_sym!(Qapply_mailbox, "apply-mailbox");
+    def_lisp_sym!(Qapply_mailbox_or_rebase, "apply-mailbox-or-rebase");
+}
+
 include!(concat!(
     env!("CARGO_MANIFEST_DIR"),
     "/out/repository_exports.rs"
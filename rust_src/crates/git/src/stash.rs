@@ -0,0 +1,218 @@
+#[cfg(test)]
+use std::fs;
+
+use git2::{ErrorCode, Index, Oid, Repository, StashApplyOptions, StashFlags};
+
+use lisp_macros::lisp_fn;
+
+use emacs::globals::{QCinclude_untracked, QCindex, QCkeep_index, QCmessage, QCoid, Qnil};
+use emacs::lisp::LispObject;
+
+use crate::merge::index_conflicts;
+use crate::repository::{
+    check_plist_args, make_list, open_repository, optional_string, signal_git_error,
+};
+#[cfg(test)]
+use crate::repository::{commit_file, make_test_repo};
+
+fn stash_index(index: LispObject) -> usize {
+    if index.is_nil() {
+        0
+    } else {
+        index.as_natnum_or_error() as usize
+    }
+}
+
+// Apply the stash at INDEX and return the index, holding the conflicts
+// it left.
+fn stash_apply(
+    repo: &mut Repository,
+    index: usize,
+    reinstate_index: bool,
+) -> Result<Index, git2::Error> {
+    let mut opts = StashApplyOptions::new();
+    if reinstate_index {
+        opts.reinstantiate_index();
+    }
+
+    repo.stash_apply(index, Some(&mut opts))?;
+    repo.index()
+}
+
+// Apply the stash at INDEX, then drop it unless it caused conflicts,
+// like 'git stash pop'.
+fn stash_pop(
+    repo: &mut Repository,
+    index: usize,
+    reinstate_index: bool,
+) -> Result<Index, git2::Error> {
+    let result = stash_apply(repo, index, reinstate_index)?;
+    if !result.has_conflicts() {
+        repo.stash_drop(index)?;
+    }
+
+    Ok(result)
+}
+
+// The stashes of REPO, most recent first, as their index, message and
+// object id.
+fn stash_entries(repo: &mut Repository) -> Result<Vec<(usize, String, Oid)>, git2::Error> {
+    let mut items = vec![];
+    repo.stash_foreach(|index, message, oid| {
+        items.push((index, message.to_string(), *oid));
+        true
+    })?;
+
+    Ok(items)
+}
+
+/// Save the local changes of the repository at PATH to a new stash and
+/// return its object id, or nil when there is nothing to stash.
+///
+/// OPTIONS is a plist.  :message describes the stash.  If
+/// :include-untracked is non-nil, untracked files are stashed too.  If
+/// :keep-index is non-nil, the staged changes are left in place.
+/// usage: (git-stash-save PATH &rest OPTIONS)
+#[lisp_fn(min = "1")]
+pub fn git_stash_save(args: &[LispObject]) -> LispObject {
+    let mut repo = open_repository(&args[0]).into_owned();
+    let mut message = None;
+    let mut flags = StashFlags::DEFAULT;
+
+    let options = &args[1..];
+    check_plist_args(options);
+    for pair in options.chunks(2) {
+        let (key, value) = (pair[0], pair[1]);
+        match key {
+            QCmessage => message = optional_string(value),
+            QCinclude_untracked => flags.set(StashFlags::INCLUDE_UNTRACKED, value.is_not_nil()),
+            QCkeep_index => flags.set(StashFlags::KEEP_INDEX, value.is_not_nil()),
+            _ => error!("Wrong type: must be :message, :include-untracked, :keep-index"),
+        }
+    }
+
+    let signature = match repo.signature() {
+        Ok(signature) => signature,
+        Err(e) => signal_git_error("Error reading signature", e),
+    };
+
+    match repo.stash_save2(&signature, message.as_deref(), Some(flags)) {
+        Ok(oid) => LispObject::from(oid.to_string().as_str()),
+        Err(e) if e.code() == ErrorCode::NotFound => Qnil,
+        Err(e) => signal_git_error("Error saving stash", e),
+    }
+}
+
+/// Return the stashes of the repository at PATH, most recent first.
+/// Each stash is a plist with the keys :index, :message and :oid.
+#[lisp_fn]
+pub fn git_stash_list(path: LispObject) -> LispObject {
+    let mut repo = open_repository(&path).into_owned();
+    let items = match stash_entries(&mut repo) {
+        Ok(items) => items,
+        Err(e) => signal_git_error("Error listing stashes", e),
+    };
+
+    let items = items
+        .iter()
+        .map(|(index, message, oid)| {
+            list!(
+                QCindex,
+                LispObject::from(*index),
+                QCmessage,
+                message.as_str(),
+                QCoid,
+                oid.to_string().as_str()
+            )
+        })
+        .collect();
+
+    make_list(items)
+}
+
+/// Apply the stash INDEX, 0 by default, to the repository at PATH.
+/// If REINSTATE-INDEX is non-nil, the staged changes of the stash are
+/// restored in the index too.  Return the resulting conflicts as a list
+/// of plists with the keys :path, :ancestor, :ours and :theirs, or nil
+/// when the stash applied cleanly.
+#[lisp_fn(min = "1")]
+pub fn git_stash_apply(path: LispObject, index: LispObject, reinstate_index: bool) -> LispObject {
+    let mut repo = open_repository(&path).into_owned();
+    match stash_apply(&mut repo, stash_index(index), reinstate_index) {
+        Ok(index) => index_conflicts(&index),
+        Err(e) => signal_git_error("Error applying stash", e),
+    }
+}
+
+/// Apply the stash INDEX like `git-stash-apply', then drop it unless
+/// it caused conflicts.  Return the conflicts like `git-stash-apply'.
+#[lisp_fn(min = "1")]
+pub fn git_stash_pop(path: LispObject, index: LispObject, reinstate_index: bool) -> LispObject {
+    let mut repo = open_repository(&path).into_owned();
+    match stash_pop(&mut repo, stash_index(index), reinstate_index) {
+        Ok(index) => index_conflicts(&index),
+        Err(e) => signal_git_error("Error popping stash", e),
+    }
+}
+
+/// Remove the stash INDEX, 0 by default, from the repository at PATH.
+#[lisp_fn(min = "1")]
+pub fn git_stash_drop(path: LispObject, index: LispObject) -> bool {
+    let mut repo = open_repository(&path).into_owned();
+
+    if let Err(e) = repo.stash_drop(stash_index(index)) {
+        signal_git_error("Error dropping stash", e);
+    }
+
+    true
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCinclude_untracked, ":include-untracked");
+    def_lisp_sym!(QCkeep_index, ":keep-index");
+    def_lisp_sym!(QCindex, ":index");
+}
+
+#[test]
+fn test_stash_indices() {
+    let mut repo = make_test_repo("stash");
+    commit_file(&repo, "file.txt", "base\n", "Base");
+    let path = repo.workdir().unwrap().join("file.txt");
+    let signature = repo.signature().unwrap();
+    for contents in &["first", "second"] {
+        fs::write(&path, format!("{}\n", contents)).unwrap();
+        repo.stash_save(&signature, contents, None).unwrap();
+    }
+
+    // The most recent stash comes first
+    let entries = stash_entries(&mut repo).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].0, 0);
+    assert!(entries[0].1.ends_with("second"));
+    assert_eq!(entries[1].0, 1);
+    assert!(entries[1].1.ends_with("first"));
+
+    let index = stash_pop(&mut repo, 1, false).unwrap();
+    assert!(!index.has_conflicts());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "first\n");
+    let entries = stash_entries(&mut repo).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert!(entries[0].1.ends_with("second"));
+}
+
+#[test]
+fn test_stash_pop_keeps_conflicts() {
+    let mut repo = make_test_repo("stash-conflict");
+    commit_file(&repo, "file.txt", "base\n", "Base");
+    let signature = repo.signature().unwrap();
+    fs::write(repo.workdir().unwrap().join("file.txt"), "stashed\n").unwrap();
+    repo.stash_save(&signature, "stashed", None).unwrap();
+    commit_file(&repo, "file.txt", "committed\n", "Change");
+
+    let index = stash_pop(&mut repo, 0, false).unwrap();
+    assert!(index.has_conflicts());
+    assert_eq!(stash_entries(&mut repo).unwrap().len(), 1);
+}
+
+include!(concat!(env!("CARGO_MANIFEST_DIR"), "/out/stash_exports.rs"));
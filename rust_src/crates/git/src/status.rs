@@ -0,0 +1,123 @@
+use git2::{Status, StatusOptions};
+
+use lisp_macros::lisp_fn;
+
+use emacs::globals::{
+    QCignored, QCpathspec, QCuntracked, Qconflicted, Qignored, Qindex_deleted, Qindex_modified,
+    Qindex_new, Qindex_renamed, Qindex_typechange, Qnil, Qwt_deleted, Qwt_modified, Qwt_new,
+    Qwt_renamed, Qwt_typechange,
+};
+use emacs::lisp::LispObject;
+
+use crate::repository::{check_plist_args, open_repository, signal_git_error, string_list};
+
+const STATUS_FLAGS: [(Status, LispObject); 11] = [
+    (Status::INDEX_NEW, Qindex_new),
+    (Status::INDEX_MODIFIED, Qindex_modified),
+    (Status::INDEX_DELETED, Qindex_deleted),
+    (Status::INDEX_RENAMED, Qindex_renamed),
+    (Status::INDEX_TYPECHANGE, Qindex_typechange),
+    (Status::WT_NEW, Qwt_new),
+    (Status::WT_MODIFIED, Qwt_modified),
+    (Status::WT_DELETED, Qwt_deleted),
+    (Status::WT_TYPECHANGE, Qwt_typechange),
+    (Status::WT_RENAMED, Qwt_renamed),
+    (Status::CONFLICTED, Qconflicted),
+];
+
+fn status_flags(status: Status) -> LispObject {
+    let mut flags = Qnil;
+    if status.contains(Status::IGNORED) {
+        flags = LispObject::cons(Qignored, flags);
+    }
+
+    for (flag, symbol) in STATUS_FLAGS.iter().rev() {
+        if status.contains(*flag) {
+            flags = LispObject::cons(*symbol, flags);
+        }
+    }
+
+    flags
+}
+
+// Untracked files are reported by default, ignored files are not,
+// which matches the output of 'git status --porcelain'.
+fn status_options_from_args(args: &[LispObject]) -> StatusOptions {
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(true).include_ignored(false);
+
+    check_plist_args(args);
+    for pair in args.chunks(2) {
+        let (key, value) = (pair[0], pair[1]);
+        match key {
+            QCpathspec => {
+                for spec in string_list(value) {
+                    opts.pathspec(spec);
+                }
+            }
+            QCuntracked => {
+                opts.include_untracked(value.is_not_nil());
+            }
+            QCignored => {
+                opts.include_ignored(value.is_not_nil());
+            }
+            _ => error!("Wrong type: must be :pathspec, :untracked, :ignored"),
+        }
+    }
+
+    opts
+}
+
+/// Return the status of the files in the repository at PATH.
+/// The result is a list of (FILE . FLAGS) entries, where FILE is
+/// relative to the working directory and FLAGS is a list of the
+/// symbols `index-new', `index-modified', `index-deleted',
+/// `index-renamed', `index-typechange', `wt-new', `wt-modified',
+/// `wt-deleted', `wt-typechange', `wt-renamed', `conflicted' and
+/// `ignored'.
+///
+/// OPTIONS is a plist.  :pathspec is a string or a list of strings
+/// restricting the files that are reported.  If :untracked is nil,
+/// untracked files are omitted.  If :ignored is non-nil, ignored files
+/// are reported as well.
+/// usage: (git-status PATH &rest OPTIONS)
+#[lisp_fn(min = "1")]
+pub fn git_status(args: &[LispObject]) -> LispObject {
+    let repo = open_repository(&args[0]);
+    let mut opts = status_options_from_args(&args[1..]);
+
+    let statuses = match repo.statuses(Some(&mut opts)) {
+        Ok(statuses) => statuses,
+        Err(e) => signal_git_error("Error reading repository status", e),
+    };
+
+    statuses.iter().rev().fold(Qnil, |result, entry| {
+        let file = String::from_utf8_lossy(entry.path_bytes());
+        let item = LispObject::cons(file.as_ref(), status_flags(entry.status()));
+        LispObject::cons(item, result)
+    })
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCpathspec, ":pathspec");
+    def_lisp_sym!(QCuntracked, ":untracked");
+    def_lisp_sym!(QCignored, ":ignored");
+    def_lisp_sym!(Qindex_new, "index-new");
+    def_lisp_sym!(Qindex_modified, "index-modified");
+    def_lisp_sym!(Qindex_deleted, "index-deleted");
+    def_lisp_sym!(Qindex_renamed, "index-renamed");
+    def_lisp_sym!(Qindex_typechange, "index-typechange");
+    def_lisp_sym!(Qwt_new, "wt-new");
+    def_lisp_sym!(Qwt_modified, "wt-modified");
+    def_lisp_sym!(Qwt_deleted, "wt-deleted");
+    def_lisp_sym!(Qwt_typechange, "wt-typechange");
+    def_lisp_sym!(Qwt_renamed, "wt-renamed");
+    def_lisp_sym!(Qconflicted, "conflicted");
+    def_lisp_sym!(Qignored, "ignored");
+}
+
+include!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/out/status_exports.rs"
+));
@@ -0,0 +1,64 @@
+use lisp_macros::lisp_fn;
+
+use emacs::lisp::LispObject;
+use emacs::multibyte::LispStringRef;
+
+use crate::repository::{make_list, open_repository, optional_string, signal_git_error};
+
+/// Create a tag called NAME in the repository at PATH, and return the
+/// object id it points to.  The tag points to the revision TARGET, HEAD
+/// by default.  If MESSAGE is non-nil, an annotated tag whose tagger is
+/// the configured identity is created, otherwise a lightweight one.  If
+/// FORCE is non-nil, an existing tag with the same name is replaced.
+#[lisp_fn(min = "2")]
+pub fn git_tag_create(
+    path: LispObject,
+    name: LispStringRef,
+    target: LispObject,
+    message: LispObject,
+    force: bool,
+) -> LispObject {
+    let repo = open_repository(&path);
+    let target = optional_string(target).unwrap_or_else(|| "HEAD".to_string());
+    let message = optional_string(message);
+    let name = name.to_utf8();
+
+    let result = repo
+        .revparse_single(&target)
+        .and_then(|object| match message {
+            Some(message) => {
+                let tagger = repo.signature()?;
+                repo.tag(&name, &object, &tagger, &message, force)
+            }
+            None => repo.tag_lightweight(&name, &object, force),
+        });
+
+    match result {
+        Ok(oid) => LispObject::from(oid.to_string().as_str()),
+        Err(e) => signal_git_error("Error creating tag", e),
+    }
+}
+
+/// Return the names of the tags of the repository at PATH.
+/// If PATTERN is non-nil, only tags matching that fnmatch pattern,
+/// such as "v1.*", are returned.
+#[lisp_fn(min = "1")]
+pub fn git_tag_list(path: LispObject, pattern: LispObject) -> LispObject {
+    let repo = open_repository(&path);
+    let pattern = optional_string(pattern);
+
+    let names = match repo.tag_names(pattern.as_deref()) {
+        Ok(names) => names,
+        Err(e) => signal_git_error("Error listing tags", e),
+    };
+
+    let items = names
+        .iter()
+        .flatten()
+        .map(|name| LispObject::from(name))
+        .collect();
+
+    make_list(items)
+}
+
+include!(concat!(env!("CARGO_MANIFEST_DIR"), "/out/tag_exports.rs"));
@@ -171,9 +171,7 @@ pub fn async_stream(_attr_ts: TokenStream, fn_ts: TokenStream) -> TokenStream {
 
     #[lisp_fn]
     pub fn #async_name (handler: emacs::lisp::LispObject) -> emacs::lisp::LispObject {
-        crate::ng_async::rust_worker(handler, |s| {
-        ::futures::executor::block_on(#name(s))
-        })
+        crate::ng_async::async_worker(handler, #name)
     }
 
     };
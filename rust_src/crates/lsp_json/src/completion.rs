@@ -0,0 +1,320 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use lsp_server::RequestId;
+use serde_json::Value;
+
+use emacs::bindings::{Fplist_get, Fprocess_plist};
+use emacs::lisp::LispObject;
+use emacs::multibyte::LispStringRef;
+use lisp_macros::lisp_fn;
+
+use emacs::globals::{QCcompletion_requests, Qlsp_completion_list_p, Qnil};
+
+use ng_async::ng_async::UserData;
+
+use crate::parsing::{get_process_json_config, serde_to_lisp};
+
+pub const COMPLETION: &str = "textDocument/completion";
+const ITEMS: &str = "items";
+const IS_INCOMPLETE: &str = "isIncomplete";
+const LABEL: &str = "label";
+const FILTER_TEXT: &str = "filterText";
+const SORT_TEXT: &str = "sortText";
+
+// Number of items returned by lsp-completion-filter by default
+const DEFAULT_LIMIT: usize = 50;
+
+// Scores of the matched characters of a candidate
+const MATCH_SCORE: i64 = 1;
+const EXACT_CASE_SCORE: i64 = 1;
+const CONSECUTIVE_SCORE: i64 = 5;
+const WORD_START_SCORE: i64 = 8;
+const FIRST_CHAR_SCORE: i64 = 10;
+// Upper bound of the penalty of the characters skipped before a match
+const MAX_GAP_PENALTY: i64 = 3;
+
+// The textDocument/completion requests whose response is kept in Rust
+#[derive(Default)]
+pub struct CompletionRequests {
+    enabled: bool,
+    ids: HashSet<RequestId>,
+}
+
+pub type SharedCompletionRequests = Arc<Mutex<CompletionRequests>>;
+
+struct CompletionItem {
+    // Lowercase characters of the filter text
+    filter_chars: Vec<char>,
+    filter_text: String,
+    sort_text: String,
+    value: Value,
+}
+
+// The items of a completion response, parsed by the reader thread so that
+// the lisp thread only converts the items it shows.
+pub struct CompletionList {
+    is_incomplete: bool,
+    items: Vec<CompletionItem>,
+}
+
+impl CompletionList {
+    // RESULT is a CompletionList, an array of CompletionItem, or null
+    fn from_result(result: Value) -> Self {
+        let (is_incomplete, items) = match result {
+            Value::Array(items) => (false, items),
+            Value::Object(mut list) => (
+                list.get(IS_INCOMPLETE)
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false),
+                match list.remove(ITEMS) {
+                    Some(Value::Array(items)) => items,
+                    _ => vec![],
+                },
+            ),
+            _ => (false, vec![]),
+        };
+
+        let items = items
+            .into_iter()
+            .map(|value| {
+                let text = |key: &str| value.get(key).and_then(Value::as_str).map(String::from);
+                let label = text(LABEL).unwrap_or_default();
+                let filter_text = text(FILTER_TEXT).unwrap_or_else(|| label.clone());
+                let sort_text = text(SORT_TEXT).unwrap_or(label);
+                CompletionItem {
+                    filter_chars: filter_text.chars().map(lowercase).collect(),
+                    filter_text,
+                    sort_text,
+                    value,
+                }
+            })
+            .collect();
+
+        CompletionList {
+            is_incomplete,
+            items,
+        }
+    }
+}
+
+unsafe extern "C" fn finalize_completion_list(raw: *mut libc::c_void) {
+    let _list = Box::from_raw(raw as *mut CompletionList);
+}
+
+impl From<CompletionList> for LispObject {
+    fn from(list: CompletionList) -> Self {
+        let data = Box::into_raw(Box::new(list)) as *mut libc::c_void;
+        UserData::with_data_and_finalizer(data, Some(finalize_completion_list)).into()
+    }
+}
+
+fn as_completion_list(object: &LispObject) -> Option<&CompletionList> {
+    unsafe { object.as_userdata_with_finalizer(finalize_completion_list) }
+}
+
+fn completion_list(object: &LispObject) -> &CompletionList {
+    match as_completion_list(object) {
+        Some(list) => list,
+        None => {
+            wrong_type!(Qlsp_completion_list_p, *object);
+        }
+    }
+}
+
+fn lowercase(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+fn is_word_start(previous: char, c: char) -> bool {
+    !previous.is_alphanumeric() && c.is_alphanumeric()
+        || previous.is_lowercase() && c.is_uppercase()
+}
+
+// Score ITEM against PATTERN, whose characters must all appear in order
+// in the filter text of the item. Matches at the start of the text, at
+// the start of words and in a row score higher, while skipped characters
+// cost a little.
+fn flex_score(pattern: &[char], lower_pattern: &[char], item: &CompletionItem) -> Option<i64> {
+    let mut score = 0;
+    let mut matched = 0;
+    let mut last_match: Option<usize> = None;
+    let mut previous = ' ';
+
+    let chars = item.filter_text.chars().zip(item.filter_chars.iter());
+    for (idx, (c, lower)) in chars.enumerate() {
+        if matched == pattern.len() {
+            break;
+        }
+
+        if *lower == lower_pattern[matched] {
+            score += MATCH_SCORE;
+            if c == pattern[matched] {
+                score += EXACT_CASE_SCORE;
+            }
+            if idx == 0 {
+                score += FIRST_CHAR_SCORE;
+            } else if is_word_start(previous, c) {
+                score += WORD_START_SCORE;
+            }
+            match last_match {
+                Some(last) if last + 1 == idx => score += CONSECUTIVE_SCORE,
+                Some(last) => score -= ((idx - last - 1) as i64).min(MAX_GAP_PENALTY),
+                None => score -= (idx as i64).min(MAX_GAP_PENALTY),
+            }
+            last_match = Some(idx);
+            matched += 1;
+        }
+        previous = c;
+    }
+
+    if matched == pattern.len() {
+        Some(score)
+    } else {
+        None
+    }
+}
+
+pub fn get_completion_requests(proc: LispObject) -> SharedCompletionRequests {
+    let plist = unsafe { Fprocess_plist(proc) };
+    let requests_obj = unsafe { Fplist_get(plist, QCcompletion_requests) };
+    if requests_obj.is_nil() {
+        error!("Process was not created by make-lsp-connection");
+    }
+
+    let requests: &SharedCompletionRequests = unsafe { requests_obj.as_userdata_ref() };
+    requests.clone()
+}
+
+/// Remember the request ID for METHOD among the completion REQUESTS of a
+/// connection, when its response must be kept in Rust.
+pub fn register_completion_request(
+    requests: &SharedCompletionRequests,
+    method: &str,
+    id: &RequestId,
+) {
+    if method != COMPLETION {
+        return;
+    }
+
+    let mut requests = requests.lock().unwrap();
+    if requests.enabled {
+        requests.ids.insert(id.clone());
+    }
+}
+
+/// Forget the request ID registered with `register_completion_request',
+/// when it could not be sent.
+pub fn unregister_completion_request(requests: &SharedCompletionRequests, id: &RequestId) {
+    requests.lock().unwrap().ids.remove(id);
+}
+
+/// Parse RESULT if it is the response to a completion request that was
+/// registered with `register_completion_request'.
+pub fn take_completion_list(
+    requests: &SharedCompletionRequests,
+    id: &RequestId,
+    result: &mut Option<Value>,
+) -> Option<CompletionList> {
+    if !requests.lock().unwrap().ids.remove(id) {
+        return None;
+    }
+
+    result.take().map(CompletionList::from_result)
+}
+
+/// Keep the results of the textDocument/completion requests sent to PROC
+/// in Rust when ENABLE is non-nil.  The responses are then passed to the
+/// handler as (:completion :id ID :list LIST), or LIST is passed to the
+/// ON-SUCCESS callback of the request, where LIST is a user-ptr to give
+/// to `lsp-completion-filter'.  Error responses are not affected.
+#[lisp_fn]
+pub fn lsp_set_completion_filtering(proc: LispObject, enable: bool) -> bool {
+    let requests = get_completion_requests(proc);
+    requests.lock().unwrap().enabled = enable;
+    true
+}
+
+/// Return the items of LIST, a completion list kept by
+/// `lsp-set-completion-filtering', whose filter text matches PATTERN.
+/// The characters of PATTERN must appear in order, but not necessarily
+/// in a row, in the filterText or the label of an item, ignoring case.
+///
+/// The items are ranked by how well they match, the best first, then by
+/// their sortText.  Matches at the start of the text or of words, in a
+/// row or with the same case rank higher.  At most LIMIT items, 50 by
+/// default, are converted to the JSON representation of PROC.
+#[lisp_fn(min = "3")]
+pub fn lsp_completion_filter(
+    proc: LispObject,
+    list: LispObject,
+    pattern: LispStringRef,
+    limit: LispObject,
+) -> LispObject {
+    let list = completion_list(&list);
+    let limit = if limit.is_nil() {
+        DEFAULT_LIMIT
+    } else {
+        limit.as_natnum_or_error() as usize
+    };
+    let pattern: Vec<char> = pattern.to_utf8().chars().collect();
+    let lower_pattern: Vec<char> = pattern.iter().copied().map(lowercase).collect();
+
+    let mut matches: Vec<(i64, &CompletionItem)> = list
+        .items
+        .iter()
+        .filter_map(|item| flex_score(&pattern, &lower_pattern, item).map(|score| (score, item)))
+        .collect();
+    matches.sort_by(|(score_a, a), (score_b, b)| match score_b.cmp(score_a) {
+        Ordering::Equal => a.sort_text.cmp(&b.sort_text),
+        ordering => ordering,
+    });
+
+    let config = get_process_json_config(proc);
+    let items: Vec<LispObject> = matches
+        .into_iter()
+        .take(limit)
+        .map(|(_, item)| serde_to_lisp(item.value.clone(), &config).unwrap_or_else(|e| error!(e)))
+        .collect();
+
+    items
+        .into_iter()
+        .rev()
+        .fold(Qnil, |result, item| LispObject::cons(item, result))
+}
+
+/// Return the number of items of LIST, a completion list kept by
+/// `lsp-set-completion-filtering'.
+#[lisp_fn]
+pub fn lsp_completion_count(list: LispObject) -> LispObject {
+    let list = completion_list(&list);
+    LispObject::from(list.items.len())
+}
+
+/// Return t if LIST, a completion list kept by
+/// `lsp-set-completion-filtering', is incomplete, meaning that typing
+/// more should request the completions again.
+#[lisp_fn]
+pub fn lsp_completion_incomplete_p(list: LispObject) -> bool {
+    let list = completion_list(&list);
+    list.is_incomplete
+}
+
+/// Return t if OBJECT is a completion list kept by
+/// `lsp-set-completion-filtering'.
+#[lisp_fn]
+pub fn lsp_completion_list_p(object: LispObject) -> bool {
+    as_completion_list(&object).is_some()
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCcompletion_requests, ":completion-requests");
+    def_lisp_sym!(Qlsp_completion_list_p, "lsp-completion-list-p");
+}
+
+include!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/out/completion_exports.rs"
+));
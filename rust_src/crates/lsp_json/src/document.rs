@@ -0,0 +1,491 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use lsp_server::{Message, Notification};
+use serde_json::Value;
+
+use ng_async::ng_async::{EmacsPipe, UserData};
+
+use emacs::bindings::{Fplist_get, Fprocess_plist};
+use emacs::lisp::LispObject;
+use emacs::multibyte::LispStringRef;
+use lisp_macros::lisp_fn;
+
+use emacs::globals::QCdocuments;
+
+use crate::parsing::{get_process_json_config, serde_to_lisp};
+
+const DID_CHANGE: &str = "textDocument/didChange";
+const TEXT_DOCUMENT: &str = "textDocument";
+const CONTENT_CHANGES: &str = "contentChanges";
+const URI: &str = "uri";
+const VERSION: &str = "version";
+const RANGE: &str = "range";
+const START: &str = "start";
+const END: &str = "end";
+const TEXT: &str = "text";
+const LINE: &str = "line";
+const CHARACTER: &str = "character";
+
+// A change applied to a document but not sent to the server yet. RANGE
+// is in the coordinates of the document before the change, as expected
+// by the server. START and TEXT_CHARS locate the new text in characters,
+// so that the next changes can be coalesced with it.
+struct PendingChange {
+    range: Value,
+    start: usize,
+    text: String,
+    text_chars: usize,
+}
+
+// The text of a buffer synchronized with the server, split in lines that
+// keep their newline. The last line never has one, and is empty when the
+// text ends with a newline, so that there are as many lines as the
+// server counts.
+pub struct Document {
+    lines: Vec<String>,
+    // Number of characters of each line, newline included
+    line_chars: Vec<usize>,
+    version: i64,
+    pending: Vec<PendingChange>,
+}
+
+pub type SharedDocuments = Arc<Mutex<HashMap<String, Document>>>;
+
+fn split_lines(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = text.split('\n').map(|line| format!("{}\n", line)).collect();
+    if let Some(last) = lines.last_mut() {
+        last.pop();
+    }
+
+    lines
+}
+
+fn utf16_column(line: &str, column: usize) -> usize {
+    line.chars().take(column).map(char::len_utf16).sum()
+}
+
+// Convert a UTF-16 column to characters. Columns past the end of the
+// line are clamped to it, as required by the LSP specification.
+fn char_column(line: &str, utf16: usize) -> usize {
+    let mut units = 0;
+    let mut column = 0;
+    for c in line.chars() {
+        if units >= utf16 || c == '\n' {
+            break;
+        }
+        units += c.len_utf16();
+        column += 1;
+    }
+
+    column
+}
+
+impl Document {
+    fn new(text: &str, version: i64) -> Self {
+        let lines = split_lines(text);
+        let line_chars = lines.iter().map(|line| line.chars().count()).collect();
+        Document {
+            lines,
+            line_chars,
+            version,
+            pending: vec![],
+        }
+    }
+
+    // Return the line and the column in characters of OFFSET, or None when
+    // it is past the end of the document.
+    fn locate(&self, offset: usize) -> Option<(usize, usize)> {
+        let last = self.lines.len() - 1;
+        let mut start = 0;
+        for (line, chars) in self.line_chars.iter().enumerate() {
+            // The offset right after a newline is on the next line
+            if offset < start + chars || line == last {
+                return if offset <= start + chars {
+                    Some((line, offset - start))
+                } else {
+                    None
+                };
+            }
+            start += chars;
+        }
+
+        None
+    }
+
+    fn position(&self, line: usize, column: usize) -> Value {
+        json!({
+            LINE: line,
+            CHARACTER: utf16_column(&self.lines[line], column),
+        })
+    }
+
+    fn offset(&self, line: usize, utf16: usize) -> usize {
+        if line >= self.lines.len() {
+            return self.line_chars.iter().sum();
+        }
+
+        let start: usize = self.line_chars[..line].iter().sum();
+        start + char_column(&self.lines[line], utf16)
+    }
+
+    // Replace OLD_CHARS characters at offset START with TEXT, and queue the
+    // change for the server.
+    fn change(&mut self, start: usize, old_chars: usize, text: String) -> Result<(), String> {
+        let out_of_sync = || String::from("Change is out of the document, which is out of sync");
+        let (first, first_column) = self.locate(start).ok_or_else(out_of_sync)?;
+        let (last, last_column) = self.locate(start + old_chars).ok_or_else(out_of_sync)?;
+        let range = json!({
+            START: self.position(first, first_column),
+            END: self.position(last, last_column),
+        });
+
+        let mut replaced: String = self.lines[first].chars().take(first_column).collect();
+        replaced.push_str(&text);
+        replaced.extend(self.lines[last].chars().skip(last_column));
+        let mut lines = split_lines(&replaced);
+        if last < self.lines.len() - 1 {
+            // The replaced text ends with the newline of LAST, which is
+            // not followed by another line
+            lines.pop();
+        }
+        let line_chars: Vec<usize> = lines.iter().map(|line| line.chars().count()).collect();
+        self.lines.splice(first..=last, lines);
+        self.line_chars.splice(first..=last, line_chars);
+
+        let text_chars = text.chars().count();
+        // Typing or deleting what was just typed only touches the text of
+        // the last change, so both are sent as a single change.
+        if let Some(previous) = self.pending.last_mut() {
+            if start >= previous.start && start + old_chars <= previous.start + previous.text_chars
+            {
+                let from = start - previous.start;
+                let mut merged: String = previous.text.chars().take(from).collect();
+                merged.push_str(&text);
+                merged.extend(previous.text.chars().skip(from + old_chars));
+                previous.text = merged;
+                previous.text_chars = previous.text_chars - old_chars + text_chars;
+                return Ok(());
+            }
+        }
+
+        self.pending.push(PendingChange {
+            range,
+            start,
+            text,
+            text_chars,
+        });
+
+        Ok(())
+    }
+
+    fn take_did_change(&mut self, uri: &str) -> Option<Message> {
+        if self.pending.is_empty() {
+            return None;
+        }
+
+        self.version += 1;
+        let changes: Vec<Value> = self
+            .pending
+            .drain(..)
+            .map(|change| json!({ RANGE: change.range, TEXT: change.text }))
+            .collect();
+
+        Some(Message::Notification(Notification::new(
+            DID_CHANGE.to_string(),
+            json!({
+                TEXT_DOCUMENT: { URI: uri, VERSION: self.version },
+                CONTENT_CHANGES: changes,
+            }),
+        )))
+    }
+}
+
+fn get_documents(proc: LispObject) -> SharedDocuments {
+    let plist = unsafe { Fprocess_plist(proc) };
+    let documents_obj = unsafe { Fplist_get(plist, QCdocuments) };
+    if documents_obj.is_nil() {
+        error!("Process was not created by make-lsp-connection");
+    }
+
+    let documents: &SharedDocuments = unsafe { documents_obj.as_userdata_ref() };
+    documents.clone()
+}
+
+// Run F on the document URI of PROC. The documents are unlocked before
+// an error is signaled.
+fn with_document<T, F>(proc: LispObject, uri: &str, f: F) -> T
+where
+    F: FnOnce(&mut Document) -> Result<T, String>,
+{
+    let documents = get_documents(proc);
+    let result = match documents.lock().unwrap().get_mut(uri) {
+        Some(document) => f(document),
+        None => Err(format!("Document {} is not tracked", uri)),
+    };
+
+    result.unwrap_or_else(|e| error!(e))
+}
+
+/// Send the pending changes of the document URI of PROC, or of all its
+/// documents when URI is None, to the server.
+pub fn flush_documents(proc: LispObject, uri: Option<&str>) {
+    let documents = get_documents(proc);
+    let mut emacs_pipe = unsafe { EmacsPipe::with_process(proc) };
+
+    // The documents stay locked until the changes are queued for the
+    // writer thread, so that they cannot be sent out of order.
+    let result = documents
+        .lock()
+        .unwrap()
+        .iter_mut()
+        .filter(|(doc_uri, _)| uri.map_or(true, |uri| uri == doc_uri.as_str()))
+        .filter_map(|(doc_uri, document)| document.take_did_change(doc_uri))
+        .try_for_each(|msg| emacs_pipe.message_rust_worker(UserData::new(msg)));
+
+    if let Err(e) = result {
+        error!("Failed to send changes to server, reason {:?}", e);
+    }
+}
+
+/// Start tracking the changes of the document URI, opened on the server
+/// of PROC with TEXT and VERSION, 0 by default.  The changes are then
+/// reported with `lsp-document-change' and sent as incremental
+/// textDocument/didChange notifications.
+#[lisp_fn(min = "3")]
+pub fn lsp_document_open(
+    proc: LispObject,
+    uri: LispStringRef,
+    text: LispStringRef,
+    version: LispObject,
+) -> bool {
+    let version = if version.is_nil() {
+        0
+    } else {
+        version.as_natnum_or_error() as i64
+    };
+
+    let documents = get_documents(proc);
+    documents
+        .lock()
+        .unwrap()
+        .insert(uri.to_utf8(), Document::new(&text.to_utf8(), version));
+    true
+}
+
+/// Record a change of the document URI of PROC, with the arguments of
+/// `after-change-functions' and the inserted TEXT.  BEG and END are the
+/// positions of TEXT, counting from 1 at the start of the document, and
+/// OLD-LEN the number of characters it replaced.
+///
+/// The change is queued until `lsp-document-flush', or until another
+/// message is sent to the server.  Changes that only touch the text of
+/// the previous change, like typing, are coalesced into one.
+#[lisp_fn]
+pub fn lsp_document_change(
+    proc: LispObject,
+    uri: LispStringRef,
+    beg: LispObject,
+    end: LispObject,
+    old_len: LispObject,
+    text: LispStringRef,
+) -> bool {
+    let beg = beg.as_natnum_or_error() as usize;
+    let end = end.as_natnum_or_error() as usize;
+    let old_len = old_len.as_natnum_or_error() as usize;
+    let text = text.to_utf8();
+    if beg < 1 || end < beg || end - beg != text.chars().count() {
+        error!("BEG and END must be the positions of TEXT");
+    }
+
+    with_document(proc, &uri.to_utf8(), |document| {
+        document.change(beg - 1, old_len, text)
+    });
+    true
+}
+
+/// Send the pending changes of the document URI of PROC to the server,
+/// or of all the documents of PROC when URI is nil.
+#[lisp_fn(min = "1")]
+pub fn lsp_document_flush(proc: LispObject, uri: LispObject) -> bool {
+    let uri = if uri.is_nil() {
+        None
+    } else {
+        let uri_s: LispStringRef = uri.into();
+        Some(uri_s.to_utf8())
+    };
+
+    flush_documents(proc, uri.as_deref());
+    true
+}
+
+/// Send the pending changes of the document URI of PROC, and stop
+/// tracking it.
+#[lisp_fn]
+pub fn lsp_document_close(proc: LispObject, uri: LispStringRef) -> bool {
+    let uri = uri.to_utf8();
+    flush_documents(proc, Some(&uri));
+    get_documents(proc).lock().unwrap().remove(&uri);
+    true
+}
+
+/// Return the LSP position of POS in the document URI of PROC, counting
+/// from 1 at the start of the document.  The position is an object with
+/// the line and the UTF-16 character, in the JSON representation of
+/// PROC.
+#[lisp_fn]
+pub fn lsp_document_position(proc: LispObject, uri: LispStringRef, pos: LispObject) -> LispObject {
+    let pos = pos.as_natnum_or_error() as usize;
+    if pos < 1 {
+        error!("Positions start at 1");
+    }
+
+    let position = with_document(proc, &uri.to_utf8(), |document| {
+        document
+            .locate(pos - 1)
+            .map(|(line, column)| document.position(line, column))
+            .ok_or_else(|| format!("Position {} is out of the document", pos))
+    });
+    let config = get_process_json_config(proc);
+    serde_to_lisp(position, &config).unwrap_or_else(|e| error!(e))
+}
+
+/// Return the position of LINE and the UTF-16 CHARACTER in the document
+/// URI of PROC, counting from 1 at the start of the document.  Positions
+/// past the end of a line or of the document are clamped to it.
+#[lisp_fn]
+pub fn lsp_document_offset(
+    proc: LispObject,
+    uri: LispStringRef,
+    line: LispObject,
+    character: LispObject,
+) -> LispObject {
+    let line = line.as_natnum_or_error() as usize;
+    let character = character.as_natnum_or_error() as usize;
+
+    let offset = with_document(proc, &uri.to_utf8(), |document| {
+        Ok(document.offset(line, character))
+    });
+    LispObject::from(offset + 1)
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCdocuments, ":documents");
+}
+
+#[test]
+fn test_locate() {
+    let document = Document::new("ab\ncd\n", 0);
+    assert_eq!(document.locate(0), Some((0, 0)));
+    assert_eq!(document.locate(2), Some((0, 2)));
+    assert_eq!(document.locate(3), Some((1, 0)));
+    assert_eq!(document.locate(6), Some((2, 0)));
+    assert_eq!(document.locate(7), None);
+}
+
+#[test]
+fn test_offset() {
+    let document = Document::new("ab\ncd", 0);
+    assert_eq!(document.offset(1, 1), 4);
+    // Columns past the end of a line stop before its newline
+    assert_eq!(document.offset(0, 10), 2);
+    // Lines past the end of the document stop at its end
+    assert_eq!(document.offset(5, 0), 5);
+}
+
+#[test]
+fn test_char_column() {
+    // U+1F600 takes two UTF-16 code units
+    let line = "a\u{1F600}b\n";
+    assert_eq!(char_column(line, 1), 1);
+    assert_eq!(char_column(line, 3), 2);
+    assert_eq!(char_column(line, 4), 3);
+    assert_eq!(char_column(line, 10), 3);
+    assert_eq!(utf16_column(line, 2), 3);
+}
+
+#[test]
+fn test_change_across_lines() {
+    let mut document = Document::new("ab\ncd", 0);
+    document.change(1, 3, String::from("X")).unwrap();
+    assert_eq!(document.lines, vec!["aXd"]);
+    assert_eq!(document.line_chars, vec![3]);
+    assert_eq!(
+        document.pending[0].range,
+        json!({
+            START: { LINE: 0, CHARACTER: 1 },
+            END: { LINE: 1, CHARACTER: 1 },
+        })
+    );
+}
+
+#[test]
+fn test_change_keeps_line_count() {
+    let mut document = Document::new("ab\ncd\nef", 0);
+    document.change(3, 2, String::from("XY")).unwrap();
+    assert_eq!(document.lines, vec!["ab\n", "XY\n", "ef"]);
+
+    document.change(2, 1, String::from("\n\n")).unwrap();
+    assert_eq!(document.lines, vec!["ab\n", "\n", "XY\n", "ef"]);
+    assert_eq!(document.line_chars, vec![3, 1, 3, 2]);
+
+    let mut document = Document::new("ab\ncd\n", 0);
+    document.change(3, 3, String::new()).unwrap();
+    assert_eq!(document.lines, vec!["ab\n", ""]);
+}
+
+#[test]
+fn test_change_out_of_sync() {
+    let mut document = Document::new("ab", 0);
+    assert!(document.change(3, 0, String::from("x")).is_err());
+    assert!(document.change(1, 2, String::new()).is_err());
+    assert_eq!(document.lines, vec!["ab"]);
+    assert!(document.pending.is_empty());
+}
+
+#[test]
+fn test_change_coalescing() {
+    let mut document = Document::new("xy", 0);
+    document.change(1, 0, String::from("a")).unwrap();
+    document.change(2, 0, String::from("b")).unwrap();
+    document.change(2, 1, String::new()).unwrap();
+    assert_eq!(document.pending.len(), 1);
+    // Not within the text of the last change
+    document.change(0, 1, String::from("c")).unwrap();
+    assert_eq!(document.pending.len(), 2);
+    assert_eq!(document.lines, vec!["cay"]);
+
+    let params = match document.take_did_change("file:///a") {
+        Some(Message::Notification(notification)) => notification.params,
+        _ => panic!("Expected a didChange notification"),
+    };
+    assert_eq!(
+        params,
+        json!({
+            TEXT_DOCUMENT: { URI: "file:///a", VERSION: 1 },
+            CONTENT_CHANGES: [
+                {
+                    RANGE: {
+                        START: { LINE: 0, CHARACTER: 1 },
+                        END: { LINE: 0, CHARACTER: 1 },
+                    },
+                    TEXT: "a",
+                },
+                {
+                    RANGE: {
+                        START: { LINE: 0, CHARACTER: 0 },
+                        END: { LINE: 0, CHARACTER: 1 },
+                    },
+                    TEXT: "c",
+                },
+            ],
+        })
+    );
+    assert!(document.take_did_change("file:///a").is_none());
+}
+
+include!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/out/document_exports.rs"
+));
@@ -10,7 +10,10 @@ extern crate emacs;
 #[macro_use]
 extern crate lisp_util;
 
+pub mod completion;
+pub mod document;
 pub mod parsing;
+pub mod schema;
 
 #[cfg(not(test))]
 include!(concat!(env!("CARGO_MANIFEST_DIR"), "/out/c_exports.rs"));
@@ -1,31 +1,55 @@
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::ffi::CString;
-use std::io::{BufReader, BufWriter, Result};
-use std::process::{Child, Command, Stdio};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Result, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, ChildStderr, Command, ExitStatus, Stdio};
+use std::slice;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use lsp_server::{Message, Notification, Request, RequestId, Response};
 use serde_json::{map::Map, Value};
 
 use ng_async::ng_async::{to_owned_userdata, EmacsPipe, PipeDataOption, UserData};
 
+use crate::completion::{
+    get_completion_requests, register_completion_request, take_completion_list,
+    unregister_completion_request, CompletionList, SharedCompletionRequests,
+};
+use crate::document::{flush_documents, SharedDocuments};
+
 use emacs::lisp::LispObject;
 use emacs::list::{LispCons, LispConsCircularChecks, LispConsEndChecks};
 use emacs::multibyte::LispStringRef;
 use lisp_macros::lisp_fn;
 
 use emacs::bindings::{
-    check_integer_range, hash_lookup, hash_put, intmax_t, make_fixed_natnum, make_float, make_int,
-    make_string_from_utf8, make_uint, make_vector, Fcons, Fintern, Flist, Fmake_hash_table,
-    Fnreverse, Fplist_get, Fplist_put, Fprocess_plist, Fset_process_plist, AREF, ASET, ASIZE,
-    FLOATP, HASH_KEY, HASH_TABLE_P, HASH_TABLE_SIZE, HASH_VALUE, INTEGERP, STRINGP, SYMBOLP,
-    SYMBOL_NAME, VECTORP, XFLOAT_DATA, XHASH_TABLE,
+    check_integer_range, current_thread, hash_lookup, hash_put, insert_from_gap, intmax_t,
+    make_fixed_natnum, make_float, make_gap, make_int, make_string_from_utf8, make_uint,
+    make_vector, move_gap_both, prepare_to_modify_buffer, record_unwind_current_buffer,
+    record_unwind_protect_excursion, set_point_both, signal_after_change, unbind_to,
+    update_compositions, validate_region, Fbuffer_live_p, Fcons, Ffuncall, Fgethash, Fgoto_char,
+    Finsert, Fintern, Flist, Fmake_hash_table, Fnreverse, Fplist_get, Fplist_put, Fpoint_max,
+    Fprocess_plist, Fputhash, Fremhash, Fset_buffer, Fset_process_plist, AREF, ASET, ASIZE,
+    BYTE_POS_ADDR, CHAR_TO_BYTE, FLOATP, FUNCTIONP, HASH_KEY, HASH_TABLE_P, HASH_TABLE_SIZE,
+    HASH_VALUE, INTEGERP, SPECPDL_INDEX, STRINGP, SYMBOLP, SYMBOL_NAME, VECTORP, XFLOAT_DATA,
+    XHASH_TABLE,
 };
 
 use emacs::globals::{
-    QCarray_type, QCfalse, QCfalse_object, QCjson_config, QCnull, QCnull_object, QCobject_type,
-    QCser_false_object, QCser_null_object, QCsize, QCtest, Qalist, Qarray, Qequal, Qhash_table,
-    Qlist, Qnil, Qplist, Qplistp, Qt, Qunbound,
+    QCarray_type, QCcallbacks, QCcode, QCcompletion, QCcompletion_requests, QCconnect_failed,
+    QCdefault_responders, QCdocuments, QCexited, QCfalse, QCfalse_object, QCid, QCjson_config,
+    QCkey_transform, QClist, QCmessage, QCnull, QCnull_object, QCobject_key_type, QCobject_type,
+    QCpending_requests, QCser_false_object, QCser_null_object, QCserver_process, QCsignal, QCsize,
+    QCstderr, QCtest, QCtracer, Qalist, Qarray, Qequal, Qhash_table, Qkebab_case, Qkeyword, Qlist,
+    Qnil, Qnumberp, Qplist, Qplistp, Qsnake_case, Qstring, Qsymbol, Qt, Qunbound,
 };
 
 const ID: &str = "id";
@@ -36,9 +60,227 @@ const PARAMS: &str = "params";
 const METHOD: &str = "method";
 const DATA: &str = "data";
 const CODE: &str = "code";
+const ITEMS: &str = "items";
+const SECTION: &str = "section";
+const IS_LSP_MESSAGE: &str = "isLSPMessage";
+const TYPE: &str = "type";
+const TIMESTAMP: &str = "timestamp";
 
 // Defined by JSON RPC
 const PARSE_ERROR: i32 = -32700;
+// Defined by the LSP specification
+const REQUEST_CANCELLED: i32 = -32800;
+const CANCEL_REQUEST: &str = "$/cancelRequest";
+const WORKSPACE_CONFIGURATION: &str = "workspace/configuration";
+
+// How often the reader thread checks whether the server exited, once
+// its output was closed
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Longest pause between two messages replayed by
+// lsp-replay-transcript, whatever the pause in the transcript
+const MAX_REPLAY_DELAY: Duration = Duration::from_secs(1);
+// How long a replayed response waits for the client to send the request
+// with its id, after which it is delivered anyway
+const MAX_REPLAY_WAIT: Duration = Duration::from_secs(5);
+
+// How long each attempt at opening a TCP connection may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// How long a server started by make-lsp-tcp-server-connection has to
+// accept the connection, and how often it is tried
+const SERVER_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+// Size of the chunks written to the gap by json-se-insert
+const INSERT_BUFFER_SIZE: usize = 64 * 1024;
+// CHECK_BORDER of composite.h, to update the compositions around
+// inserted text
+const CHECK_BORDER: i32 = 3;
+
+// Requests sent to the server that did not get a response yet. This is
+// shared between the lisp thread, which registers requests as they are
+// queued, the reader thread, which retires them on response, and the
+// timeout thread, which is woken up through the condvar when a request
+// is registered or the connection is closed.
+#[derive(Default)]
+pub struct PendingRequests {
+    deadlines: HashMap<RequestId, Option<Instant>>,
+    // Requests that were cancelled on timeout, whose late response
+    // must be dropped.
+    timed_out: HashSet<RequestId>,
+    closed: bool,
+}
+
+pub type SharedPendingRequests = Arc<(Mutex<PendingRequests>, Condvar)>;
+
+fn close_pending_requests(pending: &SharedPendingRequests) {
+    let (requests, changed) = &**pending;
+    requests.lock().unwrap().closed = true;
+    changed.notify_all();
+}
+
+// Nothing reads the messages queued for the server once the connection
+// is closed, so sending more is an error.
+fn check_connection_open(pending: &SharedPendingRequests) {
+    let closed = pending.0.lock().unwrap().closed;
+    if closed {
+        error!("Connection to the server is closed");
+    }
+}
+
+// Requests from the server that are answered by the reader thread,
+// without a round trip to lisp.
+pub enum DefaultResponder {
+    // Answer workspace/configuration by looking the requested sections
+    // up in the settings object.
+    Configuration(Value),
+    // Answer with the same result every time.
+    Result(Value),
+}
+
+impl DefaultResponder {
+    fn respond(&self, request: &Request) -> Response {
+        let result = match self {
+            DefaultResponder::Configuration(settings) => {
+                let items = request.params.get(ITEMS).and_then(Value::as_array);
+                let sections = items.map_or(vec![], |items| {
+                    items
+                        .iter()
+                        .map(|item| {
+                            item.get(SECTION)
+                                .and_then(Value::as_str)
+                                .map_or(Some(settings), |section| {
+                                    section
+                                        .split('.')
+                                        .try_fold(settings, |value, key| value.get(key))
+                                })
+                                .cloned()
+                                .unwrap_or(Value::Null)
+                        })
+                        .collect()
+                });
+                Value::Array(sections)
+            }
+            DefaultResponder::Result(result) => result.clone(),
+        };
+
+        Response::new_ok(request.id.clone(), result)
+    }
+}
+
+pub type SharedDefaultResponders = Arc<Mutex<HashMap<String, DefaultResponder>>>;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Direction {
+    Send,
+    Receive,
+}
+
+// Records the messages of a connection in the JSON log format of the
+// LSP inspector, which lsp-replay-transcript reads back.
+pub struct Tracer {
+    file: BufWriter<File>,
+}
+
+impl Tracer {
+    fn record(&mut self, direction: Direction, msg: &Message) {
+        let direction = match direction {
+            Direction::Send => "send",
+            Direction::Receive => "receive",
+        };
+        let kind = match msg {
+            Message::Request(_) => "request",
+            Message::Response(_) => "response",
+            Message::Notification(_) => "notification",
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let entry = json!({
+            IS_LSP_MESSAGE: true,
+            TYPE: format!("{}-{}", direction, kind),
+            MESSAGE: msg,
+            TIMESTAMP: now.as_millis() as u64,
+        });
+
+        let secs = now.as_secs() % 86400;
+        // Tracing must not break the connection, so errors are ignored
+        let _ = writeln!(
+            self.file,
+            "[LSP   - {:02}:{:02}:{:02}] {}",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60,
+            entry
+        )
+        .and_then(|_| self.file.flush());
+    }
+}
+
+pub type SharedTracer = Arc<Mutex<Option<Tracer>>>;
+
+fn trace(tracer: &SharedTracer, direction: Direction, msg: &Message) {
+    if let Some(tracer) = tracer.lock().unwrap().as_mut() {
+        tracer.record(direction, msg);
+    }
+}
+
+// State of a connection shared between lisp and its threads
+#[derive(Clone, Default)]
+pub struct ConnectionState {
+    pub pending: SharedPendingRequests,
+    pub responders: SharedDefaultResponders,
+    pub tracer: SharedTracer,
+    pub documents: SharedDocuments,
+    pub completions: SharedCompletionRequests,
+}
+
+// The server process started for a connection. It is reaped either by
+// the reader thread once the server exits, or by lsp-connection-kill.
+pub struct ServerProcess {
+    child: Child,
+    status: Option<ExitStatus>,
+}
+
+pub type SharedServerProcess = Arc<Mutex<ServerProcess>>;
+
+// What the connection threads send to lisp
+pub enum ServerEvent {
+    Message(Message),
+    // The response to a completion request whose result is kept in Rust
+    Completion {
+        id: RequestId,
+        list: CompletionList,
+    },
+    Stderr(String),
+    // The server started by make-lsp-tcp-server-connection did not
+    // accept the connection
+    ConnectFailed(String),
+    // The server exited, or closed the connection. The exit code and
+    // signal are only known for server processes.
+    Exited {
+        code: Option<i32>,
+        signal: Option<i32>,
+    },
+}
+
+// Sockets that can be split into a reader and a writer half
+pub trait SocketStream: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> Result<Self>;
+}
+
+impl SocketStream for TcpStream {
+    fn try_clone(&self) -> Result<Self> {
+        TcpStream::try_clone(self)
+    }
+}
+
+#[cfg(unix)]
+impl SocketStream for UnixStream {
+    fn try_clone(&self) -> Result<Self> {
+        UnixStream::try_clone(self)
+    }
+}
 
 #[derive(Clone)]
 pub enum ObjectType {
@@ -53,6 +295,94 @@ pub enum ArrayType {
     List,
 }
 
+#[derive(Clone, PartialEq)]
+pub enum KeyType {
+    String,
+    Symbol,
+    Keyword,
+}
+
+// How object keys are renamed between JSON and lisp. Only keys that are
+// symbols or keywords in lisp are renamed; string keys, like those of
+// hash tables by default, are kept as they are. The renaming is lossy:
+// "textDocument" and "text-document" both decode to text-document, which
+// encodes back to "textDocument".
+#[derive(Clone)]
+pub enum KeyTransform {
+    Identity,
+    // camelCase in JSON, kebab-case in lisp
+    KebabCase,
+    // camelCase in JSON, snake_case in lisp
+    SnakeCase,
+}
+
+impl KeyTransform {
+    fn separator(&self) -> Option<char> {
+        match self {
+            KeyTransform::Identity => None,
+            KeyTransform::KebabCase => Some('-'),
+            KeyTransform::SnakeCase => Some('_'),
+        }
+    }
+
+    // Rename a JSON key for lisp, like "textDocument" to "text-document".
+    // Runs of capitals are kept together, so that "HTTPServer" becomes
+    // "http-server".
+    fn decode(&self, key: String) -> String {
+        let separator = match self.separator() {
+            Some(separator) => separator,
+            None => return key,
+        };
+
+        let chars: Vec<char> = key.chars().collect();
+        let mut result = String::with_capacity(key.len() + 4);
+        for (idx, c) in chars.iter().enumerate() {
+            if c.is_uppercase() && idx > 0 {
+                let previous = chars[idx - 1];
+                let next_is_lower = chars.get(idx + 1).map_or(false, |n| n.is_lowercase());
+                if previous.is_lowercase()
+                    || previous.is_numeric()
+                    || (previous.is_uppercase() && next_is_lower)
+                {
+                    result.push(separator);
+                }
+            }
+            result.extend(c.to_lowercase());
+        }
+
+        result
+    }
+
+    // Rename a lisp key for JSON, like "text-document" to "textDocument".
+    // Separators that do not follow a letter or a digit are kept.
+    fn encode(&self, key: String) -> String {
+        let separator = match self.separator() {
+            Some(separator) => separator,
+            None => return key,
+        };
+
+        let mut result = String::with_capacity(key.len());
+        let mut capitalize = false;
+        let mut previous: Option<char> = None;
+        for c in key.chars() {
+            if c == separator && previous.map_or(false, char::is_alphanumeric) {
+                capitalize = true;
+            } else if capitalize {
+                result.extend(c.to_uppercase());
+                capitalize = false;
+            } else {
+                result.push(c);
+            }
+            previous = Some(c);
+        }
+        if capitalize {
+            result.push(separator);
+        }
+
+        result
+    }
+}
+
 #[derive(Clone)]
 pub struct JSONConfiguration {
     pub obj: ObjectType,
@@ -61,6 +391,20 @@ pub struct JSONConfiguration {
     pub false_obj: LispObject,
     pub ser_null_obj: LispObject,
     pub ser_false_obj: LispObject,
+    // When None, keys are strings in hash tables, symbols in alists and
+    // keywords in plists
+    pub key_type: Option<KeyType>,
+    pub key_transform: KeyTransform,
+}
+
+impl JSONConfiguration {
+    fn key_type(&self) -> KeyType {
+        self.key_type.clone().unwrap_or_else(|| match self.obj {
+            ObjectType::Hashtable => KeyType::String,
+            ObjectType::Alist => KeyType::Symbol,
+            ObjectType::Plist => KeyType::Keyword,
+        })
+    }
 }
 
 impl Default for JSONConfiguration {
@@ -72,6 +416,8 @@ impl Default for JSONConfiguration {
             false_obj: QCfalse,
             ser_null_obj: QCnull,
             ser_false_obj: QCfalse,
+            key_type: None,
+            key_transform: KeyTransform::Identity,
         }
     }
 }
@@ -82,20 +428,303 @@ impl Default for JSONConfiguration {
 /// returned from the process via stdout. The handler should take two
 /// arguments, the pipe process and the data. Data will be returned as
 /// a 'user-ptr', which should be passed to lsp-handler for further processing.
-#[lisp_fn]
+///
+/// STDERR is where the output of the command on its standard error goes:
+/// a buffer it is inserted at the end of, or a function called with the
+/// pipe process and each line of output.  When nil, the output is
+/// discarded.
+#[lisp_fn(min = "3")]
 pub fn make_lsp_connection(
     command: LispObject,
     args: LispObject,
     handler: LispObject,
+    stderr: LispObject,
 ) -> LispObject {
     let command_ref: LispStringRef = command.into();
     let command_string = command_ref.to_utf8();
+    let args_vec = string_args(args);
+    let (emacs_pipe, proc, state) = make_connection_pipe(handler, stderr);
+
+    match async_create_process(
+        command_string,
+        args_vec,
+        emacs_pipe,
+        state,
+        stderr.is_not_nil(),
+    ) {
+        Ok(server) => set_server_process(proc, server),
+        Err(e) => error!("Error creating process, reason {:?}", e),
+    }
+
+    proc
+}
+
+/// Connect to a language server listening on PORT of HOST, a host name
+/// or an IP address.  HANDLER and the returned process behave as for
+/// `make-lsp-connection'.
+#[lisp_fn]
+pub fn make_lsp_tcp_connection(
+    host: LispStringRef,
+    port: LispObject,
+    handler: LispObject,
+) -> LispObject {
+    let host = host.to_utf8();
+    let port = port_from_lisp(port);
+    let stream = connect_tcp(&host, port)
+        .unwrap_or_else(|e| error!("Error connecting to {}:{}, reason {:?}", host, port, e));
+
+    connect_stream(stream, handler)
+}
+
+/// Connect to a language server listening on the Unix domain socket at
+/// PATH.  HANDLER and the returned process behave as for
+/// `make-lsp-connection'.
+#[cfg(unix)]
+#[lisp_fn]
+pub fn make_lsp_unix_connection(path: LispStringRef, handler: LispObject) -> LispObject {
+    let path = path.to_utf8();
+    let stream = UnixStream::connect(&path)
+        .unwrap_or_else(|e| error!("Error connecting to {}, reason {:?}", path, e));
+
+    connect_stream(stream, handler)
+}
+
+/// Start COMMAND with the list of string ARGS, for a language server
+/// that listens on PORT instead of using its standard input and output,
+/// then connect to it.  HOST defaults to "localhost".
+///
+/// The process is returned right away, and the connection is retried in
+/// the background until the server accepts it.  The messages sent to the
+/// process meanwhile are held until then.  When the server exits first,
+/// or TIMEOUT seconds passed, 10 by default, the server is killed and
+/// HANDLER receives (:connect-failed :message MESSAGE), followed by the
+/// exit of the server; sending to the process then signals an error.
+/// Otherwise HANDLER, STDERR and the returned process behave as for
+/// `make-lsp-connection'.
+#[lisp_fn(min = "4")]
+pub fn make_lsp_tcp_server_connection(
+    command: LispStringRef,
+    args: LispObject,
+    port: LispObject,
+    handler: LispObject,
+    host: LispObject,
+    timeout: LispObject,
+    stderr: LispObject,
+) -> LispObject {
+    let port = port_from_lisp(port);
+    let host = if host.is_nil() {
+        String::from("localhost")
+    } else {
+        let host_s: LispStringRef = host.into();
+        host_s.to_utf8()
+    };
+    let timeout = if timeout.is_nil() {
+        SERVER_CONNECT_TIMEOUT
+    } else {
+        timeout_from_lisp(timeout)
+    };
+
+    check_stderr_destination(stderr);
+    let process = Command::new(command.to_utf8())
+        .args(string_args(args))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(stderr_stdio(stderr.is_not_nil()))
+        .spawn()
+        .unwrap_or_else(|e| error!("Error creating process, reason {:?}", e));
+
+    let (emacs_pipe, proc, state) = make_connection_pipe(handler, stderr);
+    let server = watch_server_process(process, &emacs_pipe);
+    set_server_process(proc, server.clone());
+    thread::spawn(move || connect_server(host, port, timeout, emacs_pipe, state, server));
+
+    proc
+}
+
+// Connect to the first address of HOST that accepts the connection.
+fn connect_tcp(host: &str, port: u16) -> Result<TcpStream> {
+    let mut last_error = std::io::Error::new(ErrorKind::NotFound, "No address found");
+    for address in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+
+    Err(last_error)
+}
+
+// Connect to a server started by make-lsp-tcp-server-connection, then
+// serve the connection. The connection is retried until the server
+// accepts it, exits, or TIMEOUT passed; the failure is then reported to
+// lisp along with the exit of the server. The messages that lisp queues
+// meanwhile are buffered, and sent once connected.
+fn connect_server(
+    host: String,
+    port: u16,
+    timeout: Duration,
+    mut pipe: EmacsPipe,
+    state: ConnectionState,
+    server: SharedServerProcess,
+) {
+    let messages = buffer_messages(pipe.clone());
+    let deadline = Instant::now().checked_add(timeout);
+    let reason = loop {
+        let error = match connect_tcp(&host, port).and_then(|s| Ok((s.try_clone()?, s))) {
+            Ok((reader, stream)) => {
+                spawn_io_threads(reader, stream, messages, pipe, state, Some(server));
+                return;
+            }
+            Err(e) => e,
+        };
+
+        {
+            let mut server = server.lock().unwrap();
+            if server.status.is_none() {
+                if let Ok(Some(status)) = server.child.try_wait() {
+                    server.status = Some(status);
+                }
+            }
+            if let Some(status) = server.status {
+                break format!("Server exited before accepting connections, {}", status);
+            }
+            if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                let _ = server.child.kill();
+                break format!("Error connecting to {}:{}, reason {:?}", host, port, error);
+            }
+        }
+
+        thread::sleep(CONNECT_RETRY_INTERVAL);
+    };
+
+    // Sending to the connection signals an error from now on, and the
+    // buffered messages are dropped
+    close_pending_requests(&state.pending);
+    drop(messages);
+
+    let sender = pipe.get_sender();
+    let failed = ServerEvent::ConnectFailed(reason);
+    if pipe.message_lisp(&sender, UserData::new(failed)).is_err() {
+        return;
+    }
+    for event in take_closed_requests(&state.pending) {
+        if pipe.message_lisp(&sender, UserData::new(event)).is_err() {
+            return;
+        }
+    }
+
+    let status = wait_server_process(&server);
+    let exited = ServerEvent::Exited {
+        code: status.and_then(|status| status.code()),
+        signal: status.and_then(|status| exit_signal(&status)),
+    };
+    let _ = pipe.message_lisp(&sender, UserData::new(exited));
+}
+
+// Create the pipe process of a new connection, along with the state
+// shared with its threads, and record that state in the process plist.
+fn make_connection_pipe(
+    handler: LispObject,
+    stderr: LispObject,
+) -> (EmacsPipe, LispObject, ConnectionState) {
+    check_stderr_destination(stderr);
     let (emacs_pipe, proc) = EmacsPipe::with_handler(
         handler,
         PipeDataOption::USER_DATA,
         PipeDataOption::USER_DATA,
     );
+    let state = ConnectionState::default();
+    let mut plist = unsafe { Fprocess_plist(proc) };
+    plist = unsafe {
+        Fplist_put(
+            plist,
+            QCpending_requests,
+            UserData::new(state.pending.clone()).into(),
+        )
+    };
+    plist = unsafe {
+        Fplist_put(
+            plist,
+            QCdefault_responders,
+            UserData::new(state.responders.clone()).into(),
+        )
+    };
+    plist = unsafe { Fplist_put(plist, QCtracer, UserData::new(state.tracer.clone()).into()) };
+    plist = unsafe {
+        Fplist_put(
+            plist,
+            QCdocuments,
+            UserData::new(state.documents.clone()).into(),
+        )
+    };
+    plist = unsafe {
+        Fplist_put(
+            plist,
+            QCcompletion_requests,
+            UserData::new(state.completions.clone()).into(),
+        )
+    };
+    plist = unsafe { Fplist_put(plist, QCstderr, stderr) };
+    unsafe { Fset_process_plist(proc, plist) };
+
+    (emacs_pipe, proc, state)
+}
+
+fn connect_stream<S: SocketStream>(stream: S, handler: LispObject) -> LispObject {
+    let reader = stream
+        .try_clone()
+        .unwrap_or_else(|e| error!("Error setting up connection, reason {:?}", e));
+    let (emacs_pipe, proc, state) = make_connection_pipe(handler, Qnil);
+    let messages = queued_messages(emacs_pipe.clone());
+    spawn_io_threads(reader, stream, messages, emacs_pipe, state, None);
+
+    proc
+}
+
+fn get_tracer(proc: LispObject) -> SharedTracer {
+    let plist = unsafe { Fprocess_plist(proc) };
+    let tracer_obj = unsafe { Fplist_get(plist, QCtracer) };
+    if tracer_obj.is_nil() {
+        error!("Process was not created by make-lsp-connection");
+    }
 
+    let tracer: &SharedTracer = unsafe { tracer_obj.as_userdata_ref() };
+    tracer.clone()
+}
+
+fn check_stderr_destination(stderr: LispObject) {
+    let is_buffer = unsafe { Fbuffer_live_p(stderr) }.is_not_nil();
+    if stderr.is_not_nil() && !is_buffer && !unsafe { FUNCTIONP(stderr) } {
+        error!("STDERR must be a live buffer or a function");
+    }
+}
+
+fn stderr_stdio(capture: bool) -> Stdio {
+    if capture {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    }
+}
+
+fn set_server_process(proc: LispObject, server: SharedServerProcess) {
+    let plist = unsafe { Fprocess_plist(proc) };
+    let plist = unsafe { Fplist_put(plist, QCserver_process, UserData::new(server).into()) };
+    unsafe { Fset_process_plist(proc, plist) };
+}
+
+fn get_server_process(proc: LispObject) -> Option<SharedServerProcess> {
+    let plist = unsafe { Fprocess_plist(proc) };
+    let server_obj = unsafe { Fplist_get(plist, QCserver_process) };
+    if server_obj.is_nil() {
+        None
+    } else {
+        let server: &SharedServerProcess = unsafe { server_obj.as_userdata_ref() };
+        Some(server.clone())
+    }
+}
+
+fn string_args(args: LispObject) -> Vec<String> {
     let mut args_vec: Vec<String> = vec![];
     if args.is_not_nil() {
         let list_args: LispCons = args.into();
@@ -111,20 +740,177 @@ pub fn make_lsp_connection(
             });
     }
 
-    if let Err(e) = async_create_process(command_string, args_vec, emacs_pipe) {
-        error!("Error creating process, reason {:?}", e);
+    args_vec
+}
+
+fn port_from_lisp(port: LispObject) -> u16 {
+    let port = port.as_natnum_or_error();
+    port.try_into()
+        .unwrap_or_else(|_| error!("Invalid port number {}", port))
+}
+
+// Request ids are either integers or strings, see the JSON RPC
+// specification.
+fn request_id_from_lisp(id: LispObject) -> RequestId {
+    let value = if unsafe { INTEGERP(id) } {
+        json!(unsafe { check_integer_range(id, intmax_t::MIN, intmax_t::MAX) })
+    } else if unsafe { STRINGP(id) } {
+        let id_s: LispStringRef = id.into();
+        json!(id_s.to_utf8())
+    } else {
+        error!("Request id must be an integer or a string");
+    };
+
+    serde_json::from_value(value).unwrap_or_else(|e| error!("Invalid request id: {}", e))
+}
+
+fn request_id_key(id: &RequestId) -> LispObject {
+    serde_to_lisp(json!(id), &JSONConfiguration::default()).unwrap_or_else(|e| error!(e))
+}
+
+fn get_callbacks_table(proc: LispObject) -> LispObject {
+    let plist = unsafe { Fprocess_plist(proc) };
+    unsafe { Fplist_get(plist, QCcallbacks) }
+}
+
+fn register_callbacks(
+    proc: LispObject,
+    id: &RequestId,
+    on_success: LispObject,
+    on_error: LispObject,
+) {
+    let mut table = get_callbacks_table(proc);
+    if table.is_nil() {
+        let mut args = vec![QCtest, Qequal];
+        table = unsafe { Fmake_hash_table(args.len().try_into().unwrap(), args.as_mut_ptr()) };
+        let plist = unsafe { Fprocess_plist(proc) };
+        unsafe { Fset_process_plist(proc, Fplist_put(plist, QCcallbacks, table)) };
+    }
+
+    unsafe {
+        Fputhash(
+            request_id_key(id),
+            LispObject::cons(on_success, on_error),
+            table,
+        )
+    };
+}
+
+// Remove and return the callbacks registered for the request ID, as a
+// cons (ON-SUCCESS . ON-ERROR).
+fn take_callbacks(proc: LispObject, id: &RequestId) -> Option<LispCons> {
+    let table = get_callbacks_table(proc);
+    if table.is_nil() {
+        return None;
     }
 
-    proc
+    let key = request_id_key(id);
+    let callbacks = unsafe { Fgethash(key, table, Qnil) };
+    if callbacks.is_nil() {
+        None
+    } else {
+        unsafe { Fremhash(key, table) };
+        Some(callbacks.into())
+    }
+}
+
+fn get_pending_requests(proc: LispObject) -> SharedPendingRequests {
+    let plist = unsafe { Fprocess_plist(proc) };
+    let pending_obj = unsafe { Fplist_get(plist, QCpending_requests) };
+    if pending_obj.is_nil() {
+        error!("Process was not created by make-lsp-connection");
+    }
+
+    let pending: &SharedPendingRequests = unsafe { pending_obj.as_userdata_ref() };
+    pending.clone()
+}
+
+fn get_default_responders(proc: LispObject) -> SharedDefaultResponders {
+    let plist = unsafe { Fprocess_plist(proc) };
+    let responders_obj = unsafe { Fplist_get(plist, QCdefault_responders) };
+    if responders_obj.is_nil() {
+        error!("Process was not created by make-lsp-connection");
+    }
+
+    let responders: &SharedDefaultResponders = unsafe { responders_obj.as_userdata_ref() };
+    responders.clone()
+}
+
+fn timeout_from_lisp(timeout: LispObject) -> Duration {
+    let seconds = if unsafe { INTEGERP(timeout) } {
+        unsafe { check_integer_range(timeout, 0, intmax_t::MAX) as f64 }
+    } else if unsafe { FLOATP(timeout) } {
+        unsafe { XFLOAT_DATA(timeout) }
+    } else {
+        wrong_type!(Qnumberp, timeout);
+    };
+
+    if !seconds.is_finite() || seconds < 0.0 {
+        error!("Timeout must be a non-negative number of seconds");
+    }
+
+    Duration::try_from_secs_f64(seconds).unwrap_or_else(|e| error!("Invalid timeout: {}", e))
+}
+
+fn call_lisp(function: LispObject, arg: LispObject) {
+    let mut args = vec![function, arg];
+    unsafe { Ffuncall(args.len().try_into().unwrap(), args.as_mut_ptr()) };
 }
 
 /// Process the result of a lsp-server invoked via make-lsp-connection,
 /// and convert it to a lisp object. Data should be a USER-PTR object
 /// that was provided by the lsp-servers handler.
+///
+/// When DATA is the response to a request sent with callbacks by
+/// `lsp-async-send-request', the matching callback is called with the
+/// result or the error object instead, and nil is returned.
+///
+/// When DATA is the response to a completion request kept in Rust, see
+/// `lsp-set-completion-filtering', the result is not converted.
+///
+/// When DATA is output of the server on its standard error, it is passed
+/// to the STDERR destination of the connection, and nil is returned.
+///
+/// When the server started by `make-lsp-tcp-server-connection' did not
+/// accept the connection, the result is (:connect-failed :message MESSAGE).
+///
+/// When the server exited or closed the connection, the result is the
+/// list (:exited :code CODE :signal SIGNAL), where CODE is the exit code
+/// of the server process, or SIGNAL the signal that killed it.  Both are
+/// nil for servers that were not started by the connection.
 #[lisp_fn]
 pub fn lsp_handler(proc: LispObject, data: LispObject) -> LispObject {
     let user_data: UserData = to_owned_userdata(data);
-    let msg: Message = unsafe { user_data.unpack() };
+    let event: ServerEvent = unsafe { user_data.unpack() };
+    let msg = match event {
+        ServerEvent::Message(msg) => msg,
+        ServerEvent::Completion { id, list } => {
+            let list: LispObject = list.into();
+            let callback = take_callbacks(proc, &id).map_or(Qnil, |callbacks| callbacks.car());
+            if callback.is_not_nil() {
+                call_lisp(callback, list);
+                return Qnil;
+            }
+
+            return list!(QCcompletion, QCid, request_id_key(&id), QClist, list);
+        }
+        ServerEvent::Stderr(text) => {
+            handle_stderr(proc, text);
+            return Qnil;
+        }
+        ServerEvent::ConnectFailed(reason) => {
+            return list!(QCconnect_failed, QCmessage, reason.as_str());
+        }
+        ServerEvent::Exited { code, signal } => {
+            return list!(
+                QCexited,
+                QCcode,
+                code.map_or(Qnil, LispObject::from),
+                QCsignal,
+                signal.map_or(Qnil, LispObject::from)
+            );
+        }
+    };
     let config = &get_process_json_config(proc);
     let result = match msg {
         Message::Request(re) => serde_to_lisp(
@@ -132,6 +918,7 @@ pub fn lsp_handler(proc: LispObject, data: LispObject) -> LispObject {
             config,
         ),
         Message::Response(r) => {
+            let is_error = r.error.is_some();
             let response = r.result.unwrap_or_else(|| serde_json::Value::Null);
             let error = r.error.map_or(serde_json::Value::Null, |e| {
                 json!({
@@ -140,6 +927,23 @@ pub fn lsp_handler(proc: LispObject, data: LispObject) -> LispObject {
                     DATA: e.data.unwrap_or(serde_json::Value::Null)
                 })
             });
+
+            let callback = take_callbacks(proc, &r.id)
+                .map(|callbacks| {
+                    if is_error {
+                        callbacks.cdr()
+                    } else {
+                        callbacks.car()
+                    }
+                })
+                .unwrap_or(Qnil);
+            if callback.is_not_nil() {
+                let value = if is_error { error } else { response };
+                let arg = serde_to_lisp(value, config).unwrap_or_else(|e| error!(e));
+                call_lisp(callback, arg);
+                return Qnil;
+            }
+
             serde_to_lisp(json!({ID: r.id, RESULT: response, ERROR: error}), config)
         }
         Message::Notification(n) => {
@@ -152,7 +956,29 @@ pub fn lsp_handler(proc: LispObject, data: LispObject) -> LispObject {
     })
 }
 
-fn get_process_json_config(proc: LispObject) -> JSONConfiguration {
+fn handle_stderr(proc: LispObject, text: String) {
+    let plist = unsafe { Fprocess_plist(proc) };
+    let stderr = unsafe { Fplist_get(plist, QCstderr) };
+    let text = LispObject::from(text.as_str());
+
+    if unsafe { Fbuffer_live_p(stderr) }.is_not_nil() {
+        unsafe {
+            let count = SPECPDL_INDEX();
+            record_unwind_current_buffer();
+            Fset_buffer(stderr);
+            record_unwind_protect_excursion();
+            Fgoto_char(Fpoint_max());
+            let mut args = vec![text];
+            Finsert(args.len().try_into().unwrap(), args.as_mut_ptr());
+            unbind_to(count, Qnil);
+        }
+    } else if stderr.is_not_nil() {
+        let mut args = vec![stderr, proc, text];
+        unsafe { Ffuncall(args.len().try_into().unwrap(), args.as_mut_ptr()) };
+    }
+}
+
+pub fn get_process_json_config(proc: LispObject) -> JSONConfiguration {
     let plist = unsafe { Fprocess_plist(proc) };
     let config_obj = unsafe { Fplist_get(plist, QCjson_config) };
     if config_obj.is_nil() {
@@ -176,7 +1002,7 @@ pub fn lsp_json_config(args: &[LispObject]) -> bool {
     true
 }
 
-fn lisp_to_serde(
+pub fn lisp_to_serde(
     object: LispObject,
     config: &JSONConfiguration,
 ) -> std::result::Result<serde_json::Value, String> {
@@ -217,8 +1043,7 @@ fn lisp_to_serde(
         for i in 0..size {
             let key = unsafe { HASH_KEY(h, i) };
             if key != Qunbound {
-                let key_string: LispStringRef = key.into();
-                let key_utf8 = key_string.to_utf8();
+                let key_utf8 = encode_key(key, false, config)?;
                 let lisp_val = unsafe { HASH_VALUE(h, i) };
                 let insert_result = map.insert(key_utf8, lisp_to_serde(lisp_val, config)?);
                 if insert_result.is_some() {
@@ -274,20 +1099,14 @@ fn lisp_to_serde(
                 (pair_value.car(), pair_value.cdr())
             };
 
-            if !unsafe { SYMBOLP(key) } {
-                reason =
-                    "Plist passed to deser with valid key:value combination, key is not a symbol"
-                        .to_string();
-                return_none = true;
-                return;
-            }
-
-            let key_symbol = unsafe { SYMBOL_NAME(key) };
-            let key_string: LispStringRef = key_symbol.into();
-            let mut key_utf8 = key_string.to_utf8();
-            if is_plist && key_utf8.as_bytes()[0] == b':' && key_utf8.len() > 1 {
-                key_utf8.remove(0);
-            }
+            let key_utf8 = match encode_key(key, is_plist, config) {
+                Ok(key_utf8) => key_utf8,
+                Err(e) => {
+                    reason = e;
+                    return_none = true;
+                    return;
+                }
+            };
 
             // We only will add to the map if a value is not present
             // at that key
@@ -314,7 +1133,54 @@ fn lisp_to_serde(
     }
 }
 
-fn serde_to_lisp(
+// Convert a key of a lisp object to JSON. Keys are strings or symbols,
+// whose colon is dropped in plists and when keys are keywords. Only
+// symbols are renamed by the key transform.
+fn encode_key(
+    key: LispObject,
+    is_plist: bool,
+    config: &JSONConfiguration,
+) -> std::result::Result<String, String> {
+    if unsafe { STRINGP(key) } {
+        let key_string: LispStringRef = key.into();
+        Ok(key_string.to_utf8())
+    } else if unsafe { SYMBOLP(key) } {
+        let key_string: LispStringRef = unsafe { SYMBOL_NAME(key) }.into();
+        let mut key_utf8 = key_string.to_utf8();
+        let strip_colon = is_plist || config.key_type() == KeyType::Keyword;
+        if strip_colon && key_utf8.starts_with(':') && key_utf8.len() > 1 {
+            key_utf8.remove(0);
+        }
+        Ok(config.key_transform.encode(key_utf8))
+    } else {
+        Err("Object keys must be strings or symbols".to_string())
+    }
+}
+
+// Convert a JSON key to lisp, as a string, symbol or keyword. Only
+// symbols and keywords are renamed by the key transform.
+fn decode_key(key: String, config: &JSONConfiguration) -> std::result::Result<LispObject, String> {
+    let key_type = config.key_type();
+    let mut key = if key_type == KeyType::String {
+        key
+    } else {
+        config.key_transform.decode(key)
+    };
+    if key_type == KeyType::Keyword {
+        key.insert(0, ':');
+    }
+
+    let len = key.len();
+    let cstring = CString::new(key).map_err(|e| e.to_string())?;
+    let lisp_key = unsafe { make_string_from_utf8(cstring.as_ptr(), len.try_into().unwrap()) };
+    if key_type == KeyType::String {
+        Ok(lisp_key)
+    } else {
+        Ok(unsafe { Fintern(lisp_key, Qnil) })
+    }
+}
+
+pub fn serde_to_lisp(
     value: serde_json::Value,
     config: &JSONConfiguration,
 ) -> std::result::Result<LispObject, String> {
@@ -386,14 +1252,13 @@ fn serde_to_lisp(
                         map.keys().map(|s| s.clone()).rev().collect::<Vec<String>>();
                     while let Some(k) = keys.pop() {
                         if let Some(v) = map.remove(&k) {
-                            let len = k.len();
-                            let cstring = CString::new(k).map_err(|e| e.to_string())?;
-                            let lisp_key = unsafe {
-                                make_string_from_utf8(cstring.as_ptr(), len.try_into().unwrap())
-                            };
+                            let lisp_key = decode_key(k, config)?;
                             let mut lisp_hash: LispObject = LispObject::from(0);
                             let i = unsafe { hash_lookup(h, lisp_key, &mut lisp_hash) };
-                            assert!(i < 0);
+                            // Distinct keys may be renamed to the same one
+                            if i >= 0 {
+                                return Err("Duplicate keys are not allowed".to_string());
+                            }
                             unsafe { hash_put(h, lisp_key, serde_to_lisp(v, config)?, lisp_hash) };
                         } else {
                             return Err("Error in deserializing json value".to_string());
@@ -408,17 +1273,7 @@ fn serde_to_lisp(
                         map.keys().map(|s| s.clone()).rev().collect::<Vec<String>>();
                     while let Some(k) = keys.pop() {
                         if let Some(v) = map.remove(&k) {
-                            let len = k.len();
-                            let cstring = CString::new(k).map_err(|e| e.to_string())?;
-                            let lisp_key = unsafe {
-                                Fintern(
-                                    make_string_from_utf8(
-                                        cstring.as_ptr(),
-                                        len.try_into().unwrap(),
-                                    ),
-                                    Qnil,
-                                )
-                            };
+                            let lisp_key = decode_key(k, config)?;
                             result = unsafe {
                                 Fcons(Fcons(lisp_key, serde_to_lisp(v, config)?), result)
                             };
@@ -428,27 +1283,12 @@ fn serde_to_lisp(
                     unsafe { Fnreverse(result) }
                 }
                 ObjectType::Plist => {
-                    // @TODO this likely can be optimized by appending the
-                    // : when we clone the string, followed by only looking for
-                    // a slice via map.remove
                     let mut result = Qnil;
                     let mut keys: Vec<String> =
                         map.keys().map(|s| s.clone()).rev().collect::<Vec<String>>();
                     while let Some(k) = keys.pop() {
                         if let Some(v) = map.remove(&k) {
-                            let mut colon_key = String::from(":");
-                            colon_key.push_str(&k);
-                            let len = colon_key.len();
-                            let cstring = CString::new(colon_key).map_err(|e| e.to_string())?;
-                            let lisp_key = unsafe {
-                                Fintern(
-                                    make_string_from_utf8(
-                                        cstring.as_ptr(),
-                                        len.try_into().unwrap(),
-                                    ),
-                                    Qnil,
-                                )
-                            };
+                            let lisp_key = decode_key(k, config)?;
                             result = unsafe { Fcons(lisp_key, result) };
                             result = unsafe { Fcons(serde_to_lisp(v, config)?, result) };
                         }
@@ -466,7 +1306,7 @@ fn serde_to_lisp(
 // This function is written so that if len args == 0, it will return
 // JSONConfiguration::default(). If you edit this function, ensure
 // that you aware of that functionality.
-fn generate_config_from_args(args: &[LispObject]) -> JSONConfiguration {
+pub fn generate_config_from_args(args: &[LispObject]) -> JSONConfiguration {
     let mut config = JSONConfiguration::default();
 
     if args.len() % 2 != 0 {
@@ -513,8 +1353,24 @@ fn generate_config_from_args(args: &[LispObject]) -> JSONConfiguration {
             QCser_false_object => {
                 config.ser_false_obj = value;
             }
+            QCobject_key_type => {
+                config.key_type = match value {
+                    Qstring => Some(KeyType::String),
+                    Qsymbol => Some(KeyType::Symbol),
+                    Qkeyword => Some(KeyType::Keyword),
+                    _ => error!(":object-key-type must be 'string, 'symbol, 'keyword"),
+                };
+            }
+            QCkey_transform => {
+                config.key_transform = match value {
+                    Qnil => KeyTransform::Identity,
+                    Qkebab_case => KeyTransform::KebabCase,
+                    Qsnake_case => KeyTransform::SnakeCase,
+                    _ => error!(":key-transform must be nil, 'kebab-case, 'snake-case"),
+                };
+            }
             _ => {
-                error!("Wrong type: must be :object-type, :array-type, :null-object, :false-object")
+                error!("Wrong type: must be :object-type, :array-type, :null-object, :false-object, :object-key-type, :key-transform")
             }
         }
     }
@@ -549,6 +1405,138 @@ pub fn json_de(args: &[LispObject]) -> LispObject {
     }
 }
 
+/// Parse the JSON text between BEG and END in the current buffer, without
+/// copying it to a string first.  The region must hold a single JSON
+/// value, possibly surrounded by whitespace.  Point is not moved.
+///
+/// OPTIONS are the same as for `json-de'.
+/// usage: (json-parse-buffer-region BEG END &rest OPTIONS)
+#[lisp_fn(min = "2")]
+pub fn json_parse_buffer_region(args: &[LispObject]) -> LispObject {
+    let config = generate_config_from_args(&args[2..]);
+    let (mut beg, mut end) = (args[0], args[1]);
+    unsafe { validate_region(&mut beg, &mut end) };
+    let beg_byte = unsafe { CHAR_TO_BYTE(beg.as_fixnum_or_error() as isize) };
+    let end_byte = unsafe { CHAR_TO_BYTE(end.as_fixnum_or_error() as isize) };
+
+    // The text is only read while parsing, which does not allocate lisp
+    // objects, so it cannot be moved under our feet.
+    let result: serde_json::Result<Value> = unsafe {
+        let buffer = (*current_thread).m_current_buffer;
+        let gpt_byte = (*(*buffer).text).gpt_byte;
+        let segment = |from: isize, to: isize| {
+            slice::from_raw_parts(BYTE_POS_ADDR(from) as *const u8, (to - from) as usize)
+        };
+        if beg_byte < gpt_byte && gpt_byte < end_byte {
+            serde_json::from_reader(segment(beg_byte, gpt_byte).chain(segment(gpt_byte, end_byte)))
+        } else {
+            serde_json::from_slice(segment(beg_byte, end_byte))
+        }
+    };
+
+    match result {
+        Ok(value) => serde_to_lisp(value, &config).unwrap_or_else(|e| error!(e)),
+        Err(e) => error!("Error in parsing json: {:?}", e),
+    }
+}
+
+// Writes at the start of the gap of the current buffer, which must be
+// at point, enlarging the gap as needed. The text is only part of the
+// buffer once it is inserted with insert_from_gap.
+#[derive(Default)]
+struct GapWriter {
+    inserted_chars: isize,
+    inserted_bytes: isize,
+}
+
+impl Write for GapWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let len = buf.len() as isize;
+        unsafe {
+            let text = (*(*current_thread).m_current_buffer).text;
+            let gap_size = (*text).gap_size - self.inserted_bytes;
+            if gap_size < len {
+                // Grow the gap geometrically, as it is reallocated each time
+                make_gap((len - gap_size).max(self.inserted_bytes));
+            }
+            let gpt_addr = (*text)
+                .beg
+                .offset((*text).gpt_byte - 1 + self.inserted_bytes);
+            std::ptr::copy_nonoverlapping(buf.as_ptr(), gpt_addr, buf.len());
+        }
+
+        // serde_json writes UTF-8, which is the multibyte representation of
+        // its characters
+        self.inserted_chars += buf.iter().filter(|b| (**b & 0xC0) != 0x80).count() as isize;
+        self.inserted_bytes += len;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Convert JSON to the bytes inserted in a unibyte buffer, where insert
+// keeps the low 8 bits of the characters that are not ASCII.
+fn unibyte_bytes(json: &str) -> Vec<u8> {
+    json.chars().map(|c| c as u32 as u8).collect()
+}
+
+/// Insert the JSON representation of OBJECT before point, serializing it
+/// directly into the current buffer.  This is the same as
+/// (insert (json-se OBJECT)), without the intermediate string.
+///
+/// OPTIONS are the same as for `json-se'.
+/// usage: (json-se-insert OBJECT &rest OPTIONS)
+#[lisp_fn(min = "1")]
+pub fn json_se_insert(args: &[LispObject]) -> bool {
+    let config = generate_config_from_args(&args[1..]);
+    let value = lisp_to_serde(args[0], &config)
+        .unwrap_or_else(|e| error!("Error in json serialization: {:?}", e));
+
+    let buffer = unsafe { (*current_thread).m_current_buffer };
+    let pt = unsafe { (*buffer).pt };
+    unsafe { prepare_to_modify_buffer(pt, pt, std::ptr::null_mut()) };
+    // The modification hooks may have moved point
+    let (pt, pt_byte) = unsafe { ((*buffer).pt, (*buffer).pt_byte) };
+    unsafe { move_gap_both(pt, pt_byte) };
+
+    let multibyte = unsafe { (*buffer).enable_multibyte_characters_ }.is_not_nil();
+    let mut gap = GapWriter::default();
+    let result = if multibyte {
+        let mut writer = BufWriter::with_capacity(INSERT_BUFFER_SIZE, &mut gap);
+        serde_json::to_writer(&mut writer, &value)
+            .map_err(std::io::Error::from)
+            .and_then(|_| writer.flush())
+    } else {
+        serde_json::to_string(&value)
+            .map_err(std::io::Error::from)
+            .and_then(|json| gap.write_all(&unibyte_bytes(&json)))
+    };
+    if let Err(e) = result {
+        error!("Error in json serialization: {:?}", e);
+    }
+
+    let inserted = if multibyte {
+        gap.inserted_chars
+    } else {
+        gap.inserted_bytes
+    };
+    unsafe {
+        if inserted > 0 {
+            insert_from_gap(inserted, gap.inserted_bytes, false);
+        }
+        signal_after_change(pt, 0, inserted);
+        if inserted > 0 {
+            update_compositions(pt, pt, CHECK_BORDER);
+            set_point_both(pt + inserted, pt_byte + gap.inserted_bytes);
+        }
+    }
+
+    true
+}
+
 pub fn gen_ser_deser_config() -> JSONConfiguration {
     JSONConfiguration {
         null_obj: Qnil,
@@ -571,29 +1559,172 @@ pub fn ser(o: LispObject) -> Result<String> {
         .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{:?}", e)))
 }
 
-#[lisp_fn]
+/// Send a request for METHOD with PARAMS to the server of PROC, a
+/// process made by `make-lsp-connection'.  ID is the integer or string
+/// identifying the request.
+///
+/// ON-SUCCESS and ON-ERROR are optional functions called with the result
+/// or the error object of the response, in place of handing it to the
+/// process handler.  If TIMEOUT is a number of seconds, the request is
+/// cancelled with $/cancelRequest when no response arrived in time; it
+/// is then reported as an error response with the RequestCancelled code
+/// -32800, and the late response of the server is dropped.  The requests
+/// still waiting for a response when the connection is closed are
+/// reported the same way.
+///
+/// An error is signaled when the connection is closed.
+#[lisp_fn(min = "4")]
 pub fn lsp_async_send_request(
     proc: LispObject,
     method: LispObject,
     params: LispObject,
     id: LispObject,
+    on_success: LispObject,
+    on_error: LispObject,
+    timeout: LispObject,
 ) -> bool {
     let mut emacs_pipe = unsafe { EmacsPipe::with_process(proc) };
     let method_s: LispStringRef = method.into();
-    let id_s: LispStringRef = id.into();
+    let request_id = request_id_from_lisp(id);
     let config = get_process_json_config(proc);
-    let value = lisp_to_serde(params, &config);
-    let request = Message::Request(Request::new(
-        RequestId::from(id_s.to_utf8()),
-        method_s.to_utf8(),
-        value.unwrap(),
-    ));
+    let value = lisp_to_serde(params, &config)
+        .unwrap_or_else(|e| error!("Error in json serialization: {:?}", e));
+    let deadline = if timeout.is_nil() {
+        None
+    } else {
+        Instant::now().checked_add(timeout_from_lisp(timeout))
+    };
+    let pending = get_pending_requests(proc);
+    let completions = get_completion_requests(proc);
+    check_connection_open(&pending);
+
+    // The request must see the latest contents of the documents
+    flush_documents(proc, None);
+
+    // The request is registered before it is sent, so that the reader
+    // thread cannot see the response first. The pending requests are
+    // not locked while sending, which blocks when the pipe is full.
+    let method = method_s.to_utf8();
+    let (requests, changed) = &*pending;
+    let registered = {
+        let mut requests = requests.lock().unwrap();
+        if !requests.closed {
+            requests.deadlines.insert(request_id.clone(), deadline);
+            register_completion_request(&completions, &method, &request_id);
+        }
+        !requests.closed
+    };
+    if !registered {
+        error!("Connection to the server is closed");
+    }
+    changed.notify_all();
+
+    let request = Message::Request(Request::new(request_id.clone(), method, value));
     if let Err(e) = emacs_pipe.message_rust_worker(UserData::new(request)) {
+        requests.lock().unwrap().deadlines.remove(&request_id);
+        unregister_completion_request(&completions, &request_id);
         error!("Failed to send request to server, reason {:?}", e);
     }
+    if on_success.is_not_nil() || on_error.is_not_nil() {
+        register_callbacks(proc, &request_id, on_success, on_error);
+    }
     true
 }
 
+/// Return the ids of the requests sent to the server of PROC that did
+/// not get a response yet.
+#[lisp_fn]
+pub fn lsp_pending_requests(proc: LispObject) -> LispObject {
+    let pending = get_pending_requests(proc);
+    let ids: Vec<RequestId> = pending
+        .0
+        .lock()
+        .unwrap()
+        .deadlines
+        .keys()
+        .cloned()
+        .collect();
+    ids.iter().fold(Qnil, |result, id| {
+        LispObject::cons(request_id_key(id), result)
+    })
+}
+
+/// Kill the server process of PROC, a connection made by
+/// `make-lsp-connection' or `make-lsp-tcp-server-connection', and wait
+/// for it to exit.  The handler of PROC then receives the :exited event.
+#[lisp_fn]
+pub fn lsp_connection_kill(proc: LispObject) -> bool {
+    let server = match get_server_process(proc) {
+        Some(server) => server,
+        None => error!("Connection has no server process"),
+    };
+
+    // The lock must be released before signaling an error
+    let result = {
+        let mut server = server.lock().unwrap();
+        if server.status.is_none() {
+            // The server may have exited on its own, and not be reaped yet
+            let _ = server.child.kill();
+            server
+                .child
+                .wait()
+                .map(|status| server.status = Some(status))
+        } else {
+            Ok(())
+        }
+    };
+
+    if let Err(e) = result {
+        error!("Error waiting for server process, reason {:?}", e);
+    }
+
+    true
+}
+
+/// Record the messages exchanged with the server of PROC to FILE, which
+/// is truncated first.  Each message is written on its own line with a
+/// timestamp, in the JSON log format of the LSP inspector, and can be
+/// replayed with `lsp-replay-transcript'.  If FILE is nil, stop
+/// recording.
+#[lisp_fn]
+pub fn lsp_connection_trace(proc: LispObject, file: LispObject) -> bool {
+    let tracer = if file.is_nil() {
+        None
+    } else {
+        let file_s: LispStringRef = file.into();
+        let path = file_s.to_utf8();
+        match File::create(&path) {
+            Ok(f) => Some(Tracer {
+                file: BufWriter::new(f),
+            }),
+            Err(e) => error!("Error opening {}, reason {:?}", path, e),
+        }
+    };
+
+    *get_tracer(proc).lock().unwrap() = tracer;
+    true
+}
+
+/// Replay FILE, a transcript recorded by `lsp-connection-trace', as if
+/// it came from a server.  Return a process object that behaves like the
+/// one of `make-lsp-connection', with HANDLER receiving the messages the
+/// server sent during the recording.
+///
+/// The messages are replayed in order, with the pauses between them, up
+/// to a second each.  A response is held until the request it answers
+/// was sent to the process with the same id, so that the transcript can
+/// drive the same client code again, or for five seconds at most.  The
+/// messages that the client sent during the recording are ignored.
+#[lisp_fn]
+pub fn lsp_replay_transcript(file: LispStringRef, handler: LispObject) -> LispObject {
+    let path = file.to_utf8();
+    let transcript = read_transcript(&path).unwrap_or_else(|e| error!("{}: {}", path, e));
+    let (emacs_pipe, proc, state) = make_connection_pipe(handler, Qnil);
+    spawn_replay_threads(transcript, emacs_pipe, state);
+
+    proc
+}
+
 #[lisp_fn]
 pub fn lsp_async_send_notification(
     proc: LispObject,
@@ -604,6 +1735,8 @@ pub fn lsp_async_send_notification(
     let method_s: LispStringRef = method.into();
     let config = get_process_json_config(proc);
     let value = lisp_to_serde(params, &config);
+    check_connection_open(&get_pending_requests(proc));
+    flush_documents(proc, None);
     let request = Message::Notification(Notification::new(method_s.to_utf8(), value.unwrap()));
     if let Err(e) = emacs_pipe.message_rust_worker(UserData::new(request)) {
         error!("Failed to send notification to server, reason {:?}", e);
@@ -612,54 +1745,544 @@ pub fn lsp_async_send_notification(
     true
 }
 
-pub fn async_create_process(program: String, args: Vec<String>, pipe: EmacsPipe) -> Result<()> {
-    let process: Child = Command::new(program)
+fn send_response(proc: LispObject, response: Response) -> bool {
+    let mut emacs_pipe = unsafe { EmacsPipe::with_process(proc) };
+    check_connection_open(&get_pending_requests(proc));
+    flush_documents(proc, None);
+    if let Err(e) = emacs_pipe.message_rust_worker(UserData::new(Message::Response(response))) {
+        error!("Failed to send response to server, reason {:?}", e);
+    }
+
+    true
+}
+
+/// Answer the request ID received from the server of PROC with RESULT.
+/// ID is the integer or string id of the request, as passed to the
+/// process handler.
+#[lisp_fn]
+pub fn lsp_async_send_response(proc: LispObject, id: LispObject, result: LispObject) -> bool {
+    let config = get_process_json_config(proc);
+    let value = lisp_to_serde(result, &config)
+        .unwrap_or_else(|e| error!("Error in json serialization: {:?}", e));
+    send_response(proc, Response::new_ok(request_id_from_lisp(id), value))
+}
+
+/// Answer the request ID received from the server of PROC with an error
+/// of integer CODE and string MESSAGE.  DATA is optional additional
+/// information about the error.
+#[lisp_fn(min = "4")]
+pub fn lsp_async_send_error(
+    proc: LispObject,
+    id: LispObject,
+    code: LispObject,
+    message: LispStringRef,
+    data: LispObject,
+) -> bool {
+    let config = get_process_json_config(proc);
+    let code = unsafe { check_integer_range(code, i32::MIN.into(), i32::MAX.into()) };
+    let mut response = Response::new_err(
+        request_id_from_lisp(id),
+        code.try_into().unwrap(),
+        message.to_utf8(),
+    );
+    if data.is_not_nil() {
+        let value = lisp_to_serde(data, &config)
+            .unwrap_or_else(|e| error!("Error in json serialization: {:?}", e));
+        if let Some(error) = response.error.as_mut() {
+            error.data = Some(value);
+        }
+    }
+
+    send_response(proc, response)
+}
+
+/// Answer the requests for METHOD from the server of PROC without
+/// calling the process handler.  The response is sent as soon as the
+/// request is read, which suits requests that would otherwise wait for
+/// lisp to be idle, like window/showMessageRequest or
+/// client/registerCapability.
+///
+/// RESULT is the result of every response, null when nil.  For
+/// workspace/configuration, RESULT is instead the settings object that
+/// the requested sections are looked up in, where a section like
+/// "rust-analyzer.cargo" is a path of keys.  Sections that are not
+/// found are answered with null.
+#[lisp_fn(min = "2")]
+pub fn lsp_register_default_responder(
+    proc: LispObject,
+    method: LispStringRef,
+    result: LispObject,
+) -> bool {
+    let config = get_process_json_config(proc);
+    let value = lisp_to_serde(result, &config)
+        .unwrap_or_else(|e| error!("Error in json serialization: {:?}", e));
+    let method = method.to_utf8();
+    let responder = if method == WORKSPACE_CONFIGURATION {
+        DefaultResponder::Configuration(value)
+    } else {
+        DefaultResponder::Result(value)
+    };
+
+    let responders = get_default_responders(proc);
+    responders.lock().unwrap().insert(method, responder);
+    true
+}
+
+/// Stop answering the requests for METHOD from the server of PROC, so
+/// that they are passed to the process handler again.
+#[lisp_fn]
+pub fn lsp_unregister_default_responder(proc: LispObject, method: LispStringRef) -> bool {
+    let responders = get_default_responders(proc);
+    responders.lock().unwrap().remove(&method.to_utf8());
+    true
+}
+
+// Take the requests that are still pending once the connection is
+// closed, as the error responses to report to lisp so that their
+// callbacks are called.
+fn take_closed_requests(pending: &SharedPendingRequests) -> Vec<ServerEvent> {
+    let mut requests = pending.0.lock().unwrap();
+    requests.timed_out.clear();
+    requests
+        .deadlines
+        .drain()
+        .map(|(id, _)| {
+            let report =
+                Response::new_err(id, REQUEST_CANCELLED, String::from("Connection closed"));
+            ServerEvent::Message(Message::Response(report))
+        })
+        .collect()
+}
+
+// Cancel the requests whose deadline passed, and report them to lisp
+// as error responses. Once the connection is closed, the remaining
+// requests are reported as well.
+fn expire_requests(pending: SharedPendingRequests, mut pipe: EmacsPipe) {
+    let sender = pipe.get_sender();
+    let (requests, changed) = &*pending;
+    loop {
+        let expired: Vec<RequestId> = {
+            let mut requests = requests.lock().unwrap();
+            // Sleep until the nearest deadline, or until a request is
+            // registered or the connection closed
+            loop {
+                if requests.closed {
+                    drop(requests);
+                    for event in take_closed_requests(&pending) {
+                        if pipe.message_lisp(&sender, UserData::new(event)).is_err() {
+                            break;
+                        }
+                    }
+                    return;
+                }
+
+                let now = Instant::now();
+                match requests.deadlines.values().flatten().min() {
+                    Some(&deadline) if deadline <= now => break,
+                    Some(&deadline) => {
+                        requests = changed.wait_timeout(requests, deadline - now).unwrap().0;
+                    }
+                    None => requests = changed.wait(requests).unwrap(),
+                }
+            }
+
+            let now = Instant::now();
+            let expired: Vec<RequestId> = requests
+                .deadlines
+                .iter()
+                .filter(|(_, deadline)| deadline.map_or(false, |d| d <= now))
+                .map(|(id, _)| id.clone())
+                .collect();
+            for id in &expired {
+                requests.deadlines.remove(id);
+                requests.timed_out.insert(id.clone());
+            }
+
+            expired
+        };
+
+        for id in expired {
+            let cancel = Message::Notification(Notification::new(
+                CANCEL_REQUEST.to_string(),
+                json!({ ID: id }),
+            ));
+            let report = Message::Response(Response::new_err(
+                id,
+                REQUEST_CANCELLED,
+                String::from("Request timed out"),
+            ));
+
+            if pipe.message_rust_worker(UserData::new(cancel)).is_err()
+                || pipe
+                    .message_lisp(&sender, UserData::new(ServerEvent::Message(report)))
+                    .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+pub fn async_create_process(
+    program: String,
+    args: Vec<String>,
+    pipe: EmacsPipe,
+    state: ConnectionState,
+    capture_stderr: bool,
+) -> Result<SharedServerProcess> {
+    let mut process: Child = Command::new(program)
         .args(args)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
+        .stderr(stderr_stdio(capture_stderr))
         .spawn()?;
 
-    let mut inn = process.stdin;
-    let in_pipe = pipe.clone();
+    let stdin = process.stdin.take().unwrap();
+    let stdout = process.stdout.take().unwrap();
+    let server = watch_server_process(process, &pipe);
+    let messages = queued_messages(pipe.clone());
+    spawn_io_threads(stdout, stdin, messages, pipe, state, Some(server.clone()));
+
+    Ok(server)
+}
+
+// Start forwarding the standard error of the server to lisp, if it is
+// captured, and return the server process to share with the threads.
+fn watch_server_process(mut process: Child, pipe: &EmacsPipe) -> SharedServerProcess {
+    if let Some(stderr) = process.stderr.take() {
+        let stderr_pipe = pipe.clone();
+        thread::spawn(move || forward_stderr(stderr, stderr_pipe));
+    }
+
+    Arc::new(Mutex::new(ServerProcess {
+        child: process,
+        status: None,
+    }))
+}
+
+fn forward_stderr(stderr: ChildStderr, mut pipe: EmacsPipe) {
+    let sender = pipe.get_sender();
+    let mut stderr_reader = BufReader::new(stderr);
+    let mut line = vec![];
+    loop {
+        line.clear();
+        match stderr_reader.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                let text = String::from_utf8_lossy(&line).into_owned();
+                if pipe
+                    .message_lisp(&sender, UserData::new(ServerEvent::Stderr(text)))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// Wait for the server to exit, unless it was already reaped by
+// lsp-connection-kill.
+fn wait_server_process(server: &SharedServerProcess) -> Option<ExitStatus> {
+    loop {
+        {
+            let mut server = server.lock().unwrap();
+            if server.status.is_some() {
+                return server.status;
+            }
+            match server.child.try_wait() {
+                Ok(Some(status)) => {
+                    server.status = Some(status);
+                    return server.status;
+                }
+                Ok(None) => (),
+                Err(_) => return None,
+            }
+        }
+
+        thread::sleep(EXIT_POLL_INTERVAL);
+    }
+}
+
+#[cfg(unix)]
+fn exit_signal(status: &ExitStatus) -> Option<i32> {
+    status.signal()
+}
+
+#[cfg(not(unix))]
+fn exit_signal(_status: &ExitStatus) -> Option<i32> {
+    None
+}
+
+// Answer MSG, read from the server, with a default responder, or retire
+// the pending request it responds to. Return the event to hand to lisp,
+// if any.
+fn filter_message(
+    mut msg: Message,
+    state: &ConnectionState,
+    pipe: &mut EmacsPipe,
+) -> Result<Option<ServerEvent>> {
+    if let Message::Request(request) = &msg {
+        let response = state
+            .responders
+            .lock()
+            .unwrap()
+            .get(&request.method)
+            .map(|responder| responder.respond(request));
+        if let Some(response) = response {
+            pipe.message_rust_worker(UserData::new(Message::Response(response)))?;
+            return Ok(None);
+        }
+    }
+
+    if let Message::Response(response) = &mut msg {
+        {
+            let mut pending = state.pending.0.lock().unwrap();
+            if pending.timed_out.remove(&response.id) {
+                // Already reported to lisp when it timed out
+                return Ok(None);
+            }
+            pending.deadlines.remove(&response.id);
+        }
+
+        let id = response.id.clone();
+        if let Some(list) = take_completion_list(&state.completions, &id, &mut response.result) {
+            return Ok(Some(ServerEvent::Completion { id, list }));
+        }
+    }
+
+    Ok(Some(ServerEvent::Message(msg)))
+}
+
+// The messages queued by lisp, read from the pipe as the writer thread
+// asks for them.
+fn queued_messages(pipe: EmacsPipe) -> impl Iterator<Item = Message> + Send {
+    std::iter::from_fn(move || {
+        let msg = pipe.read_pend_message::<UserData>().ok()?;
+        Some(unsafe { msg.unpack() })
+    })
+}
+
+// Read the messages queued by lisp as they come, so that they do not
+// pile up in the pipe before the connection is made. The messages read
+// once the receiver is dropped are dropped as well.
+fn buffer_messages(pipe: EmacsPipe) -> mpsc::IntoIter<Message> {
+    let (sender, receiver) = mpsc::channel();
     thread::spawn(move || {
-        let mut stdout_writer = BufWriter::new(inn.as_mut().unwrap());
-        while let Ok(msg) = in_pipe.read_pend_message::<UserData>() {
-            let value: Message = unsafe { msg.unpack() };
+        for msg in queued_messages(pipe) {
+            let _ = sender.send(msg);
+        }
+    });
+
+    receiver.into_iter()
+}
 
-            if let Err(_) = value.write(&mut stdout_writer) {
+// Start the threads serving a connection: the writer sends the MESSAGES
+// queued by lisp to the server, the reader hands the messages of the
+// server to lisp, and the timeout thread expires pending requests. Once
+// the server closed its output, the reader reports its exit.
+fn spawn_io_threads<R, W, M>(
+    reader: R,
+    writer: W,
+    messages: M,
+    pipe: EmacsPipe,
+    state: ConnectionState,
+    server: Option<SharedServerProcess>,
+) where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+    M: Iterator<Item = Message> + Send + 'static,
+{
+    let tracer = state.tracer.clone();
+    thread::spawn(move || {
+        let mut stdout_writer = BufWriter::new(writer);
+        for msg in messages {
+            trace(&tracer, Direction::Send, &msg);
+
+            if let Err(_) = msg.write(&mut stdout_writer) {
                 break;
             }
         }
     });
 
-    let mut out = process.stdout;
+    let timeout_pending = state.pending.clone();
+    let timeout_pipe = pipe.clone();
+    let timeout_thread = thread::spawn(move || expire_requests(timeout_pending, timeout_pipe));
+
     let mut out_pipe = pipe.clone();
     let sender = out_pipe.get_sender();
     thread::spawn(move || {
-        let mut stdout_reader = BufReader::new(out.as_mut().unwrap());
+        let mut stdout_reader = BufReader::new(reader);
         loop {
             let parsed_message = Message::read(&mut stdout_reader);
             let msg = match parsed_message {
                 Ok(Some(m)) => m,
-                Ok(None) => Message::Response(Response::new_err(
-                    RequestId::from(0),
-                    PARSE_ERROR,
-                    String::from("Unable to read from stdin"),
-                )),
-                Err(e) => Message::Response(Response::new_err(
-                    RequestId::from(0),
-                    PARSE_ERROR,
-                    format!("JSON Message Error: {:?}", e),
-                )),
+                // The server closed its output
+                Ok(None) => break,
+                // A malformed message, the next one can still be read
+                Err(e) if e.kind() == ErrorKind::InvalidData => {
+                    Message::Response(Response::new_err(
+                        RequestId::from(0),
+                        PARSE_ERROR,
+                        format!("JSON Message Error: {:?}", e),
+                    ))
+                }
+                Err(_) => break,
             };
+            trace(&state.tracer, Direction::Receive, &msg);
 
-            if let Err(_) = out_pipe.message_lisp(&sender, UserData::new(msg)) {
+            let event = match filter_message(msg, &state, &mut out_pipe) {
+                Ok(Some(event)) => event,
+                Ok(None) => continue,
+                Err(_) => break,
+            };
+
+            if let Err(_) = out_pipe.message_lisp(&sender, UserData::new(event)) {
                 break;
             }
         }
+
+        // The requests still pending are reported before the exit
+        close_pending_requests(&state.pending);
+        let _ = timeout_thread.join();
+
+        let status = server.and_then(|server| wait_server_process(&server));
+        let exited = ServerEvent::Exited {
+            code: status.and_then(|status| status.code()),
+            signal: status.and_then(|status| exit_signal(&status)),
+        };
+        let _ = out_pipe.message_lisp(&sender, UserData::new(exited));
+    });
+}
+
+pub struct TranscriptEntry {
+    direction: Direction,
+    timestamp: u64,
+    message: Message,
+}
+
+// Read the messages of a transcript written by a Tracer. Lines that are
+// not messages, like the other output of the LSP inspector format, are
+// skipped.
+fn read_transcript(path: &str) -> std::result::Result<Vec<TranscriptEntry>, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let mut entries = vec![];
+
+    for (lineno, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        let entry: Value = match line.find('{') {
+            Some(start) => match serde_json::from_str(&line[start..]) {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+        if entry.get(IS_LSP_MESSAGE) != Some(&Value::Bool(true)) {
+            continue;
+        }
+
+        let invalid = |what: &str| format!("line {}: invalid {}", lineno + 1, what);
+        let kind = entry
+            .get(TYPE)
+            .and_then(Value::as_str)
+            .ok_or_else(|| invalid(TYPE))?;
+        let direction = if kind.starts_with("send-") {
+            Direction::Send
+        } else if kind.starts_with("receive-") {
+            Direction::Receive
+        } else {
+            return Err(invalid(TYPE));
+        };
+        let timestamp = entry
+            .get(TIMESTAMP)
+            .and_then(Value::as_u64)
+            .ok_or_else(|| invalid(TIMESTAMP))?;
+        let message = entry
+            .get(MESSAGE)
+            .cloned()
+            .and_then(|message| serde_json::from_value(message).ok())
+            .ok_or_else(|| invalid(MESSAGE))?;
+
+        entries.push(TranscriptEntry {
+            direction,
+            timestamp,
+            message,
+        });
+    }
+
+    Ok(entries)
+}
+
+// Ids of the requests sent by lisp to a replayed connection, that the
+// replay thread waits for before delivering their response.
+type SentRequests = Arc<(Mutex<HashSet<RequestId>>, Condvar)>;
+
+fn spawn_replay_threads(transcript: Vec<TranscriptEntry>, pipe: EmacsPipe, state: ConnectionState) {
+    let sent = SentRequests::default();
+
+    let in_pipe = pipe.clone();
+    let in_sent = sent.clone();
+    thread::spawn(move || {
+        while let Ok(msg) = in_pipe.read_pend_message::<UserData>() {
+            if let Message::Request(request) = unsafe { msg.unpack() } {
+                let (ids, sent_cond) = &*in_sent;
+                ids.lock().unwrap().insert(request.id);
+                sent_cond.notify_all();
+            }
+        }
     });
 
-    Ok(())
+    let timeout_pending = state.pending.clone();
+    let timeout_pipe = pipe.clone();
+    let timeout_thread = thread::spawn(move || expire_requests(timeout_pending, timeout_pipe));
+
+    let mut out_pipe = pipe.clone();
+    let sender = out_pipe.get_sender();
+    thread::spawn(move || {
+        let mut previous = transcript.first().map_or(0, |entry| entry.timestamp);
+        for entry in transcript {
+            let delay = Duration::from_millis(entry.timestamp.saturating_sub(previous));
+            previous = entry.timestamp;
+            if entry.direction == Direction::Send {
+                continue;
+            }
+
+            if let Message::Response(response) = &entry.message {
+                // The client may number its requests differently than
+                // the recorded one, so the response is not held forever.
+                let (ids, sent_cond) = &*sent;
+                let deadline = Instant::now() + MAX_REPLAY_WAIT;
+                let mut ids = ids.lock().unwrap();
+                while !ids.remove(&response.id) {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    ids = sent_cond.wait_timeout(ids, deadline - now).unwrap().0;
+                }
+            }
+            thread::sleep(delay.min(MAX_REPLAY_DELAY));
+
+            let event = match filter_message(entry.message, &state, &mut out_pipe) {
+                Ok(Some(event)) => event,
+                Ok(None) => continue,
+                Err(_) => break,
+            };
+
+            if let Err(_) = out_pipe.message_lisp(&sender, UserData::new(event)) {
+                break;
+            }
+        }
+
+        close_pending_requests(&state.pending);
+        let _ = timeout_thread.join();
+
+        let exited = ServerEvent::Exited {
+            code: None,
+            signal: None,
+        };
+        let _ = out_pipe.message_lisp(&sender, UserData::new(exited));
+    });
 }
 
 // In order to have rust generate symbols at compile time,
@@ -678,11 +2301,110 @@ fn init_syms() {
     def_lisp_sym!(QCser_null_object, ":ser-null-object");
     def_lisp_sym!(QCser_false_object, ":ser-false-object");
     def_lisp_sym!(QCjson_config, ":json-config");
+    def_lisp_sym!(QCpending_requests, ":pending-requests");
+    def_lisp_sym!(QCcallbacks, ":callbacks");
+    def_lisp_sym!(QCdefault_responders, ":default-responders");
+    def_lisp_sym!(QCserver_process, ":server-process");
+    def_lisp_sym!(QCstderr, ":stderr");
+    def_lisp_sym!(QCexited, ":exited");
+    def_lisp_sym!(QCconnect_failed, ":connect-failed");
+    def_lisp_sym!(QCcode, ":code");
+    def_lisp_sym!(QCsignal, ":signal");
+    def_lisp_sym!(QCtracer, ":tracer");
+    def_lisp_sym!(QCcompletion, ":completion");
+    def_lisp_sym!(QCid, ":id");
+    def_lisp_sym!(QClist, ":list");
     def_lisp_sym!(Qalist, "alist");
     def_lisp_sym!(Qplist, "plist");
+    def_lisp_sym!(QCobject_key_type, ":object-key-type");
+    def_lisp_sym!(QCkey_transform, ":key-transform");
+    def_lisp_sym!(Qkebab_case, "kebab-case");
+    def_lisp_sym!(Qsnake_case, "snake-case");
+    def_lisp_sym!(Qkeyword, "keyword");
     def_lisp_sym!(Qarray, "array");
 }
 
+#[test]
+fn test_unibyte_bytes() {
+    assert_eq!(unibyte_bytes("[\"a\"]"), b"[\"a\"]");
+    // U+00E9 and U+20AC keep their low 8 bits
+    assert_eq!(
+        unibyte_bytes("\"\u{e9}\u{20ac}\""),
+        vec![b'"', 0xE9, 0xAC, b'"']
+    );
+}
+
+#[test]
+fn test_take_closed_requests() {
+    let pending = SharedPendingRequests::default();
+    {
+        let mut requests = pending.0.lock().unwrap();
+        requests.deadlines.insert(RequestId::from(1), None);
+        requests.timed_out.insert(RequestId::from(2));
+    }
+    close_pending_requests(&pending);
+
+    let events = take_closed_requests(&pending);
+    assert_eq!(events.len(), 1);
+    match &events[0] {
+        ServerEvent::Message(Message::Response(response)) => {
+            assert_eq!(response.id, RequestId::from(1));
+            assert_eq!(response.error.as_ref().unwrap().code, REQUEST_CANCELLED);
+        }
+        _ => panic!("expected an error response"),
+    }
+
+    let requests = pending.0.lock().unwrap();
+    assert!(requests.deadlines.is_empty() && requests.timed_out.is_empty());
+}
+
+#[test]
+fn test_key_transform_decode() {
+    let kebab = KeyTransform::KebabCase;
+    assert_eq!(kebab.decode("textDocument".to_string()), "text-document");
+    assert_eq!(kebab.decode("HTTPServer".to_string()), "http-server");
+    assert_eq!(kebab.decode("utf8String".to_string()), "utf8-string");
+    assert_eq!(kebab.decode("uri".to_string()), "uri");
+    assert_eq!(
+        KeyTransform::SnakeCase.decode("textDocument".to_string()),
+        "text_document"
+    );
+    assert_eq!(
+        KeyTransform::Identity.decode("textDocument".to_string()),
+        "textDocument"
+    );
+}
+
+#[test]
+fn test_key_transform_encode() {
+    let kebab = KeyTransform::KebabCase;
+    assert_eq!(kebab.encode("text-document".to_string()), "textDocument");
+    // Separators that do not follow a letter or a digit are kept
+    assert_eq!(kebab.encode("-private".to_string()), "-private");
+    assert_eq!(kebab.encode("trailing-".to_string()), "trailing-");
+    assert_eq!(
+        KeyTransform::SnakeCase.encode("text_document".to_string()),
+        "textDocument"
+    );
+    assert_eq!(
+        KeyTransform::Identity.encode("text-document".to_string()),
+        "text-document"
+    );
+}
+
+#[test]
+fn test_key_transform_round_trip() {
+    let kebab = KeyTransform::KebabCase;
+    for key in &["textDocument", "workDoneProgress", "uri"] {
+        assert_eq!(kebab.encode(kebab.decode(key.to_string())), *key);
+    }
+
+    // Keys already in kebab-case do not survive the round trip
+    let decoded = kebab.decode("text-document".to_string());
+    assert_eq!(decoded, "text-document");
+    assert_eq!(kebab.encode(decoded), "textDocument");
+}
+
 include!(concat!(
     env!("CARGO_MANIFEST_DIR"),
     "/out/parsing_exports.rs"
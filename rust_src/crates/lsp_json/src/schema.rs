@@ -0,0 +1,734 @@
+use std::collections::HashMap;
+use std::fs;
+
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+use serde_json::{Map, Value};
+
+use emacs::lisp::LispObject;
+use emacs::multibyte::LispStringRef;
+use lisp_macros::lisp_fn;
+
+use emacs::globals::{QCmessage, QCpointer, QCschema_pointer, Qnil};
+
+use emacs::bindings::STRINGP;
+
+use crate::parsing::{generate_config_from_args, lisp_to_serde};
+
+// Bound on nested $ref, so that recursive schemas that do not consume
+// the instance cannot loop forever
+const MAX_REF_DEPTH: usize = 64;
+// Tolerance of multipleOf on floats
+const MULTIPLE_OF_EPSILON: f64 = 1e-9;
+
+// A value that does not satisfy a keyword of the schema. POINTER locates
+// the value in the instance and SCHEMA_POINTER the keyword in the schema.
+struct Violation {
+    pointer: String,
+    schema_pointer: String,
+    message: String,
+}
+
+// Escape KEY for use as a JSON pointer token
+fn escape_pointer(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_i64() || n.is_u64() => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match (name, value) {
+        ("integer", Value::Number(n)) => n.as_f64().map_or(false, |f| f.fract() == 0.0),
+        ("number", Value::Number(_)) => true,
+        _ => type_name(value) == name,
+    }
+}
+
+// Equality of JSON values, where 1 and 1.0 are the same number
+fn json_equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        (Value::Array(a), Value::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| json_equal(a, b))
+        }
+        (Value::Object(a), Value::Object(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .all(|(key, a)| b.get(key).map_or(false, |b| json_equal(a, b)))
+        }
+        _ => a == b,
+    }
+}
+
+struct Validator<'a> {
+    root: &'a Value,
+    // Compiled patterns, None when the pattern is not a valid regex
+    patterns: HashMap<String, Option<Regex>>,
+    violations: Vec<Violation>,
+}
+
+impl<'a> Validator<'a> {
+    fn new(root: &'a Value) -> Self {
+        Validator {
+            root,
+            patterns: HashMap::new(),
+            violations: vec![],
+        }
+    }
+
+    fn violation(&mut self, pointer: &str, schema_pointer: &str, message: String) {
+        self.violations.push(Violation {
+            pointer: pointer.to_string(),
+            schema_pointer: schema_pointer.to_string(),
+            message,
+        });
+    }
+
+    // Return whether INSTANCE is valid against SCHEMA, without recording
+    // any violation.
+    fn is_valid(&mut self, instance: &Value, schema: &'a Value, depth: usize) -> bool {
+        let len = self.violations.len();
+        self.validate(instance, schema, "", "", depth);
+        let valid = self.violations.len() == len;
+        self.violations.truncate(len);
+        valid
+    }
+
+    // Return whether STRING matches PATTERN, or None if PATTERN is invalid
+    fn matches(&mut self, pattern: &str, string: &str) -> Option<bool> {
+        let regex = self
+            .patterns
+            .entry(pattern.to_string())
+            .or_insert_with(|| Regex::new(pattern).ok());
+        regex.as_ref().map(|regex| regex.is_match(string))
+    }
+
+    // Return the JSON pointer in REFERENCE, a percent-encoded URI
+    // fragment, with the schema it designates.
+    fn resolve(&self, reference: &str) -> Option<(String, &'a Value)> {
+        let fragment = reference.strip_prefix('#')?;
+        let pointer = percent_decode_str(fragment).decode_utf8().ok()?;
+        let target = self.root.pointer(&pointer)?;
+        Some((pointer.into_owned(), target))
+    }
+
+    fn validate(
+        &mut self,
+        instance: &Value,
+        schema: &'a Value,
+        pointer: &str,
+        schema_pointer: &str,
+        depth: usize,
+    ) {
+        let schema = match schema {
+            Value::Bool(true) => return,
+            Value::Bool(false) => {
+                return self.violation(pointer, schema_pointer, "No value is allowed".to_string())
+            }
+            Value::Object(schema) => schema,
+            _ => {
+                return self.violation(
+                    pointer,
+                    schema_pointer,
+                    "Schema must be an object or a boolean".to_string(),
+                )
+            }
+        };
+
+        // Keywords next to $ref are ignored, as in draft 7. The violations
+        // of the referenced schema are reported where it is defined.
+        if let Some(reference) = schema.get("$ref") {
+            let at = format!("{}/$ref", schema_pointer);
+            let reference = reference.as_str().unwrap_or_default();
+            return match self.resolve(reference) {
+                Some(_) if depth >= MAX_REF_DEPTH => self.violation(
+                    pointer,
+                    &at,
+                    format!("Too many nested $ref at {}", reference),
+                ),
+                Some((target_pointer, target)) => {
+                    self.validate(instance, target, pointer, &target_pointer, depth + 1)
+                }
+                None => self.violation(pointer, &at, format!("Cannot resolve $ref {}", reference)),
+            };
+        }
+
+        self.validate_generic(instance, schema, pointer, schema_pointer, depth);
+        match instance {
+            Value::Number(n) => {
+                let n = n.as_f64().unwrap_or_default();
+                self.validate_number(n, schema, pointer, schema_pointer);
+            }
+            Value::String(s) => self.validate_string(s, schema, pointer, schema_pointer),
+            Value::Array(items) => {
+                self.validate_array(items, schema, pointer, schema_pointer, depth)
+            }
+            Value::Object(map) => self.validate_object(map, schema, pointer, schema_pointer, depth),
+            _ => {}
+        }
+    }
+
+    fn validate_generic(
+        &mut self,
+        instance: &Value,
+        schema: &'a Map<String, Value>,
+        pointer: &str,
+        schema_pointer: &str,
+        depth: usize,
+    ) {
+        let at = |keyword: &str| format!("{}/{}", schema_pointer, keyword);
+
+        if let Some(types) = schema.get("type") {
+            let names: Vec<&str> = match types {
+                Value::String(name) => vec![name.as_str()],
+                Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+                _ => vec![],
+            };
+            if !names.iter().any(|name| has_type(instance, name)) {
+                let message = format!(
+                    "Expected {}, got {}",
+                    names.join(" or "),
+                    type_name(instance)
+                );
+                self.violation(pointer, &at("type"), message);
+            }
+        }
+
+        if let Some(Value::Array(values)) = schema.get("enum") {
+            if !values.iter().any(|value| json_equal(instance, value)) {
+                let message = "Value is not one of the allowed values".to_string();
+                self.violation(pointer, &at("enum"), message);
+            }
+        }
+
+        if let Some(value) = schema.get("const") {
+            if !json_equal(instance, value) {
+                self.violation(pointer, &at("const"), format!("Value must be {}", value));
+            }
+        }
+
+        if let Some(Value::Array(schemas)) = schema.get("allOf") {
+            for (idx, sub) in schemas.iter().enumerate() {
+                let sub_pointer = format!("{}/allOf/{}", schema_pointer, idx);
+                self.validate(instance, sub, pointer, &sub_pointer, depth);
+            }
+        }
+
+        if let Some(Value::Array(schemas)) = schema.get("anyOf") {
+            if !schemas
+                .iter()
+                .any(|sub| self.is_valid(instance, sub, depth))
+            {
+                let message = "Value does not match any schema of anyOf".to_string();
+                self.violation(pointer, &at("anyOf"), message);
+            }
+        }
+
+        if let Some(Value::Array(schemas)) = schema.get("oneOf") {
+            let count = schemas
+                .iter()
+                .filter(|sub| self.is_valid(instance, sub, depth))
+                .count();
+            if count != 1 {
+                let message = format!("Value matches {} schemas of oneOf instead of 1", count);
+                self.violation(pointer, &at("oneOf"), message);
+            }
+        }
+
+        if let Some(sub) = schema.get("not") {
+            if self.is_valid(instance, sub, depth) {
+                let message = "Value must not match the schema of not".to_string();
+                self.violation(pointer, &at("not"), message);
+            }
+        }
+
+        if let Some(condition) = schema.get("if") {
+            let (keyword, branch) = if self.is_valid(instance, condition, depth) {
+                ("then", schema.get("then"))
+            } else {
+                ("else", schema.get("else"))
+            };
+            if let Some(branch) = branch {
+                self.validate(instance, branch, pointer, &at(keyword), depth);
+            }
+        }
+    }
+
+    fn validate_number(
+        &mut self,
+        n: f64,
+        schema: &Map<String, Value>,
+        pointer: &str,
+        schema_pointer: &str,
+    ) {
+        let bound = |keyword: &str| schema.get(keyword).and_then(Value::as_f64);
+        let checks = [
+            ("minimum", bound("minimum").filter(|min| n < *min), ">="),
+            ("maximum", bound("maximum").filter(|max| n > *max), "<="),
+            (
+                "exclusiveMinimum",
+                bound("exclusiveMinimum").filter(|min| n <= *min),
+                ">",
+            ),
+            (
+                "exclusiveMaximum",
+                bound("exclusiveMaximum").filter(|max| n >= *max),
+                "<",
+            ),
+        ];
+        for (keyword, limit, operator) in checks.iter() {
+            if let Some(limit) = limit {
+                let at = format!("{}/{}", schema_pointer, keyword);
+                self.violation(
+                    pointer,
+                    &at,
+                    format!("Value must be {} {}", operator, limit),
+                );
+            }
+        }
+
+        if let Some(divisor) = bound("multipleOf").filter(|d| *d > 0.0) {
+            let quotient = n / divisor;
+            if (quotient - quotient.round()).abs() > MULTIPLE_OF_EPSILON {
+                let at = format!("{}/multipleOf", schema_pointer);
+                let message = format!("Value must be a multiple of {}", divisor);
+                self.violation(pointer, &at, message);
+            }
+        }
+    }
+
+    fn validate_string(
+        &mut self,
+        s: &str,
+        schema: &Map<String, Value>,
+        pointer: &str,
+        schema_pointer: &str,
+    ) {
+        let at = |keyword: &str| format!("{}/{}", schema_pointer, keyword);
+        let len = s.chars().count() as u64;
+
+        if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+            if len < min {
+                let message = format!("String is shorter than {} characters", min);
+                self.violation(pointer, &at("minLength"), message);
+            }
+        }
+
+        if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+            if len > max {
+                let message = format!("String is longer than {} characters", max);
+                self.violation(pointer, &at("maxLength"), message);
+            }
+        }
+
+        if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+            match self.matches(pattern, s) {
+                Some(true) => {}
+                Some(false) => {
+                    let message = format!("String does not match {}", pattern);
+                    self.violation(pointer, &at("pattern"), message);
+                }
+                None => {
+                    let message = format!("Invalid pattern {}", pattern);
+                    self.violation(pointer, &at("pattern"), message);
+                }
+            }
+        }
+    }
+
+    fn validate_array(
+        &mut self,
+        items: &[Value],
+        schema: &'a Map<String, Value>,
+        pointer: &str,
+        schema_pointer: &str,
+        depth: usize,
+    ) {
+        let at = |keyword: &str| format!("{}/{}", schema_pointer, keyword);
+        let item_pointer = |idx: usize| format!("{}/{}", pointer, idx);
+        let len = items.len() as u64;
+
+        match schema.get("items") {
+            Some(Value::Array(schemas)) => {
+                for (idx, (item, sub)) in items.iter().zip(schemas).enumerate() {
+                    let sub_pointer = format!("{}/{}", at("items"), idx);
+                    self.validate(item, sub, &item_pointer(idx), &sub_pointer, depth);
+                }
+                if let Some(additional) = schema.get("additionalItems") {
+                    for (idx, item) in items.iter().enumerate().skip(schemas.len()) {
+                        let sub_pointer = at("additionalItems");
+                        self.validate(item, additional, &item_pointer(idx), &sub_pointer, depth);
+                    }
+                }
+            }
+            Some(sub) => {
+                for (idx, item) in items.iter().enumerate() {
+                    self.validate(item, sub, &item_pointer(idx), &at("items"), depth);
+                }
+            }
+            None => {}
+        }
+
+        if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+            if len < min {
+                let message = format!("Array has fewer than {} items", min);
+                self.violation(pointer, &at("minItems"), message);
+            }
+        }
+
+        if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+            if len > max {
+                let message = format!("Array has more than {} items", max);
+                self.violation(pointer, &at("maxItems"), message);
+            }
+        }
+
+        if schema.get("uniqueItems") == Some(&Value::Bool(true)) {
+            let duplicate = items
+                .iter()
+                .enumerate()
+                .any(|(idx, a)| items[idx + 1..].iter().any(|b| json_equal(a, b)));
+            if duplicate {
+                let message = "Array items are not unique".to_string();
+                self.violation(pointer, &at("uniqueItems"), message);
+            }
+        }
+
+        if let Some(sub) = schema.get("contains") {
+            if !items.iter().any(|item| self.is_valid(item, sub, depth)) {
+                let message = "No item matches the schema of contains".to_string();
+                self.violation(pointer, &at("contains"), message);
+            }
+        }
+    }
+
+    fn validate_object(
+        &mut self,
+        map: &Map<String, Value>,
+        schema: &'a Map<String, Value>,
+        pointer: &str,
+        schema_pointer: &str,
+        depth: usize,
+    ) {
+        let at = |keyword: &str| format!("{}/{}", schema_pointer, keyword);
+        let len = map.len() as u64;
+
+        if let Some(Value::Array(required)) = schema.get("required") {
+            for name in required.iter().filter_map(Value::as_str) {
+                if !map.contains_key(name) {
+                    let message = format!("Missing required property {}", name);
+                    self.violation(pointer, &at("required"), message);
+                }
+            }
+        }
+
+        if let Some(min) = schema.get("minProperties").and_then(Value::as_u64) {
+            if len < min {
+                let message = format!("Object has fewer than {} properties", min);
+                self.violation(pointer, &at("minProperties"), message);
+            }
+        }
+
+        if let Some(max) = schema.get("maxProperties").and_then(Value::as_u64) {
+            if len > max {
+                let message = format!("Object has more than {} properties", max);
+                self.violation(pointer, &at("maxProperties"), message);
+            }
+        }
+
+        let properties = schema.get("properties").and_then(Value::as_object);
+        let pattern_properties = schema.get("patternProperties").and_then(Value::as_object);
+        let additional = schema.get("additionalProperties");
+        let property_names = schema.get("propertyNames");
+        let dependencies = schema.get("dependencies").and_then(Value::as_object);
+
+        for (key, value) in map {
+            let value_pointer = format!("{}/{}", pointer, escape_pointer(key));
+            let mut matched = false;
+
+            if let Some(sub) = properties.and_then(|properties| properties.get(key)) {
+                let sub_pointer = format!("{}/{}", at("properties"), escape_pointer(key));
+                self.validate(value, sub, &value_pointer, &sub_pointer, depth);
+                matched = true;
+            }
+
+            for (pattern, sub) in pattern_properties.into_iter().flatten() {
+                let sub_pointer =
+                    format!("{}/{}", at("patternProperties"), escape_pointer(pattern));
+                match self.matches(pattern, key) {
+                    Some(true) => {
+                        self.validate(value, sub, &value_pointer, &sub_pointer, depth);
+                        matched = true;
+                    }
+                    Some(false) => {}
+                    None => {
+                        let message = format!("Invalid pattern {}", pattern);
+                        self.violation(pointer, &sub_pointer, message);
+                    }
+                }
+            }
+
+            match additional {
+                Some(Value::Bool(false)) if !matched => {
+                    let message = format!("Property {} is not allowed", key);
+                    self.violation(&value_pointer, &at("additionalProperties"), message);
+                }
+                Some(sub) if !matched => {
+                    let sub_pointer = at("additionalProperties");
+                    self.validate(value, sub, &value_pointer, &sub_pointer, depth);
+                }
+                _ => {}
+            }
+
+            if let Some(sub) = property_names {
+                let name = Value::String(key.clone());
+                if !self.is_valid(&name, sub, depth) {
+                    let message = format!("Property name {} is not allowed", key);
+                    self.violation(&value_pointer, &at("propertyNames"), message);
+                }
+            }
+
+            match dependencies.and_then(|dependencies| dependencies.get(key)) {
+                Some(Value::Array(names)) => {
+                    for name in names.iter().filter_map(Value::as_str) {
+                        if !map.contains_key(name) {
+                            let message = format!("Property {} requires property {}", key, name);
+                            let sub_pointer =
+                                format!("{}/{}", at("dependencies"), escape_pointer(key));
+                            self.violation(pointer, &sub_pointer, message);
+                        }
+                    }
+                }
+                Some(sub) => {
+                    let sub_pointer = format!("{}/{}", at("dependencies"), escape_pointer(key));
+                    let instance = Value::Object(map.clone());
+                    self.validate(&instance, sub, pointer, &sub_pointer, depth);
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+fn violation_to_lisp(violation: Violation) -> LispObject {
+    list!(
+        QCpointer,
+        LispObject::from(violation.pointer.as_str()),
+        QCschema_pointer,
+        LispObject::from(violation.schema_pointer.as_str()),
+        QCmessage,
+        LispObject::from(violation.message.as_str())
+    )
+}
+
+/// Validate VALUE against the JSON Schema SCHEMA, given as lisp data or
+/// as the name of a JSON file.  Return nil if VALUE is valid, otherwise
+/// a list of violations, each a plist with the keys :pointer, the JSON
+/// pointer of the offending part of VALUE, :schema-pointer, the JSON
+/// pointer of the keyword of SCHEMA it violates, and :message.
+///
+/// The keywords of draft 7 are supported, except format and $ref to
+/// other documents.  VALUE, and SCHEMA when given as lisp data, are
+/// converted to JSON with OPTIONS, which are the same as for `json-se'.
+/// usage: (json-validate VALUE SCHEMA &rest OPTIONS)
+#[lisp_fn(min = "2")]
+pub fn json_validate(args: &[LispObject]) -> LispObject {
+    let config = generate_config_from_args(&args[2..]);
+    let instance = lisp_to_serde(args[0], &config)
+        .unwrap_or_else(|e| error!("Error in json serialization: {:?}", e));
+
+    let schema = if unsafe { STRINGP(args[1]) } {
+        let file: LispStringRef = args[1].into();
+        let path = file.to_utf8();
+        fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| serde_json::from_str(&text).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| error!("Error reading schema {}, reason {}", path, e))
+    } else {
+        lisp_to_serde(args[1], &config)
+            .unwrap_or_else(|e| error!("Error in json serialization: {:?}", e))
+    };
+
+    let mut validator = Validator::new(&schema);
+    validator.validate(&instance, &schema, "", "", 0);
+
+    validator
+        .violations
+        .into_iter()
+        .rev()
+        .fold(Qnil, |result, violation| {
+            LispObject::cons(violation_to_lisp(violation), result)
+        })
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCpointer, ":pointer");
+    def_lisp_sym!(QCschema_pointer, ":schema-pointer");
+}
+
+// The pointers of the violations of INSTANCE against SCHEMA
+#[cfg(test)]
+fn violations(schema: Value, instance: Value) -> Vec<(String, String)> {
+    let mut validator = Validator::new(&schema);
+    validator.validate(&instance, &schema, "", "", 0);
+    validator
+        .violations
+        .into_iter()
+        .map(|violation| (violation.pointer, violation.schema_pointer))
+        .collect()
+}
+
+#[cfg(test)]
+fn violation(pointer: &str, schema_pointer: &str) -> (String, String) {
+    (pointer.to_string(), schema_pointer.to_string())
+}
+
+#[test]
+fn test_validate_type() {
+    let schema = json!({ "type": "integer" });
+    assert!(violations(schema.clone(), json!(1.0)).is_empty());
+    assert_eq!(violations(schema, json!("a")), vec![violation("", "/type")]);
+    assert!(violations(json!({ "type": ["string", "null"] }), json!(null)).is_empty());
+    assert_eq!(violations(json!(false), json!(1)), vec![violation("", "")]);
+}
+
+#[test]
+fn test_validate_object() {
+    let schema = json!({
+        "properties": { "a/b": { "type": "string" } },
+        "required": ["c"],
+    });
+    assert_eq!(
+        violations(schema, json!({ "a/b": 1 })),
+        vec![
+            violation("", "/required"),
+            violation("/a~1b", "/properties/a~1b/type"),
+        ]
+    );
+
+    let schema = json!({
+        "patternProperties": { "^x-": { "type": "string" } },
+        "additionalProperties": false,
+    });
+    assert_eq!(
+        violations(schema, json!({ "x-a": "s", "b": 1 })),
+        vec![violation("/b", "/additionalProperties")]
+    );
+}
+
+#[test]
+fn test_validate_ref() {
+    let schema = json!({
+        "definitions": { "positive": { "type": "integer", "minimum": 0 } },
+        "items": { "$ref": "#/definitions/positive" },
+    });
+    assert_eq!(
+        violations(schema, json!([1, -1])),
+        vec![violation("/1", "/definitions/positive/minimum")]
+    );
+    assert_eq!(
+        violations(json!({ "$ref": "#/nothing" }), json!(1)),
+        vec![violation("", "/$ref")]
+    );
+    // A reference to itself stops at MAX_REF_DEPTH
+    assert_eq!(
+        violations(json!({ "$ref": "#" }), json!(1)),
+        vec![violation("", "/$ref")]
+    );
+    // The fragment is percent-decoded before being used as a pointer
+    let schema = json!({
+        "definitions": { "a b": { "type": "string" } },
+        "$ref": "#/definitions/a%20b",
+    });
+    assert_eq!(
+        violations(schema, json!(1)),
+        vec![violation("", "/definitions/a b/type")]
+    );
+}
+
+#[test]
+fn test_validate_combinators() {
+    let schema = json!({ "oneOf": [{ "type": "integer" }, { "minimum": 0 }] });
+    assert_eq!(
+        violations(schema.clone(), json!(1)),
+        vec![violation("", "/oneOf")]
+    );
+    assert!(violations(schema.clone(), json!(-1)).is_empty());
+    assert!(violations(schema.clone(), json!(0.5)).is_empty());
+    assert_eq!(
+        violations(schema, json!(-0.5)),
+        vec![violation("", "/oneOf")]
+    );
+
+    let schema = json!({ "not": { "type": "string" } });
+    assert_eq!(violations(schema, json!("a")), vec![violation("", "/not")]);
+
+    let schema = json!({
+        "if": { "type": "string" },
+        "then": { "maxLength": 1 },
+        "else": { "maximum": 1 },
+    });
+    assert_eq!(
+        violations(schema.clone(), json!("ab")),
+        vec![violation("", "/then/maxLength")]
+    );
+    assert_eq!(
+        violations(schema, json!(2)),
+        vec![violation("", "/else/maximum")]
+    );
+}
+
+#[test]
+fn test_validate_numbers_and_strings() {
+    let schema = json!({ "multipleOf": 0.1 });
+    assert!(violations(schema.clone(), json!(0.3)).is_empty());
+    assert_eq!(
+        violations(schema, json!(0.35)),
+        vec![violation("", "/multipleOf")]
+    );
+
+    // Lengths are counted in characters
+    assert!(violations(json!({ "maxLength": 1 }), json!("\u{e9}")).is_empty());
+    assert_eq!(
+        violations(json!({ "pattern": "(" }), json!("a")),
+        vec![violation("", "/pattern")]
+    );
+}
+
+#[test]
+fn test_validate_equality() {
+    assert!(violations(json!({ "const": 1 }), json!(1.0)).is_empty());
+    assert!(violations(
+        json!({ "enum": [[1, { "a": 2 }]] }),
+        json!([1.0, { "a": 2.0 }])
+    )
+    .is_empty());
+    assert_eq!(
+        violations(json!({ "uniqueItems": true }), json!([1, 1.0])),
+        vec![violation("", "/uniqueItems")]
+    );
+}
+
+#[test]
+fn test_is_valid_discards_violations() {
+    let schema = json!({ "type": "string" });
+    let mut validator = Validator::new(&schema);
+    assert!(!validator.is_valid(&json!(1), &schema, 0));
+    assert!(validator.violations.is_empty());
+}
+
+include!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/out/schema_exports.rs"
+));
@@ -0,0 +1,633 @@
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::os::unix::fs::symlink;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::Sender;
+use ignore::{Walk, WalkBuilder};
+use regex::bytes::{Regex, RegexBuilder};
+
+use emacs::bindings::{
+    Fexpand_file_name, Flist, Fplist_get, Fplist_put, Fprocess_plist, Fset_process_plist,
+};
+use emacs::globals::{
+    QCbatch_size, QCcancel, QCcase_fold, QChidden, QCmax_depth, QCno_ignore, Qnil, Qplistp,
+};
+use emacs::lisp::LispObject;
+use emacs::multibyte::LispStringRef;
+use lisp_macros::lisp_fn;
+
+use crate::lisp_data::LispData;
+use crate::ng_async::{
+    eprint_if_unexpected_error, worker_outcome, EmacsPipe, PipeDataOption, UserData,
+};
+
+const DEFAULT_BATCH_SIZE: usize = 256;
+
+// Partial batches are sent after this delay, so that slow searches
+// still report their results as they go.
+const BATCH_FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+// Files with a NUL byte in their first bytes are binary, and are not
+// searched.
+const BINARY_DETECTION_BYTES: usize = 8192;
+
+struct WalkOptions {
+    hidden: bool,
+    // Honor .gitignore, .ignore and the like
+    ignore: bool,
+    max_depth: Option<usize>,
+    batch_size: usize,
+    case_fold: bool,
+}
+
+impl Default for WalkOptions {
+    fn default() -> Self {
+        WalkOptions {
+            hidden: false,
+            ignore: true,
+            max_depth: None,
+            batch_size: DEFAULT_BATCH_SIZE,
+            case_fold: false,
+        }
+    }
+}
+
+impl WalkOptions {
+    fn from_args(args: &[LispObject]) -> Self {
+        let mut options = WalkOptions::default();
+
+        if args.len() % 2 != 0 {
+            wrong_type!(Qplistp, unsafe {
+                Flist(
+                    args.len().try_into().unwrap(),
+                    args.as_ptr() as *mut LispObject,
+                )
+            });
+        }
+
+        for pair in args.chunks(2) {
+            let value = pair[1];
+            match pair[0] {
+                QChidden => options.hidden = value.is_not_nil(),
+                QCno_ignore => options.ignore = value.is_nil(),
+                QCmax_depth => {
+                    options.max_depth = if value.is_nil() {
+                        None
+                    } else {
+                        Some(value.as_natnum_or_error() as usize)
+                    };
+                }
+                QCbatch_size => {
+                    options.batch_size = value.as_natnum_or_error() as usize;
+                    if options.batch_size == 0 {
+                        error!(":batch-size must be positive");
+                    }
+                }
+                QCcase_fold => options.case_fold = value.is_not_nil(),
+                _ => {
+                    error!("Wrong type: must be :hidden, :no-ignore, :max-depth, :batch-size, :case-fold")
+                }
+            }
+        }
+
+        options
+    }
+
+    fn walk(&self, root: &Path) -> Walk {
+        WalkBuilder::new(root)
+            .standard_filters(self.ignore)
+            .hidden(!self.hidden)
+            .max_depth(self.max_depth)
+            .build()
+    }
+}
+
+// Receives the results of a filesystem operation
+trait Sink {
+    fn is_cancelled(&self) -> bool;
+    fn push(&mut self, item: LispData) -> Result<(), String>;
+}
+
+// Sends the results of a filesystem operation to lisp in batches, as
+// (entries ITEM...) events.
+struct BatchSink {
+    pipe: EmacsPipe,
+    sender: Sender<String>,
+    cancelled: Arc<AtomicBool>,
+    batch: Vec<LispData>,
+    batch_size: usize,
+    last_flush: Instant,
+    total: i64,
+}
+
+impl Sink for BatchSink {
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    fn push(&mut self, item: LispData) -> Result<(), String> {
+        self.batch.push(item);
+        self.total += 1;
+        if self.batch.len() >= self.batch_size || self.last_flush.elapsed() >= BATCH_FLUSH_INTERVAL
+        {
+            self.flush()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl BatchSink {
+    fn flush(&mut self) -> Result<(), String> {
+        self.last_flush = Instant::now();
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+
+        let mut event = vec![LispData::Symbol("entries".to_string())];
+        event.append(&mut self.batch);
+        self.pipe
+            .message_lisp(&self.sender, LispData::List(event, None))
+            .map_err(|e| e.to_string())
+    }
+
+    // Send the last batch, and return the final event
+    fn finish(&mut self) -> Result<LispData, String> {
+        self.flush()?;
+        let state = if self.is_cancelled() {
+            "cancelled"
+        } else {
+            "done"
+        };
+        Ok(LispData::List(
+            vec![LispData::Symbol(state.to_string())],
+            Some(Box::new(LispData::Integer(self.total))),
+        ))
+    }
+}
+
+// Run JOB on a new thread, and return the process through which it
+// reports to HANDLER. Panics and errors of JOB are reported as for
+// rust_worker. The process outlives the job, and is deleted by the
+// handler once it got the last event.
+fn spawn_job<F>(handler: LispObject, batch_size: usize, job: F) -> LispObject
+where
+    F: 'static + FnOnce(&mut BatchSink) -> Result<(), String> + Send,
+{
+    let (pipe, proc) = EmacsPipe::with_handler(
+        handler,
+        PipeDataOption::LISP_DATA,
+        PipeDataOption::LISP_DATA,
+    );
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let mut plist = unsafe { Fprocess_plist(proc) };
+    plist = unsafe { Fplist_put(plist, QCcancel, UserData::new(cancelled.clone()).into()) };
+    unsafe { Fset_process_plist(proc, plist) };
+
+    let sender = pipe.get_sender();
+    thread::spawn(move || {
+        let mut sink = BatchSink {
+            pipe,
+            sender,
+            cancelled,
+            batch: vec![],
+            batch_size,
+            last_flush: Instant::now(),
+            total: 0,
+        };
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+            job(&mut sink).and_then(|()| sink.finish())
+        }));
+        let sent = match worker_outcome(outcome, None) {
+            Ok(event) => sink.pipe.message_lisp(&sink.sender, event),
+            Err(error) => sink.pipe.message_lisp_error(&sink.sender, error),
+        };
+        if let Err(err) = sent {
+            eprint_if_unexpected_error(err);
+        }
+    });
+
+    proc
+}
+
+fn expand_directory(dir: LispObject) -> PathBuf {
+    let expanded: LispStringRef = unsafe { Fexpand_file_name(dir, Qnil) }.into();
+    let path = PathBuf::from(expanded.to_utf8());
+    if !path.is_dir() {
+        error!("Not a directory: {}", path.display());
+    }
+
+    path
+}
+
+fn file_name(path: &Path) -> LispData {
+    LispData::String(path.to_string_lossy().into_owned())
+}
+
+/// Walk the directory tree under DIR in the background, passing the
+/// names of the files found to HANDLER in batches.  HANDLER is called
+/// with the process returned by this function and an event, either
+/// (entries FILE...) for a batch of absolute file names, or (done
+/// . TOTAL) once the walk completes, where TOTAL is the number of
+/// files found.  If the walk is cancelled with `async-fs-cancel', the
+/// last event is (cancelled . TOTAL) instead.
+///
+/// The process is not deleted when the walk is over: HANDLER should pass
+/// it to `delete-process' after the last event, or after an
+/// `async-error' event if the walk failed.
+///
+/// Hidden files and the files ignored by .gitignore, .ignore and the
+/// like are skipped.  OPTIONS is a plist:
+///
+/// :hidden - if non-nil, hidden files are walked too.
+/// :no-ignore - if non-nil, ignore files are not honored.
+/// :max-depth - the depth at which to stop descending, DIR being 0.
+/// :batch-size - the largest number of files in an event, 256 by
+///   default.  Smaller batches are sent when files come slowly.
+/// usage: (async-directory-walk DIR HANDLER &rest OPTIONS)
+#[lisp_fn(min = "2")]
+pub fn async_directory_walk(args: &[LispObject]) -> LispObject {
+    let root = expand_directory(args[0]);
+    let options = WalkOptions::from_args(&args[2..]);
+    let walk = options.walk(&root);
+
+    spawn_job(args[1], options.batch_size, move |sink| {
+        for entry in walk {
+            if sink.is_cancelled() {
+                break;
+            }
+
+            // Unreadable directories are skipped
+            if let Ok(entry) = entry {
+                if entry.file_type().map_or(false, |t| !t.is_dir()) {
+                    sink.push(file_name(entry.path()))?;
+                }
+            }
+        }
+
+        Ok(())
+    })
+}
+
+// Search FILE for REGEX, pushing one (FILE LINE COLUMN TEXT) item per
+// matching line. Unreadable and binary files are skipped.
+fn grep_file<S: Sink>(path: &Path, regex: &Regex, sink: &mut S) -> Result<(), String> {
+    let mut reader = match File::open(path) {
+        Ok(file) => BufReader::new(file),
+        Err(_) => return Ok(()),
+    };
+
+    let is_binary = match reader.fill_buf() {
+        Ok(head) => head.iter().take(BINARY_DETECTION_BYTES).any(|&b| b == 0),
+        Err(_) => true,
+    };
+    if is_binary {
+        return Ok(());
+    }
+
+    let mut line = vec![];
+    let mut number = 0;
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => number += 1,
+        }
+
+        if sink.is_cancelled() {
+            break;
+        }
+
+        let mut text = line.as_slice();
+        while let Some((b'\n', rest)) | Some((b'\r', rest)) = text.split_last() {
+            text = rest;
+        }
+
+        if let Some(m) = regex.find(text) {
+            let column = String::from_utf8_lossy(&text[..m.start()]).chars().count();
+            sink.push(LispData::List(
+                vec![
+                    file_name(path),
+                    LispData::Integer(number),
+                    LispData::Integer(column as i64),
+                    LispData::String(String::from_utf8_lossy(text).into_owned()),
+                ],
+                None,
+            ))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Search the files under DIR for REGEXP in the background, passing
+/// the matching lines to HANDLER in batches.  REGEXP uses the syntax
+/// of the Rust regex crate, not the Emacs one.  The events are as for
+/// `async-directory-walk', except that each match is reported as (FILE
+/// LINE COLUMN TEXT), where LINE counts from 1 and COLUMN, the column
+/// of the match in TEXT, from 0.  Only the first match of a line is
+/// reported, and binary files are skipped.
+///
+/// OPTIONS are those of `async-directory-walk', and :case-fold, which
+/// makes the search ignore case when non-nil.
+/// usage: (async-grep REGEXP DIR HANDLER &rest OPTIONS)
+#[lisp_fn(min = "3")]
+pub fn async_grep(args: &[LispObject]) -> LispObject {
+    let pattern: LispStringRef = args[0].into();
+    let root = expand_directory(args[1]);
+    let options = WalkOptions::from_args(&args[3..]);
+    let regex = match RegexBuilder::new(&pattern.to_utf8())
+        .case_insensitive(options.case_fold)
+        .build()
+    {
+        Ok(regex) => regex,
+        Err(e) => error!("Invalid regexp: {}", e),
+    };
+    let walk = options.walk(&root);
+
+    spawn_job(args[2], options.batch_size, move |sink| {
+        for entry in walk {
+            if sink.is_cancelled() {
+                break;
+            }
+
+            if let Ok(entry) = entry {
+                if entry.file_type().map_or(false, |t| t.is_file()) {
+                    grep_file(entry.path(), &regex, sink)?;
+                }
+            }
+        }
+
+        Ok(())
+    })
+}
+
+// The file a walk error is about, if any
+fn error_path(error: &ignore::Error) -> Option<&Path> {
+    match error {
+        ignore::Error::WithPath { path, .. } => Some(path),
+        ignore::Error::WithDepth { err, .. } | ignore::Error::WithLineNumber { err, .. } => {
+            error_path(err)
+        }
+        ignore::Error::Loop { child, .. } => Some(child),
+        ignore::Error::Partial(errors) => errors.iter().find_map(error_path),
+        _ => None,
+    }
+}
+
+fn copy_entry(
+    source: &Path,
+    target: &Path,
+    file_type: Option<fs::FileType>,
+) -> std::io::Result<()> {
+    match file_type {
+        Some(t) if t.is_dir() => fs::create_dir_all(target),
+        Some(t) if t.is_symlink() => {
+            if fs::symlink_metadata(target).is_ok() {
+                fs::remove_file(target)?;
+            }
+            symlink(fs::read_link(source)?, target)
+        }
+        _ => fs::copy(source, target).map(|_| ()),
+    }
+}
+
+/// Copy the directory tree SOURCE to DEST in the background, creating
+/// DEST if needed and overwriting the files it already has.  All the
+/// files are copied, including hidden and ignored ones, and symbolic
+/// links are copied as links.  HANDLER receives the events described
+/// in `async-directory-walk', with the names of the files copied in
+/// DEST as entries.  The files that could not be copied are reported
+/// among them as (error FILE . MESSAGE).
+///
+/// OPTIONS only accepts :batch-size, as for `async-directory-walk'.
+/// usage: (async-copy-tree SOURCE DEST HANDLER &rest OPTIONS)
+#[lisp_fn(min = "3")]
+pub fn async_copy_tree(args: &[LispObject]) -> LispObject {
+    let source = expand_directory(args[0]);
+    let dest: LispStringRef = unsafe { Fexpand_file_name(args[1], Qnil) }.into();
+    let dest = PathBuf::from(dest.to_utf8());
+    if dest.starts_with(&source) {
+        error!("Cannot copy a directory into itself");
+    }
+
+    let options = WalkOptions::from_args(&args[3..]);
+    if args[3..].iter().step_by(2).any(|key| *key != QCbatch_size) {
+        error!("Wrong type: must be :batch-size");
+    }
+    let walk = WalkOptions {
+        hidden: true,
+        ignore: false,
+        ..options
+    }
+    .walk(&source);
+
+    spawn_job(args[2], options.batch_size, move |sink| {
+        for entry in walk {
+            if sink.is_cancelled() {
+                break;
+            }
+
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    let path = error_path(&e).unwrap_or(&source);
+                    sink.push(LispData::List(
+                        vec![LispData::Symbol("error".to_string()), file_name(path)],
+                        Some(Box::new(LispData::String(e.to_string()))),
+                    ))?;
+                    continue;
+                }
+            };
+
+            let target = match entry.path().strip_prefix(&source) {
+                Ok(relative) => dest.join(relative),
+                Err(_) => continue,
+            };
+            let is_dir = entry.file_type().map_or(false, |t| t.is_dir());
+            match copy_entry(entry.path(), &target, entry.file_type()) {
+                Ok(()) if is_dir => {}
+                Ok(()) => sink.push(file_name(&target))?,
+                Err(e) => sink.push(LispData::List(
+                    vec![
+                        LispData::Symbol("error".to_string()),
+                        file_name(entry.path()),
+                    ],
+                    Some(Box::new(LispData::String(e.to_string()))),
+                ))?,
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// Cancel the filesystem operation of PROC, started by
+/// `async-directory-walk', `async-grep' or `async-copy-tree'.  The
+/// operation stops after the file at hand, and its handler receives a
+/// last (cancelled . TOTAL) event.  Return nil if PROC was not started
+/// by one of these functions.
+#[lisp_fn]
+pub fn async_fs_cancel(proc: LispObject) -> bool {
+    let plist = unsafe { Fprocess_plist(proc) };
+    let cancelled = unsafe { Fplist_get(plist, QCcancel) };
+    if cancelled.is_nil() {
+        return false;
+    }
+
+    unsafe { cancelled.as_userdata_ref::<Arc<AtomicBool>>() }.store(true, Ordering::Relaxed);
+    true
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QChidden, ":hidden");
+    def_lisp_sym!(QCno_ignore, ":no-ignore");
+    def_lisp_sym!(QCmax_depth, ":max-depth");
+    def_lisp_sym!(QCbatch_size, ":batch-size");
+    def_lisp_sym!(QCcase_fold, ":case-fold");
+    def_lisp_sym!(QCcancel, ":cancel");
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct VecSink {
+    items: Vec<LispData>,
+    cancelled: bool,
+}
+
+#[cfg(test)]
+impl Sink for VecSink {
+    fn is_cancelled(&self) -> bool {
+        self.cancelled
+    }
+
+    fn push(&mut self, item: LispData) -> Result<(), String> {
+        self.items.push(item);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn make_test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ng-async-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_grep_file() {
+    let dir = make_test_dir("grep");
+    let path = dir.join("file.txt");
+    fs::write(&path, "foo\nbar baz\r\nxbaz baz\n").unwrap();
+
+    let mut sink = VecSink::default();
+    let regex = RegexBuilder::new("BAZ")
+        .case_insensitive(true)
+        .build()
+        .unwrap();
+    grep_file(&path, &regex, &mut sink).unwrap();
+    let item = |line, column, text: &str| {
+        LispData::List(
+            vec![
+                file_name(&path),
+                LispData::Integer(line),
+                LispData::Integer(column),
+                LispData::String(text.to_string()),
+            ],
+            None,
+        )
+    };
+    assert_eq!(
+        sink.items,
+        vec![item(2, 4, "bar baz"), item(3, 1, "xbaz baz")]
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_grep_file_skips_binary_and_cancelled() {
+    let dir = make_test_dir("grep-binary");
+    let regex = Regex::new("baz").unwrap();
+
+    let binary = dir.join("binary");
+    fs::write(&binary, b"baz\0baz\n").unwrap();
+    let mut sink = VecSink::default();
+    grep_file(&binary, &regex, &mut sink).unwrap();
+    assert!(sink.items.is_empty());
+
+    let text = dir.join("text");
+    fs::write(&text, "baz\n").unwrap();
+    let mut sink = VecSink {
+        cancelled: true,
+        ..Default::default()
+    };
+    grep_file(&text, &regex, &mut sink).unwrap();
+    assert!(sink.items.is_empty());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_copy_entry() {
+    let dir = make_test_dir("copy");
+    let source = dir.join("source");
+    let target = dir.join("target");
+    fs::create_dir(&source).unwrap();
+    fs::write(source.join("file"), "text").unwrap();
+    symlink("file", source.join("link")).unwrap();
+
+    let file_type = |path: &Path| Some(fs::symlink_metadata(path).unwrap().file_type());
+    copy_entry(&source, &target, file_type(&source)).unwrap();
+    assert!(target.is_dir());
+    copy_entry(
+        &source.join("file"),
+        &target.join("file"),
+        file_type(&source.join("file")),
+    )
+    .unwrap();
+    assert_eq!(fs::read_to_string(target.join("file")).unwrap(), "text");
+
+    // Links are copied as links, replacing an existing one
+    symlink("elsewhere", target.join("link")).unwrap();
+    copy_entry(
+        &source.join("link"),
+        &target.join("link"),
+        file_type(&source.join("link")),
+    )
+    .unwrap();
+    assert_eq!(
+        fs::read_link(target.join("link")).unwrap(),
+        PathBuf::from("file")
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_error_path() {
+    let io = || std::io::Error::new(std::io::ErrorKind::PermissionDenied, "denied");
+    let error = ignore::Error::WithDepth {
+        depth: 1,
+        err: Box::new(ignore::Error::WithPath {
+            path: PathBuf::from("/a/b"),
+            err: Box::new(ignore::Error::Io(io())),
+        }),
+    };
+    assert_eq!(error_path(&error), Some(Path::new("/a/b")));
+    assert_eq!(error_path(&ignore::Error::Io(io())), None);
+}
+
+include!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/out/filesystem_exports.rs"
+));
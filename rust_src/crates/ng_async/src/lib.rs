@@ -7,7 +7,10 @@ extern crate emacs;
 #[macro_use]
 extern crate lisp_util;
 
+pub mod filesystem;
+pub mod lisp_data;
 pub mod ng_async;
+pub mod promise;
 
 #[cfg(not(test))]
 include!(concat!(env!("CARGO_MANIFEST_DIR"), "/out/c_exports.rs"));
@@ -0,0 +1,163 @@
+use std::convert::TryInto;
+
+use emacs::bindings::{
+    check_integer_range, intmax_t, make_float, make_int, make_string_from_utf8, make_vector,
+    Fhash_table_test, Fintern, Fmake_hash_table, Fputhash, Ftype_of, AREF, ASET, ASIZE, FLOATP,
+    HASH_KEY, HASH_TABLE_P, HASH_TABLE_SIZE, HASH_VALUE, INTEGERP, STRINGP, SYMBOLP, VECTORP,
+    XFLOAT_DATA, XHASH_TABLE,
+};
+use emacs::globals::{QCsize, QCtest, Qnil, Qunbound};
+use emacs::lisp::LispObject;
+use emacs::list::{LispConsCircularChecks, LispConsEndChecks};
+use emacs::multibyte::LispStringRef;
+
+use crate::ng_async::{PipeData, PipeDataOption};
+
+// Deeper data is most likely self-referential
+const MAX_DEPTH: usize = 10000;
+
+/// A deep copy of lisp data, that can be sent to and from the threads
+/// of the rust workers. Only numbers, strings, symbols, lists, vectors
+/// and hash tables can be copied. Strings lose their text properties,
+/// and symbols are interned again by name when converted back to lisp.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LispData {
+    Integer(i64),
+    Float(f64),
+    String(String),
+    Symbol(String),
+    // The elements of a list, and its last cdr if it is a dotted list
+    List(Vec<LispData>, Option<Box<LispData>>),
+    Vector(Vec<LispData>),
+    // The name of the test of the table, and its entries
+    HashTable(String, Vec<(LispData, LispData)>),
+}
+
+impl PipeData for LispData {
+    fn marker() -> PipeDataOption {
+        PipeDataOption::LISP_DATA
+    }
+}
+
+impl LispData {
+    /// Copy OBJECT, signaling an error if it holds objects that cannot
+    /// be sent to another thread, such as buffers or functions.
+    pub fn from_lisp(object: LispObject) -> LispData {
+        Self::copy(object, 0)
+    }
+
+    fn copy(object: LispObject, depth: usize) -> LispData {
+        if depth > MAX_DEPTH {
+            error!("Lisp data is nested too deeply to be transferred");
+        }
+
+        if unsafe { INTEGERP(object) } {
+            let value = unsafe { check_integer_range(object, intmax_t::MIN, intmax_t::MAX) };
+            LispData::Integer(value)
+        } else if unsafe { FLOATP(object) } {
+            LispData::Float(unsafe { XFLOAT_DATA(object) })
+        } else if unsafe { STRINGP(object) } {
+            let string: LispStringRef = object.into();
+            LispData::String(string.to_utf8())
+        } else if unsafe { SYMBOLP(object) } {
+            let name: LispStringRef = object.force_symbol().symbol_name().into();
+            LispData::Symbol(name.to_utf8())
+        } else if object.is_cons() {
+            let mut items = vec![];
+            let mut last = Qnil;
+            // Signals on circular lists
+            for tail in object.iter_tails(LispConsEndChecks::off, LispConsCircularChecks::on) {
+                items.push(Self::copy(tail.car(), depth + 1));
+                last = tail.cdr();
+            }
+
+            let last = if last.is_nil() {
+                None
+            } else {
+                Some(Box::new(Self::copy(last, depth + 1)))
+            };
+            LispData::List(items, last)
+        } else if unsafe { VECTORP(object) } {
+            let size = unsafe { ASIZE(object) };
+            let items = (0..size)
+                .map(|i| Self::copy(unsafe { AREF(object, i) }, depth + 1))
+                .collect();
+            LispData::Vector(items)
+        } else if unsafe { HASH_TABLE_P(object) } {
+            let test: LispStringRef = unsafe { Fhash_table_test(object) }
+                .force_symbol()
+                .symbol_name()
+                .into();
+            let h = unsafe { XHASH_TABLE(object) };
+            let size = unsafe { HASH_TABLE_SIZE(h) };
+            let mut entries = vec![];
+            for i in 0..size {
+                let key = unsafe { HASH_KEY(h, i) };
+                if key != Qunbound {
+                    let value = unsafe { HASH_VALUE(h, i) };
+                    entries.push((Self::copy(key, depth + 1), Self::copy(value, depth + 1)));
+                }
+            }
+
+            LispData::HashTable(test.to_utf8(), entries)
+        } else {
+            let type_name: LispStringRef = unsafe { Ftype_of(object) }
+                .force_symbol()
+                .symbol_name()
+                .into();
+            error!(
+                "Objects of type {} cannot be transferred between threads",
+                type_name.to_utf8()
+            );
+        }
+    }
+}
+
+fn make_lisp_string(s: &str) -> LispObject {
+    unsafe {
+        make_string_from_utf8(
+            s.as_ptr() as *const libc::c_char,
+            s.len().try_into().unwrap(),
+        )
+    }
+}
+
+impl From<LispData> for LispObject {
+    fn from(data: LispData) -> Self {
+        match data {
+            LispData::Integer(i) => unsafe { make_int(i) },
+            LispData::Float(f) => unsafe { make_float(f) },
+            LispData::String(s) => make_lisp_string(&s),
+            LispData::Symbol(name) => unsafe { Fintern(make_lisp_string(&name), Qnil) },
+            LispData::List(items, last) => {
+                let last = last.map_or(Qnil, |last| LispObject::from(*last));
+                items
+                    .into_iter()
+                    .rev()
+                    .fold(last, |list, item| LispObject::cons(item, list))
+            }
+            LispData::Vector(items) => {
+                let vector = unsafe { make_vector(items.len().try_into().unwrap(), Qnil) };
+                for (i, item) in items.into_iter().enumerate() {
+                    unsafe { ASET(vector, i.try_into().unwrap(), item.into()) };
+                }
+
+                vector
+            }
+            LispData::HashTable(test, entries) => {
+                let mut args = vec![
+                    QCtest,
+                    unsafe { Fintern(make_lisp_string(&test), Qnil) },
+                    QCsize,
+                    LispObject::from(entries.len()),
+                ];
+                let table = unsafe { Fmake_hash_table(args.len() as isize, args.as_mut_ptr()) };
+                for (key, value) in entries {
+                    unsafe { Fputhash(key.into(), value.into(), table) };
+                }
+
+                table
+            }
+        }
+    }
+}
@@ -6,23 +6,37 @@ use std::{
     os::unix::io::{FromRawFd, IntoRawFd},
 };
 
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 
 use crossbeam::channel::{Receiver, Sender};
+use futures::executor::ThreadPool;
+use futures::future::{AbortHandle, Abortable, FutureExt};
+use lazy_static::lazy_static;
 
 use emacs::bindings::{
-    build_string, intern_c_string, make_string_from_utf8, make_user_ptr, Ffuncall,
-    Fmake_pipe_process, Fplist_get, Fplist_put, Fprocess_plist, Fset_process_plist, Fuser_ptrp,
-    XUSER_PTR,
+    build_string, intern_c_string, make_string_from_utf8, make_user_ptr, Ffuncall, Fgethash,
+    Fmake_pipe_process, Fplist_get, Fplist_put, Fprocess_plist, Fput, Frecord, Fremhash,
+    Fset_process_plist, Fuser_ptrp, XUSER_PTR,
 };
 use emacs::globals::{
-    QCcoding, QCfilter, QCinchannel, QCname, QCoutchannel, QCplist, QCtype, Qcall, Qdata, Qnil,
-    Qraw_text, Qreturn, Qstring, Quser_ptr, Quser_ptrp,
+    QCclosed, QCcoding, QCfilter, QCinchannel, QCname, QCnext_id, QCoutchannel, QCplist,
+    QCpromises, QCtasks, QCtype, Qasync_error, Qasync_worker_error, Qasync_worker_panic, Qcall,
+    Qdata, Qerror, Qerror_conditions, Qerror_message, Qlisp_data, Qnil, Qraw_text, Qreturn,
+    Qstring, Qt, Quser_ptr, Quser_ptrp,
 };
 use emacs::process::LispProcessRef;
 use emacs::{lisp::LispObject, multibyte::LispStringRef};
 use lisp_macros::{async_stream, lisp_fn};
 
+use crate::lisp_data::LispData;
+use crate::promise::{fulfill_promise, reject_promises};
+
 #[repr(u32)]
 enum PIPE_PROCESS {
     SUBPROCESS_STDIN = 0,
@@ -33,6 +47,21 @@ enum PIPE_PROCESS {
     _EXEC_MONITOR_OUTPUT = 5,
 }
 
+// Prefix of the pointers sent to async-handler that are worker errors
+const ERROR_TAG: char = 'e';
+
+// Upper bound of the tasks of an async stream that run at the same
+// time. Further messages wait in the pipe until a task completes.
+const MAX_IN_FLIGHT_TASKS: usize = 64;
+
+lazy_static! {
+    // Executor shared by all the async streams
+    static ref EXECUTOR: ThreadPool = ThreadPool::builder()
+        .name_prefix("ng-async-")
+        .create()
+        .expect("Failed to create the async executor");
+}
+
 #[derive(Clone)]
 pub struct EmacsPipe {
     // Represents SUBPROCESS_STDOUT, used to write from a thread or
@@ -61,6 +90,7 @@ fn to_data_option(obj: LispObject) -> Option<PipeDataOption> {
     match obj {
         Qstring => Some(String::marker()),
         Quser_ptr => Some(UserData::marker()),
+        Qlisp_data => Some(LispData::marker()),
         _ => None,
     }
 }
@@ -69,6 +99,7 @@ fn from_data_option(option: PipeDataOption) -> LispObject {
     match option {
         PipeDataOption::STRING => Qstring,
         PipeDataOption::USER_DATA => Quser_ptr,
+        PipeDataOption::LISP_DATA => Qlisp_data,
     }
 }
 
@@ -155,6 +186,7 @@ impl Default for UserData {
 pub enum PipeDataOption {
     STRING,
     USER_DATA,
+    LISP_DATA,
 }
 
 pub trait PipeData {
@@ -173,6 +205,154 @@ impl PipeData for UserData {
     }
 }
 
+/// A message of an async stream, or its result, tagged with the id
+/// returned by async-send-message.
+pub struct Correlated<T> {
+    pub id: i64,
+    pub content: T,
+}
+
+impl<T: PipeData> PipeData for Correlated<T> {
+    fn marker() -> PipeDataOption {
+        T::marker()
+    }
+}
+
+/// The failure of a worker on a message, reported to lisp before the
+/// stream is closed. ID is the id of the message for async streams.
+pub struct WorkerError {
+    pub id: Option<i64>,
+    pub panicked: bool,
+    pub message: String,
+}
+
+/// The value returned by the function of a worker. Either the result
+/// sent back to lisp, or a Result whose errors close the stream.
+pub trait WorkerResult {
+    type Output: PipeData + Send;
+
+    fn into_result(self) -> Result<Self::Output, String>;
+}
+
+impl<T: PipeData + Send> WorkerResult for T {
+    type Output = T;
+
+    fn into_result(self) -> Result<T, String> {
+        Ok(self)
+    }
+}
+
+impl<T: PipeData + Send, E: Display> WorkerResult for Result<T, E> {
+    type Output = T;
+
+    fn into_result(self) -> Result<T, String> {
+        self.map_err(|e| e.to_string())
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Worker panicked".to_string()
+    }
+}
+
+// Turn the outcome of a worker on the message ID, as returned by
+// catch_unwind, into its result or its failure.
+pub fn worker_outcome<R: WorkerResult>(
+    outcome: thread::Result<R>,
+    id: Option<i64>,
+) -> Result<R::Output, WorkerError> {
+    match outcome {
+        Ok(result) => result.into_result().map_err(|message| WorkerError {
+            id,
+            panicked: false,
+            message,
+        }),
+        Err(payload) => Err(WorkerError {
+            id,
+            panicked: true,
+            message: panic_message(payload),
+        }),
+    }
+}
+
+// Counts the tasks of an async stream that did not complete yet
+#[derive(Default)]
+struct TaskSlots {
+    in_flight: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl TaskSlots {
+    fn acquire(&self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        while *in_flight >= MAX_IN_FLIGHT_TASKS {
+            in_flight = self.freed.wait(in_flight).unwrap();
+        }
+        *in_flight += 1;
+    }
+
+    fn release(&self) {
+        *self.in_flight.lock().unwrap() -= 1;
+        self.freed.notify_one();
+    }
+}
+
+// The tasks of an async stream that can still be cancelled
+#[derive(Default)]
+pub struct TaskTable {
+    running: HashMap<i64, AbortHandle>,
+    // Messages cancelled before their task was spawned
+    cancelled: HashSet<i64>,
+    // Id of the last message read by the stream
+    dispatched: i64,
+}
+
+pub type SharedTasks = Arc<Mutex<TaskTable>>;
+
+impl TaskTable {
+    // Return whether the task of message ID should run
+    fn dispatch(&mut self, id: i64, handle: AbortHandle) -> bool {
+        self.dispatched = id;
+        if self.cancelled.remove(&id) {
+            false
+        } else {
+            self.running.insert(id, handle);
+            true
+        }
+    }
+
+    fn finish(&mut self, id: i64) {
+        self.running.remove(&id);
+    }
+
+    /// Abort the task of message ID, or skip it if it was not spawned
+    /// yet.  Tasks that already completed are not affected.
+    pub fn cancel(&mut self, id: i64) {
+        if let Some(handle) = self.running.remove(&id) {
+            handle.abort();
+        } else if id > self.dispatched {
+            self.cancelled.insert(id);
+        }
+    }
+}
+
+/// Return the task table of the async stream PROC, or None if PROC was
+/// not created by async_worker.
+pub fn get_tasks(proc: LispObject) -> Option<SharedTasks> {
+    let plist = unsafe { Fprocess_plist(proc) };
+    let tasks_obj = unsafe { Fplist_get(plist, QCtasks) };
+    if tasks_obj.is_nil() {
+        None
+    } else {
+        Some(unsafe { tasks_obj.as_userdata_ref::<SharedTasks>() }.clone())
+    }
+}
+
 impl EmacsPipe {
     pub unsafe fn with_process(process: LispObject) -> EmacsPipe {
         let raw_proc: LispProcessRef = process.into();
@@ -285,10 +465,26 @@ impl EmacsPipe {
         sender: &Sender<String>,
         content: T,
     ) -> std::io::Result<()> {
-        let mut f = unsafe { File::from_raw_fd(self.out_fd) };
         let ptr = Box::into_raw(Box::new(content));
         let bin = ptr as *mut _ as usize;
-        Self::send(sender, bin.to_string())?;
+        self.notify_lisp(sender, bin.to_string())
+    }
+
+    // Like message_lisp, for the failure of the worker. The pointer is
+    // tagged so that async-handler can tell it from a result.
+    pub fn message_lisp_error(
+        &mut self,
+        sender: &Sender<String>,
+        error: WorkerError,
+    ) -> std::io::Result<()> {
+        let ptr = Box::into_raw(Box::new(error));
+        let bin = ptr as *mut _ as usize;
+        self.notify_lisp(sender, format!("{}{}", ERROR_TAG, bin))
+    }
+
+    fn notify_lisp(&mut self, sender: &Sender<String>, s: String) -> std::io::Result<()> {
+        Self::send(sender, s)?;
+        let mut f = unsafe { File::from_raw_fd(self.out_fd) };
         f.write("r".as_bytes())?;
         f.into_raw_fd();
         Ok(())
@@ -341,7 +537,7 @@ impl EmacsPipe {
     }
 }
 
-fn eprint_if_unexpected_error(err: std::io::Error) {
+pub fn eprint_if_unexpected_error(err: std::io::Error) {
     // If we explicity set "ConnectionAborted" to close the stream
     // we don't want to log, as that was expected.
     if err.kind() != std::io::ErrorKind::ConnectionAborted {
@@ -349,21 +545,31 @@ fn eprint_if_unexpected_error(err: std::io::Error) {
     }
 }
 
-pub fn rust_worker<
-    INPUT: Send + PipeData,
-    OUTPUT: Send + PipeData,
-    T: 'static + Fn(INPUT) -> OUTPUT + Send,
->(
+/// Run FNC on a new thread for each message of a new stream, whose
+/// results are passed to HANDLER. If FNC panics or returns an error,
+/// HANDLER gets an `async-error' event instead, and the stream is
+/// closed.
+pub fn rust_worker<INPUT: Send + PipeData, R: WorkerResult, T: 'static + Fn(INPUT) -> R + Send>(
     handler: LispObject,
     fnc: T,
 ) -> LispObject {
-    let (mut pipe, proc) = EmacsPipe::with_handler(handler, INPUT::marker(), OUTPUT::marker());
+    let (mut pipe, proc) = EmacsPipe::with_handler(handler, INPUT::marker(), R::Output::marker());
     let sender = pipe.get_sender();
     thread::spawn(move || loop {
         match pipe.read_pend_message() {
             Ok(message) => {
-                let result = fnc(message);
-                if let Err(err) = pipe.message_lisp(&sender, result) {
+                let outcome = panic::catch_unwind(AssertUnwindSafe(|| fnc(message)));
+                let sent = match worker_outcome(outcome, None) {
+                    Ok(result) => pipe.message_lisp(&sender, result),
+                    Err(error) => {
+                        if let Err(err) = pipe.message_lisp_error(&sender, error) {
+                            eprint_if_unexpected_error(err);
+                        }
+                        break;
+                    }
+                };
+
+                if let Err(err) = sent {
                     eprint_if_unexpected_error(err);
                     break;
                 }
@@ -378,19 +584,100 @@ pub fn rust_worker<
     proc
 }
 
-fn make_return_value(ptrval: usize, option: PipeDataOption) -> LispObject {
-    match option {
-        PipeDataOption::STRING => {
-            let content = unsafe { *Box::from_raw(ptrval as *mut String) };
-            let nbytes = content.len();
-            let c_content = CString::new(content).unwrap();
-            // These unwraps should be 'safe', as we want to panic if we overflow
-            unsafe { make_string_from_utf8(c_content.as_ptr(), nbytes.try_into().unwrap()) }
+/// Run FNC for each message of a new async stream, whose results are
+/// passed to HANDLER. Each message spawns a task on the shared executor,
+/// so that the results are delivered as they complete, as (ID . RESULT)
+/// where ID is the value returned by async-send-message for the message.
+/// Tasks can be cancelled through the promise of their message. The
+/// stream is closed when a task panics or returns an error, like for
+/// rust_worker.
+pub fn async_worker<INPUT, R, T, F>(handler: LispObject, fnc: T) -> LispObject
+where
+    INPUT: Send + PipeData,
+    R: 'static + Send + WorkerResult,
+    T: 'static + Fn(INPUT) -> F + Send,
+    F: 'static + Future<Output = R> + Send,
+{
+    let (pipe, proc) = EmacsPipe::with_handler(handler, INPUT::marker(), R::Output::marker());
+    let tasks = SharedTasks::default();
+    let mut plist = unsafe { Fprocess_plist(proc) };
+    plist = unsafe { Fplist_put(plist, QCnext_id, LispObject::from(1)) };
+    plist = unsafe { Fplist_put(plist, QCtasks, UserData::new(tasks.clone()).into()) };
+    unsafe { Fset_process_plist(proc, plist) };
+
+    let sender = pipe.get_sender();
+    let slots = Arc::new(TaskSlots::default());
+    thread::spawn(move || loop {
+        match pipe.read_pend_message::<Correlated<INPUT>>() {
+            Ok(Correlated { id, content }) => {
+                let (handle, registration) = AbortHandle::new_pair();
+                if !tasks.lock().unwrap().dispatch(id, handle) {
+                    continue;
+                }
+
+                slots.acquire();
+                let task =
+                    Abortable::new(AssertUnwindSafe(fnc(content)).catch_unwind(), registration);
+                let mut pipe = pipe.clone();
+                let sender = sender.clone();
+                let tasks = tasks.clone();
+                let slots = slots.clone();
+                EXECUTOR.spawn_ok(async move {
+                    // Aborted tasks have no result, their promise is
+                    // already settled.
+                    let sent = match task.await.map(|outcome| worker_outcome(outcome, Some(id))) {
+                        Ok(Ok(content)) => pipe.message_lisp(&sender, Correlated { id, content }),
+                        Ok(Err(error)) => pipe.message_lisp_error(&sender, error),
+                        Err(_) => Ok(()),
+                    };
+                    if let Err(err) = sent {
+                        eprint_if_unexpected_error(err);
+                    }
+                    tasks.lock().unwrap().finish(id);
+                    slots.release();
+                });
+            }
+            Err(err) => {
+                eprint_if_unexpected_error(err);
+                break;
+            }
         }
+    });
 
-        PipeDataOption::USER_DATA => {
-            let content = unsafe { *Box::from_raw(ptrval as *mut UserData) };
-            unsafe { make_user_ptr(content.finalizer, content.data) }
+    proc
+}
+
+fn string_to_lisp(content: String) -> LispObject {
+    let nbytes = content.len();
+    let c_content = CString::new(content).unwrap();
+    // These unwraps should be 'safe', as we want to panic if we overflow
+    unsafe { make_string_from_utf8(c_content.as_ptr(), nbytes.try_into().unwrap()) }
+}
+
+fn user_data_to_lisp(content: UserData) -> LispObject {
+    unsafe { make_user_ptr(content.finalizer, content.data) }
+}
+
+fn correlated_to_lisp<T>(ptrval: usize, to_lisp: fn(T) -> LispObject) -> LispObject {
+    let Correlated { id, content } = unsafe { *Box::from_raw(ptrval as *mut Correlated<T>) };
+    LispObject::cons(LispObject::from(id), to_lisp(content))
+}
+
+fn make_return_value(ptrval: usize, option: PipeDataOption, correlated: bool) -> LispObject {
+    match (option, correlated) {
+        (PipeDataOption::STRING, false) => {
+            string_to_lisp(unsafe { *Box::from_raw(ptrval as *mut String) })
+        }
+        (PipeDataOption::USER_DATA, false) => {
+            user_data_to_lisp(unsafe { *Box::from_raw(ptrval as *mut UserData) })
+        }
+        (PipeDataOption::LISP_DATA, false) => {
+            LispObject::from(unsafe { *Box::from_raw(ptrval as *mut LispData) })
+        }
+        (PipeDataOption::STRING, true) => correlated_to_lisp(ptrval, string_to_lisp),
+        (PipeDataOption::USER_DATA, true) => correlated_to_lisp(ptrval, user_data_to_lisp),
+        (PipeDataOption::LISP_DATA, true) => {
+            correlated_to_lisp::<LispData>(ptrval, LispObject::from)
         }
     }
 }
@@ -405,6 +692,8 @@ pub fn async_handler(proc: LispObject, data: LispStringRef) -> bool {
     let orig_handler = unsafe { Fplist_get(plist, Qcall) };
 
     let mut pipe = unsafe { EmacsPipe::with_process(proc) };
+    // Async streams tag their results with the id of the message
+    let correlated = unsafe { Fplist_get(plist, QCnext_id) }.is_not_nil();
     // This code may seem odd. Since we are in the same process space as
     // the lisp thread, our data transfer is not the data itself, but
     // a pointer to the data. However, 'async-handler' can be called by
@@ -416,12 +705,33 @@ pub fn async_handler(proc: LispObject, data: LispStringRef) -> bool {
     // data from a crossbeam channel.
     for _ in 0..data.len_bytes() {
         if let Ok(s) = pipe.recv() {
+            if let Some(bin) = s.strip_prefix(ERROR_TAG) {
+                let bin = bin.parse::<usize>().unwrap();
+                let error = unsafe { *Box::from_raw(bin as *mut WorkerError) };
+                report_worker_error(proc, &mut pipe, orig_handler, error);
+                continue;
+            }
+
             let bin = s.parse::<usize>().unwrap();
             let qtype = unsafe { Fplist_get(plist, Qreturn) };
             if let Some(quoted_type) = to_data_option(qtype) {
-                let retval = make_return_value(bin, quoted_type);
-                let mut buffer = vec![orig_handler, proc, retval];
-                unsafe { Ffuncall(3, buffer.as_mut_ptr()) };
+                let retval = make_return_value(bin, quoted_type, correlated);
+                // Results awaited by a promise settle it instead of being
+                // passed to the handler.
+                let promises = unsafe { Fplist_get(plist, QCpromises) };
+                let promise = if correlated && promises.is_not_nil() {
+                    unsafe { Fgethash(retval.force_cons().car(), promises, Qnil) }
+                } else {
+                    Qnil
+                };
+                if promise.is_not_nil() {
+                    let result = retval.force_cons();
+                    unsafe { Fremhash(result.car(), promises) };
+                    fulfill_promise(promise, result.cdr());
+                } else {
+                    let mut buffer = vec![orig_handler, proc, retval];
+                    unsafe { Ffuncall(3, buffer.as_mut_ptr()) };
+                }
             } else {
                 // This means that someone has mishandled the
                 // process plist and removed :type. Without this,
@@ -436,6 +746,42 @@ pub fn async_handler(proc: LispObject, data: LispStringRef) -> bool {
     true
 }
 
+// Close PROC after the failure ERROR of its worker, rejecting its
+// pending promises, and pass an `async-error' event to HANDLER.
+fn report_worker_error(
+    proc: LispObject,
+    pipe: &mut EmacsPipe,
+    handler: LispObject,
+    error: WorkerError,
+) {
+    let signal = if error.panicked {
+        Qasync_worker_panic
+    } else {
+        Qasync_worker_error
+    };
+    let message = LispObject::from(error.message.as_str());
+    let err = list!(signal, message);
+
+    let mut plist = unsafe { Fprocess_plist(proc) };
+    plist = unsafe { Fplist_put(plist, QCclosed, err) };
+    unsafe { Fset_process_plist(proc, plist) };
+    // Stop the worker, as the messages sent to it are refused from now on
+    if let Err(err) = pipe.close_stream() {
+        eprint_if_unexpected_error(err);
+    }
+
+    let promises = unsafe { Fplist_get(plist, QCpromises) };
+    if promises.is_not_nil() {
+        reject_promises(promises, err);
+    }
+
+    let id = error.id.map_or(Qnil, LispObject::from);
+    let mut record = vec![Qasync_error, signal, message, id];
+    let event = unsafe { Frecord(record.len() as isize, record.as_mut_ptr()) };
+    let mut buffer = vec![handler, proc, event];
+    unsafe { Ffuncall(3, buffer.as_mut_ptr()) };
+}
+
 #[async_stream]
 pub async fn async_echo(s: String) -> String {
     s
@@ -446,15 +792,29 @@ pub async fn async_data_echo(e: UserData) -> UserData {
     e
 }
 
+#[async_stream]
+pub async fn async_lisp_data_echo(d: LispData) -> LispData {
+    d
+}
+
+fn send_content<T: PipeData>(pipe: &mut EmacsPipe, content: T, id: Option<i64>) -> bool {
+    match id {
+        Some(id) => pipe.message_rust_worker(Correlated { id, content }),
+        None => pipe.message_rust_worker(content),
+    }
+    .is_ok()
+}
+
 fn internal_send_message(
     pipe: &mut EmacsPipe,
     message: LispObject,
     option: PipeDataOption,
+    id: Option<i64>,
 ) -> bool {
     match option {
         PipeDataOption::STRING => {
             let string: LispStringRef = message.into();
-            pipe.message_rust_worker(string.to_utf8()).is_ok()
+            send_content(pipe, string.to_utf8(), id)
         }
         PipeDataOption::USER_DATA => {
             if !is_user_ptr(message) {
@@ -469,18 +829,49 @@ fn internal_send_message(
                 (*data_ptr).finalizer = None;
             };
 
-            pipe.message_rust_worker(ud).is_ok()
+            send_content(pipe, ud, id)
         }
+        PipeDataOption::LISP_DATA => send_content(pipe, LispData::from_lisp(message), id),
     }
 }
 
+/// Send MESSAGE to the rust worker of PROC.  For async streams, whose
+/// messages run concurrently, return the id of the message, which is
+/// given back with its result as (ID . RESULT).  Otherwise return t.
+/// Return nil if the message could not be sent.
+///
+/// When the worker panics or returns an error, the handler of PROC is
+/// called with an `async-error' record instead, #s(async-error SIGNAL
+/// MESSAGE ID), where SIGNAL is `async-worker-panic' or
+/// `async-worker-error' and ID is the id of the failed message, if
+/// any.  PROC is then closed, and sending it a message signals an
+/// error.
 #[lisp_fn]
-pub fn async_send_message(proc: LispObject, message: LispObject) -> bool {
+pub fn async_send_message(proc: LispObject, message: LispObject) -> LispObject {
     let mut pipe = unsafe { EmacsPipe::with_process(proc) };
     let plist = unsafe { Fprocess_plist(proc) };
+    let closed = unsafe { Fplist_get(plist, QCclosed) };
+    if closed.is_not_nil() {
+        let reason: LispStringRef = closed.force_cons().cdr().force_cons().car().into();
+        error!("Async stream is closed: {}", reason.to_utf8());
+    }
+
     let qtype = unsafe { Fplist_get(plist, QCtype) };
     if let Some(option) = to_data_option(qtype) {
-        internal_send_message(&mut pipe, message, option)
+        let next_id = unsafe { Fplist_get(plist, QCnext_id) };
+        let id = if next_id.is_nil() {
+            None
+        } else {
+            let id = next_id.as_fixnum_or_error();
+            unsafe { Fplist_put(plist, QCnext_id, LispObject::from(id + 1)) };
+            Some(id)
+        };
+
+        match (internal_send_message(&mut pipe, message, option, id), id) {
+            (true, Some(id)) => LispObject::from(id),
+            (true, None) => Qt,
+            (false, _) => Qnil,
+        }
     } else {
         // This means that someone has mishandled the
         // process plist and removed :type. Without this,
@@ -495,10 +886,85 @@ pub fn async_close_stream(proc: LispObject) -> bool {
     pipe.close_stream().is_ok()
 }
 
+// Give the errors reported by the workers their conditions, like
+// define-error. This is called at startup, see build.rs.
+pub fn init_errors() {
+    unsafe {
+        Fput(
+            Qasync_worker_error,
+            Qerror_conditions,
+            list!(Qasync_worker_error, Qerror),
+        );
+        Fput(
+            Qasync_worker_error,
+            Qerror_message,
+            LispObject::from("Async worker failed"),
+        );
+        Fput(
+            Qasync_worker_panic,
+            Qerror_conditions,
+            list!(Qasync_worker_panic, Qasync_worker_error, Qerror),
+        );
+        Fput(
+            Qasync_worker_panic,
+            Qerror_message,
+            LispObject::from("Async worker panicked"),
+        );
+    }
+}
+
 #[allow(dead_code)]
 fn init_syms() {
     def_lisp_sym!(QCinchannel, "inchannel");
     def_lisp_sym!(QCoutchannel, "outchannel");
+    def_lisp_sym!(QCnext_id, ":next-id");
+    def_lisp_sym!(QCtasks, ":tasks");
+    def_lisp_sym!(QCpromises, ":promises");
+    def_lisp_sym!(Qlisp_data, "lisp-data");
+    def_lisp_sym!(QCclosed, ":closed");
+    def_lisp_sym!(Qasync_error, "async-error");
+    def_lisp_sym!(Qasync_worker_error, "async-worker-error");
+    def_lisp_sym!(Qasync_worker_panic, "async-worker-panic");
+}
+
+#[cfg(test)]
+fn is_aborted(registration: futures::future::AbortRegistration) -> bool {
+    futures::executor::block_on(Abortable::new(async {}, registration)).is_err()
+}
+
+#[test]
+fn test_cancel_running_task() {
+    let mut tasks = TaskTable::default();
+    let (handle, registration) = AbortHandle::new_pair();
+    assert!(tasks.dispatch(1, handle));
+    tasks.cancel(1);
+    assert!(is_aborted(registration));
+    assert!(tasks.running.is_empty());
+}
+
+#[test]
+fn test_cancel_task_before_dispatch() {
+    let mut tasks = TaskTable::default();
+    tasks.cancel(2);
+    let (handle, registration) = AbortHandle::new_pair();
+    assert!(tasks.dispatch(1, handle));
+    let (handle, _) = AbortHandle::new_pair();
+    assert!(!tasks.dispatch(2, handle));
+    assert!(tasks.cancelled.is_empty());
+    assert!(!is_aborted(registration));
+}
+
+#[test]
+fn test_cancel_finished_task() {
+    let mut tasks = TaskTable::default();
+    let (handle, registration) = AbortHandle::new_pair();
+    assert!(tasks.dispatch(1, handle));
+    tasks.finish(1);
+    tasks.cancel(1);
+    // The message was already read, so it is not remembered
+    assert!(tasks.cancelled.is_empty());
+    assert!(tasks.running.is_empty());
+    assert!(!is_aborted(registration));
 }
 
 include!(concat!(
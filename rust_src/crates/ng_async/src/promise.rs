@@ -0,0 +1,541 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+
+use emacs::bindings::{
+    check_integer_range, internal_condition_case_n, intmax_t, make_float, make_vector,
+    Faccept_process_output, Fclrhash, Ffuncall, Fget, Fgethash, Fmake_hash_table, Fnreverse,
+    Fplist_get, Fplist_put, Fprocess_plist, Fput, Fputhash, Fremhash, Fset_process_plist, AREF,
+    ASET, FLOATP, HASH_KEY, HASH_TABLE_SIZE, HASH_VALUE, INTEGERP, XFLOAT_DATA, XHASH_TABLE,
+};
+use emacs::eval::signal_rust;
+use emacs::globals::{
+    QCpromises, QCtest, Qall, Qasync_promise, Qasync_promisep, Qcancelled, Qeql, Qerror,
+    Qfulfilled, Qnil, Qnumberp, Qpending, Qrace, Qregistry, Qrejected, Qt, Qthen, Qunbound,
+};
+use emacs::lisp::LispObject;
+use lisp_macros::lisp_fn;
+
+use crate::ng_async::{async_send_message, get_tasks, SharedTasks, UserData};
+
+// How often async-promise-await reads the output of the async streams
+const AWAIT_POLL_INTERVAL: f64 = 0.05;
+
+// Slots of the registry entry of a promise
+const STATE: isize = 0;
+const VALUE: isize = 1;
+const REACTIONS: isize = 2;
+// Non-nil once the promise object was garbage collected, so that the
+// entry can go as soon as the promise is settled
+const ORPHANED: isize = 3;
+// (PROMISES . MESSAGE-ID) for the promise of a message, where PROMISES
+// is the :promises table of its stream
+const SOURCE: isize = 4;
+const ENTRY_SIZE: isize = 5;
+
+// Slots of a reaction, run when the promise it is registered on is
+// settled. KIND is then, all or race, and CHILD the id of the promise
+// settled by the reaction.
+const KIND: isize = 0;
+const CHILD: isize = 1;
+// The callbacks of a then reaction, or the index of an all reaction
+const ARG1: isize = 2;
+const ARG2: isize = 3;
+const REACTION_SIZE: isize = 4;
+
+static NEXT_PROMISE_ID: AtomicI64 = AtomicI64::new(1);
+
+lazy_static! {
+    // Promises whose lisp object was garbage collected
+    static ref DEAD_PROMISES: Mutex<Vec<i64>> = Mutex::new(vec![]);
+}
+
+thread_local! {
+    // Error signaled by the last promise callback
+    static CALLBACK_ERROR: Cell<Option<LispObject>> = Cell::new(None);
+}
+
+// The lisp side of a promise. Its state, value and reactions are kept
+// in the registry, a hash table reachable from lisp, as the garbage
+// collector does not see the objects referenced from a user-ptr.
+struct Promise {
+    id: i64,
+    // The task that settles the promise, and the id of its message
+    task: Option<(SharedTasks, i64)>,
+}
+
+impl Drop for Promise {
+    fn drop(&mut self) {
+        DEAD_PROMISES.lock().unwrap().push(self.id);
+    }
+}
+
+extern "C" fn finalize_promise(raw: *mut libc::c_void) {
+    let _promise = unsafe { Box::from_raw(raw as *mut Promise) };
+}
+
+fn as_promise_data(obj: &LispObject) -> Option<&Promise> {
+    unsafe { obj.as_userdata_with_finalizer(finalize_promise) }
+}
+
+fn is_promise(obj: LispObject) -> bool {
+    as_promise_data(&obj).is_some()
+}
+
+fn as_promise(obj: &LispObject) -> &Promise {
+    match as_promise_data(obj) {
+        Some(promise) => promise,
+        None => {
+            wrong_type!(Qasync_promisep, *obj);
+        }
+    }
+}
+
+fn registry() -> LispObject {
+    let table = unsafe { Fget(Qasync_promise, Qregistry) };
+    if table.is_not_nil() {
+        return table;
+    }
+
+    let mut args = vec![QCtest, Qeql];
+    let table = unsafe { Fmake_hash_table(args.len() as isize, args.as_mut_ptr()) };
+    unsafe { Fput(Qasync_promise, Qregistry, table) };
+    table
+}
+
+fn entry(id: i64) -> LispObject {
+    unsafe { Fgethash(LispObject::from(id), registry(), Qnil) }
+}
+
+// Drop the entries of the settled promises that were garbage collected,
+// and mark the pending ones.
+fn purge_dead_promises() {
+    let dead = std::mem::take(&mut *DEAD_PROMISES.lock().unwrap());
+    for id in dead {
+        let entry = entry(id);
+        if entry.is_nil() {
+            continue;
+        }
+
+        if unsafe { AREF(entry, STATE) } == Qpending {
+            unsafe { ASET(entry, ORPHANED, Qt) };
+        } else {
+            unsafe { Fremhash(LispObject::from(id), registry()) };
+        }
+    }
+}
+
+fn make_promise(task: Option<(SharedTasks, i64)>) -> (i64, LispObject) {
+    purge_dead_promises();
+
+    let id = NEXT_PROMISE_ID.fetch_add(1, Ordering::SeqCst);
+    let entry = unsafe { make_vector(ENTRY_SIZE, Qnil) };
+    unsafe {
+        ASET(entry, STATE, Qpending);
+        Fputhash(LispObject::from(id), entry, registry());
+    }
+
+    let raw = Box::into_raw(Box::new(Promise { id, task }));
+    let promise =
+        UserData::with_data_and_finalizer(raw as *mut libc::c_void, Some(finalize_promise));
+    (id, promise.into())
+}
+
+fn make_reaction(kind: LispObject, child: i64, arg1: LispObject, arg2: LispObject) -> LispObject {
+    let reaction = unsafe { make_vector(REACTION_SIZE, Qnil) };
+    unsafe {
+        ASET(reaction, KIND, kind);
+        ASET(reaction, CHILD, LispObject::from(child));
+        ASET(reaction, ARG1, arg1);
+        ASET(reaction, ARG2, arg2);
+    }
+    reaction
+}
+
+extern "C" fn funcall_callback(nargs: isize, args: *mut LispObject) -> LispObject {
+    unsafe { Ffuncall(nargs, args) }
+}
+
+extern "C" fn catch_callback_error(
+    err: LispObject,
+    _nargs: isize,
+    _args: *mut LispObject,
+) -> LispObject {
+    CALLBACK_ERROR.with(|error| error.set(Some(err)));
+    Qnil
+}
+
+// Call CALLBACK with VALUE, returning the error it signals as
+// (ERROR-SYMBOL . DATA).
+fn call_callback(callback: LispObject, value: LispObject) -> Result<LispObject, LispObject> {
+    let mut args = [callback, value];
+    let result = unsafe {
+        internal_condition_case_n(
+            Some(funcall_callback),
+            args.len() as isize,
+            args.as_mut_ptr(),
+            Qerror,
+            Some(catch_callback_error),
+        )
+    };
+
+    match CALLBACK_ERROR.with(|error| error.take()) {
+        Some(err) => Err(err),
+        None => Ok(result),
+    }
+}
+
+// Settle the promise ID with STATE and VALUE, unless it is already
+// settled, and run its reactions.
+fn settle(id: i64, state: LispObject, value: LispObject) {
+    let entry = entry(id);
+    if entry.is_nil() || unsafe { AREF(entry, STATE) } != Qpending {
+        return;
+    }
+
+    let reactions = unsafe {
+        ASET(entry, STATE, state);
+        ASET(entry, VALUE, value);
+        let reactions = AREF(entry, REACTIONS);
+        ASET(entry, REACTIONS, Qnil);
+        if AREF(entry, ORPHANED).is_not_nil() {
+            Fremhash(LispObject::from(id), registry());
+        }
+        Fnreverse(reactions)
+    };
+
+    let mut tail = reactions;
+    while tail.is_cons() {
+        let cons = tail.force_cons();
+        react(cons.car(), state, value);
+        tail = cons.cdr();
+    }
+}
+
+/// Fulfill PROMISE with VALUE, the result of its task.
+pub fn fulfill_promise(promise: LispObject, value: LispObject) {
+    settle(as_promise(&promise).id, Qfulfilled, value);
+}
+
+/// Reject the promises in the hash table PROMISES with ERROR, as
+/// (ERROR-SYMBOL . DATA), and empty the table.
+pub fn reject_promises(promises: LispObject, error: LispObject) {
+    let h = unsafe { XHASH_TABLE(promises) };
+    let size = unsafe { HASH_TABLE_SIZE(h) };
+    let mut ids = vec![];
+    for i in 0..size {
+        if unsafe { HASH_KEY(h, i) } != Qunbound {
+            ids.push(as_promise(&unsafe { HASH_VALUE(h, i) }).id);
+        }
+    }
+
+    // The callbacks of the promises may use the table
+    unsafe { Fclrhash(promises) };
+    for id in ids {
+        settle(id, Qrejected, error);
+    }
+}
+
+fn react(reaction: LispObject, state: LispObject, value: LispObject) {
+    let (kind, child, arg1, arg2) = unsafe {
+        (
+            AREF(reaction, KIND),
+            AREF(reaction, CHILD).as_fixnum_or_error(),
+            AREF(reaction, ARG1),
+            AREF(reaction, ARG2),
+        )
+    };
+
+    match kind {
+        Qthen => {
+            let callback = match state {
+                Qfulfilled => arg1,
+                Qrejected => arg2,
+                _ => Qnil,
+            };
+            if callback.is_nil() {
+                return settle(child, state, value);
+            }
+
+            match call_callback(callback, value) {
+                Ok(result) if is_promise(result) => {
+                    let reaction = make_reaction(Qrace, child, Qnil, Qnil);
+                    add_reaction(as_promise(&result).id, reaction);
+                }
+                Ok(result) => settle(child, Qfulfilled, result),
+                Err(err) => settle(child, Qrejected, err),
+            }
+        }
+        Qall if state == Qfulfilled => {
+            let entry = entry(child);
+            if entry.is_nil() || unsafe { AREF(entry, STATE) } != Qpending {
+                return;
+            }
+
+            // The values of an all promise are collected in a vector,
+            // whose last slot counts the promises left.
+            let values = unsafe { AREF(entry, VALUE) };
+            let count = unsafe { AREF(values, 0) };
+            let left = unsafe { AREF(values, count.as_fixnum_or_error() as isize + 1) };
+            let left = left.as_fixnum_or_error() - 1;
+            unsafe {
+                ASET(values, arg1.as_fixnum_or_error() as isize + 1, value);
+                ASET(
+                    values,
+                    count.as_fixnum_or_error() as isize + 1,
+                    LispObject::from(left),
+                );
+            }
+
+            if left == 0 {
+                let count = count.as_fixnum_or_error() as isize;
+                let list = (1..=count).rev().fold(Qnil, |list, idx| {
+                    LispObject::cons(unsafe { AREF(values, idx) }, list)
+                });
+                settle(child, Qfulfilled, list);
+            }
+        }
+        _ => settle(child, state, value),
+    }
+}
+
+// Run REACTION when the promise ID is settled, or now if it already is
+fn add_reaction(id: i64, reaction: LispObject) {
+    let entry = entry(id);
+    if entry.is_nil() {
+        return;
+    }
+
+    let state = unsafe { AREF(entry, STATE) };
+    if state == Qpending {
+        unsafe {
+            let reactions = AREF(entry, REACTIONS);
+            ASET(entry, REACTIONS, LispObject::cons(reaction, reactions));
+        }
+    } else {
+        react(reaction, state, unsafe { AREF(entry, VALUE) });
+    }
+}
+
+fn promises_from_list(promises: LispObject) -> Vec<i64> {
+    let mut ids = vec![];
+    let mut tail = promises;
+    while tail.is_cons() {
+        let cons = tail.force_cons();
+        ids.push(as_promise(&cons.car()).id);
+        tail = cons.cdr();
+    }
+
+    ids
+}
+
+// Return None for timeouts too long to be represented
+fn timeout_from_lisp(timeout: LispObject) -> Option<Duration> {
+    let seconds = if unsafe { INTEGERP(timeout) } {
+        unsafe { check_integer_range(timeout, 0, intmax_t::MAX) as f64 }
+    } else if unsafe { FLOATP(timeout) } {
+        unsafe { XFLOAT_DATA(timeout) }
+    } else {
+        wrong_type!(Qnumberp, timeout);
+    };
+
+    Duration::try_from_secs_f64(seconds.max(0.0)).ok()
+}
+
+/// Send MESSAGE to the async stream PROC, and return a promise of its
+/// result.  The result settles the promise instead of being passed to
+/// the handler of PROC.
+#[lisp_fn]
+pub fn async_promise_send(proc: LispObject, message: LispObject) -> LispObject {
+    let tasks = match get_tasks(proc) {
+        Some(tasks) => tasks,
+        None => error!("Process is not an async stream"),
+    };
+
+    // The result is only read by async-handler, so the promise can be
+    // registered once the message is sent.
+    let message_id = async_send_message(proc, message);
+    if message_id.is_nil() {
+        error!("Failed to send message to async stream");
+    }
+
+    let plist = unsafe { Fprocess_plist(proc) };
+    let mut promises = unsafe { Fplist_get(plist, QCpromises) };
+    if promises.is_nil() {
+        let mut args = vec![QCtest, Qeql];
+        promises = unsafe { Fmake_hash_table(args.len() as isize, args.as_mut_ptr()) };
+        unsafe { Fset_process_plist(proc, Fplist_put(plist, QCpromises, promises)) };
+    }
+
+    let (id, promise) = make_promise(Some((tasks, message_id.as_fixnum_or_error())));
+    unsafe {
+        ASET(entry(id), SOURCE, LispObject::cons(promises, message_id));
+        Fputhash(message_id, promise, promises);
+    }
+
+    promise
+}
+
+/// Return a promise settled with the result of ON-FULFILLED, called with
+/// the value of PROMISE once it is fulfilled.  If PROMISE is rejected,
+/// ON-REJECTED is called with its error instead, as (ERROR-SYMBOL
+/// . DATA).  A missing callback passes the value or the error through.
+///
+/// The returned promise is rejected if the callback signals an error,
+/// and follows the promise returned by the callback, if any.  It is
+/// cancelled when PROMISE is cancelled.
+#[lisp_fn(min = "2")]
+pub fn async_promise_then(
+    promise: LispObject,
+    on_fulfilled: LispObject,
+    on_rejected: LispObject,
+) -> LispObject {
+    let id = as_promise(&promise).id;
+    let (child, child_promise) = make_promise(None);
+    add_reaction(id, make_reaction(Qthen, child, on_fulfilled, on_rejected));
+    child_promise
+}
+
+/// Return a promise fulfilled with the list of the values of PROMISES,
+/// in the same order, once they are all fulfilled.  It is rejected or
+/// cancelled as soon as one of PROMISES is.
+#[lisp_fn]
+pub fn async_promise_all(promises: LispObject) -> LispObject {
+    let ids = promises_from_list(promises);
+    let (child, child_promise) = make_promise(None);
+    if ids.is_empty() {
+        settle(child, Qfulfilled, Qnil);
+        return child_promise;
+    }
+
+    // The first slot holds the number of promises, and the last the
+    // number of promises left.
+    let count = ids.len() as isize;
+    let values = unsafe { make_vector(count + 2, Qnil) };
+    unsafe {
+        ASET(values, 0, LispObject::from(count as i64));
+        ASET(values, count + 1, LispObject::from(count as i64));
+        ASET(entry(child), VALUE, values);
+    }
+
+    for (idx, id) in ids.into_iter().enumerate() {
+        let reaction = make_reaction(Qall, child, LispObject::from(idx), Qnil);
+        add_reaction(id, reaction);
+    }
+
+    child_promise
+}
+
+/// Return a promise settled like the first of PROMISES to be settled.
+#[lisp_fn]
+pub fn async_promise_race(promises: LispObject) -> LispObject {
+    let ids = promises_from_list(promises);
+    let (child, child_promise) = make_promise(None);
+    for id in ids {
+        add_reaction(id, make_reaction(Qrace, child, Qnil, Qnil));
+    }
+
+    child_promise
+}
+
+/// Cancel PROMISE if it is pending, aborting the task that was to
+/// settle it.  The promises that depend on it are cancelled too.
+/// Return t if PROMISE was cancelled, nil if it was already settled.
+#[lisp_fn]
+pub fn async_promise_cancel(promise: LispObject) -> bool {
+    let promise_ref = as_promise(&promise);
+    let entry = entry(promise_ref.id);
+    if entry.is_nil() || unsafe { AREF(entry, STATE) } != Qpending {
+        return false;
+    }
+
+    if let Some((tasks, message_id)) = &promise_ref.task {
+        tasks.lock().unwrap().cancel(*message_id);
+    }
+    // The aborted task never reports back to its stream
+    let source = unsafe { AREF(entry, SOURCE) };
+    if source.is_cons() {
+        let source = source.force_cons();
+        unsafe { Fremhash(source.cdr(), source.car()) };
+    }
+    settle(promise_ref.id, Qcancelled, Qnil);
+    true
+}
+
+/// Return the state of PROMISE: `pending', `fulfilled', `rejected' or
+/// `cancelled'.
+#[lisp_fn]
+pub fn async_promise_state(promise: LispObject) -> LispObject {
+    let entry = entry(as_promise(&promise).id);
+    if entry.is_nil() {
+        Qnil
+    } else {
+        unsafe { AREF(entry, STATE) }
+    }
+}
+
+/// Return t if OBJECT is a promise.
+#[lisp_fn]
+pub fn async_promisep(object: LispObject) -> bool {
+    is_promise(object)
+}
+
+/// Wait until PROMISE is settled and return its value, processing the
+/// output of the async streams meanwhile.  Signal the error of PROMISE
+/// if it is rejected, or an error if it is cancelled or is still
+/// pending after TIMEOUT seconds.
+#[lisp_fn(min = "1")]
+pub fn async_promise_await(promise: LispObject, timeout: LispObject) -> LispObject {
+    let id = as_promise(&promise).id;
+    // Timeouts that overflow are as good as none
+    let deadline = if timeout.is_nil() {
+        None
+    } else {
+        timeout_from_lisp(timeout).and_then(|timeout| Instant::now().checked_add(timeout))
+    };
+
+    loop {
+        let entry = entry(id);
+        if entry.is_nil() {
+            error!("Promise is not registered");
+        }
+
+        let value = unsafe { AREF(entry, VALUE) };
+        match unsafe { AREF(entry, STATE) } {
+            Qfulfilled => return value,
+            Qrejected => signal_rust(value.force_cons().car(), value.force_cons().cdr()),
+            Qcancelled => error!("Promise was cancelled"),
+            _ => {}
+        }
+
+        if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+            error!("Timed out waiting for promise");
+        }
+
+        unsafe {
+            Faccept_process_output(Qnil, make_float(AWAIT_POLL_INTERVAL), Qnil, Qnil);
+        }
+    }
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(Qasync_promise, "async-promise");
+    def_lisp_sym!(Qasync_promisep, "async-promisep");
+    def_lisp_sym!(Qregistry, "registry");
+    def_lisp_sym!(Qpending, "pending");
+    def_lisp_sym!(Qfulfilled, "fulfilled");
+    def_lisp_sym!(Qrejected, "rejected");
+    def_lisp_sym!(Qcancelled, "cancelled");
+    def_lisp_sym!(Qthen, "then");
+    def_lisp_sym!(Qall, "all");
+    def_lisp_sym!(Qrace, "race");
+}
+
+include!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/out/promise_exports.rs"
+));